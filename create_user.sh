@@ -1,7 +1,26 @@
 #!/bin/bash
 
+DB_FILE="mydb.db"
+
+ensureDatabaseExists() {
+    RESULT=$(sqlite3 "${DB_FILE}" "SELECT name FROM sqlite_master WHERE type='table' AND name='users';")
+
+    if [[ -z $RESULT ]]; then
+        echo "Please run application for first time before adding new users"
+        exit 1
+    fi
+}
+
+isValidUsername() {
+    if [[ "$1" =~ ^[A-Za-z0-9_-][A-Za-z0-9_.-]{0,63}$ ]]; then
+        echo true
+    else
+        echo false
+    fi
+}
+
 isUsernameTaken() {
-    numAccounts=$(awk -F '|' -v user="$1" '{if ($1 == user) { print $1 }}' users.csv | wc -l)
+    numAccounts=$(sqlite3 "${DB_FILE}" "SELECT COUNT(*) FROM users WHERE username = '$1';")
 
     if [[ numAccounts -eq 0 ]]; then
         echo false
@@ -10,19 +29,15 @@ isUsernameTaken() {
     fi
 }
 
-createUserFileIfNonExistent() {
-    if [[ ! -e "users.csv" ]]; then
-        touch users.csv
-    fi
-}
-
 main() {
-    createUserFileIfNonExistent
+    ensureDatabaseExists
 
     while true; do
         read -p "Enter a username: " USERNAME
 
-        if [[ $(isUsernameTaken $USERNAME) == true ]]; then
+        if [[ $(isValidUsername "$USERNAME") == false ]]; then
+            echo "Usernames may only contain letters, numbers, '.', '_' and '-'"
+        elif [[ $(isUsernameTaken "$USERNAME") == true ]]; then
             echo "Username already taken, please type a new one"
         else
             break
@@ -31,10 +46,16 @@ main() {
 
     read -p "Enter a password: " PASSWORD
 
+    IS_ADMIN=0
+    read -p "Should this user be an admin: (y/n) " ANSWER
+    if [[ "$ANSWER" == "y" ]]; then
+        IS_ADMIN=1
+    fi
+
     SALT=$(openssl rand -hex 16)
     HASH=$(echo -n "$PASSWORD" | argon2 "$SALT" -i -l 32 -m 12 -p 1 -t 3 | grep "Encoded:" | awk '{ print $2 }' )
-    echo "$USERNAME|$HASH" >> users.csv
+    sqlite3 "${DB_FILE}" "INSERT INTO users (username, password, is_admin, disabled, created_at) VALUES ('$USERNAME', '$HASH', $IS_ADMIN, 0, strftime('%s', 'now'));"
     echo "User added successfully"
 }
 
-main 
\ No newline at end of file
+main
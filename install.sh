@@ -21,21 +21,22 @@ installLibraries() {
         brew install openssl@1.1
         brew install argon2
         brew install tmux
+        brew install sqlite
     elif [ -x "$(command -v apt-get)" ]; then
         sudo apt-get update
         sudo apt-get upgrade
-        sudo apt-get install pkg-config libssl-dev libsqlite3-dev build-essential tmux
+        sudo apt-get install pkg-config libssl-dev libsqlite3-dev sqlite3 build-essential tmux
         sudo apt-get -y install argon2
     elif [ -x "$(command -v pacman)"]; then
         sudo pacman -Syu
-        sudo pacman -S pkg-config openssl argon2 tmux
+        sudo pacman -S pkg-config openssl argon2 sqlite tmux
     elif [ -x "$(command -v dnf)"]; then
         sudo dnf update
-        sudo dnf install pkg-config openssl-devel sqlite-devel make automake gcc gcc-c+ tmux
+        sudo dnf install pkg-config openssl-devel sqlite-devel sqlite make automake gcc gcc-c+ tmux
         sudo dnf -y install argon2
     elif [ -x "$(command -v apk)"]; then
         sudo apk update
-        apk add pkgconfig openssl-dev argon2 tmux
+        apk add pkgconfig openssl-dev sqlite-dev sqlite argon2 tmux
     else
         echo "Please install OpenSSL and try again"
         exit 1
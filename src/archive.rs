@@ -0,0 +1,891 @@
+use async_recursion::async_recursion;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::time::OffsetDateTime;
+use rocket::{get, Request, State};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+use crate::audit::Audit;
+use crate::conflicts;
+use crate::encryption::{self, UnlockedKeys, UserKeys};
+use crate::range::{content_disposition, BoxedReader};
+use crate::storage::{self, StorageState};
+use crate::AuthenticatedSession;
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+// Flag bit 3: sizes and CRC follow the data; bit 11: names are UTF-8.
+const ZIP_FLAGS: u16 = 0x0808;
+const ZIP_VERSION: u16 = 20;
+
+// Counts, sizes and offsets that don't fit the 16 and 32 bit fields of a
+// plain zip go in ZIP64 records instead.
+const ZIP64_VERSION: u16 = 45;
+const ZIP32_LIMIT: u64 = 0xFFFF_FFFF;
+const ZIP32_MAX_ENTRIES: usize = 0xFFFF;
+
+// ustar has room for 100 bytes of name and 11 octal digits of size. Longer
+// or non-ASCII names and larger sizes go in a PAX header in front.
+const TAR_BLOCK: usize = 512;
+const TAR_NAME_LEN: usize = 100;
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+const DEFLATE_STORED_BLOCK: usize = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn parse(format: Option<&str>) -> Result<ArchiveFormat, Status> {
+        match format {
+            None | Some("zip") => Ok(ArchiveFormat::Zip),
+            Some("tar") => Ok(ArchiveFormat::Tar),
+            Some("tar.gz") | Some("tgz") => Ok(ArchiveFormat::TarGz),
+            Some(_) => Err(Status::BadRequest),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    fn content_type(&self) -> ContentType {
+        match self {
+            ArchiveFormat::Zip => ContentType::ZIP,
+            ArchiveFormat::Tar => ContentType::new("application", "x-tar"),
+            ArchiveFormat::TarGz => ContentType::new("application", "gzip"),
+        }
+    }
+}
+
+// Where the contents of the files come from: the user's storage backend,
+// decrypting with their keys when there are any.
+struct ArchiveSource {
+    storage: StorageState,
+    username: String,
+    keys: Option<UnlockedKeys>,
+}
+
+struct ArchiveEntry {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+    encrypted: bool,
+}
+
+// Folders and files streamed as an uncompressed zip or a tar. Entries are
+// collected up front and file contents are read while the response is being
+// sent, so nothing is buffered on disk or in memory.
+pub struct Archive {
+    file_name: String,
+    format: ArchiveFormat,
+    source: ArchiveSource,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl Archive {
+    // The given files and folders of a user, each at the top of the archive.
+    // Encrypted files go in decrypted, and need the keys like a download.
+    pub async fn from_paths(storage: &StorageState, username: &str, keys: UserKeys, paths: &[PathBuf], format: ArchiveFormat) -> Result<Archive, Status> {
+        let keys = match keys {
+            UserKeys::Disabled => KeyCheck::Disabled,
+            UserKeys::Locked => KeyCheck::Locked,
+            UserKeys::Unlocked(keys) => KeyCheck::Unlocked(keys),
+        };
+        Archive::collect(storage, username, keys, paths, format).await
+    }
+
+    // A shared folder as a zip. The owner's keys are never available to a
+    // share, so encrypted files are left out.
+    pub async fn shared(storage: &StorageState, username: &str, path: &Path) -> Result<Archive, Status> {
+        Archive::collect(storage, username, KeyCheck::Skip, &[path.to_path_buf()], ArchiveFormat::Zip).await
+    }
+
+    async fn collect(storage: &StorageState, username: &str, keys: KeyCheck, paths: &[PathBuf], format: ArchiveFormat) -> Result<Archive, Status> {
+        let mut entries = Vec::new();
+        let mut top_names: Vec<String> = Vec::new();
+        for path in paths {
+            let entry = storage.metadata(username, path).await.map_err(|_| Status::NotFound)?;
+
+            // Selections from different folders can share a name.
+            let name = storage::file_name(path);
+            let mut unique = name.clone();
+            let mut number = 1;
+            while top_names.contains(&unique) {
+                unique = conflicts::numbered_name(&name, number);
+                number += 1;
+            }
+            top_names.push(unique.clone());
+
+            if entry.is_dir {
+                entries.push(ArchiveEntry {
+                    name: format!("{}/", unique),
+                    path: path.clone(),
+                    is_dir: true,
+                    size: 0,
+                    modified: UNIX_EPOCH + Duration::from_secs(entry.modified),
+                    encrypted: false,
+                });
+                collect_stored_entries(storage, username, &keys, path, &format!("{}/", unique), &mut entries).await?;
+            } else {
+                entries.extend(stored_file_entry(storage, username, &keys, path, unique, entry.size, entry.modified).await?);
+            }
+        }
+
+        let file_name = match paths {
+            [_] => format!("{}.{}", top_names[0], format.extension()),
+            _ => format!("download.{}", format.extension()),
+        };
+        let keys = match keys {
+            KeyCheck::Unlocked(keys) => Some(keys),
+            _ => None,
+        };
+        Ok(Archive {
+            file_name,
+            format,
+            source: ArchiveSource { storage: storage.clone(), username: username.to_string(), keys },
+            entries,
+        })
+    }
+}
+
+enum KeyCheck {
+    Disabled,
+    Locked,
+    Unlocked(UnlockedKeys),
+    Skip,
+}
+
+#[async_recursion]
+async fn collect_stored_entries(storage: &StorageState, username: &str, keys: &KeyCheck, path: &Path, prefix: &str,
+    entries: &mut Vec<ArchiveEntry>) -> Result<(), Status> {
+    let mut names = storage.entry_names(username, path).await.map_err(|_| Status::NotFound)?;
+    names.sort();
+
+    for child in names {
+        let child_path = path.join(&child);
+        let name = format!("{}{}", prefix, child);
+        let entry = match storage.metadata(username, &child_path).await {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+
+        if entry.is_dir {
+            entries.push(ArchiveEntry {
+                name: format!("{}/", name),
+                path: child_path.clone(),
+                is_dir: true,
+                size: 0,
+                modified: UNIX_EPOCH + Duration::from_secs(entry.modified),
+                encrypted: false,
+            });
+            collect_stored_entries(storage, username, keys, &child_path, &format!("{}/", name), entries).await?;
+        } else {
+            entries.extend(stored_file_entry(storage, username, keys, &child_path, name, entry.size, entry.modified).await?);
+        }
+    }
+    Ok(())
+}
+
+// Files uploaded before encryption was turned on may still be plain, so the
+// header of each file is looked at, as for a download.
+async fn stored_file_entry(storage: &StorageState, username: &str, keys: &KeyCheck, path: &Path, name: String,
+    size: u64, modified: u64) -> Result<Option<ArchiveEntry>, Status> {
+    let key_id = match keys {
+        KeyCheck::Disabled => None,
+        _ => {
+            let header = storage.read_prefix(username, path, encryption::HEADER_LEN).await.map_err(|_| Status::NotFound)?;
+            encryption::header_key_id(&header)
+        },
+    };
+
+    match (key_id, keys) {
+        (Some(key_id), KeyCheck::Unlocked(keys)) if !keys.contains(key_id) => Err(Status::Forbidden),
+        (Some(_), KeyCheck::Locked) => Err(Status::Locked),
+        (Some(_), KeyCheck::Skip) => Ok(None),
+        _ => Ok(Some(ArchiveEntry {
+            name,
+            path: path.to_path_buf(),
+            is_dir: false,
+            size: if key_id.is_some() { encryption::plaintext_len(size) } else { size },
+            modified: UNIX_EPOCH + Duration::from_secs(modified),
+            encrypted: key_id.is_some(),
+        })),
+    }
+}
+
+async fn open_entry(source: &ArchiveSource, entry: &ArchiveEntry) -> std::io::Result<BoxedReader> {
+    let reader = source.storage.reader(&source.username, &entry.path).await?;
+    match &source.keys {
+        Some(keys) if entry.encrypted => {
+            let (writer, plain): (DuplexStream, DuplexStream) = tokio::io::duplex(PIPE_SIZE);
+            tokio::spawn(encryption::decrypt_stream(keys.clone(), reader, writer));
+            Ok(Box::pin(plain))
+        },
+        _ => Ok(reader),
+    }
+}
+
+fn dos_date_time(time: SystemTime) -> (u16, u16) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let date = OffsetDateTime::from_unix_timestamp(secs as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    // DOS dates start in 1980.
+    if date.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+
+    let dos_time = ((date.hour() as u16) << 11) | ((date.minute() as u16) << 5) | (date.second() as u16 / 2);
+    let dos_date = (((date.year() - 1980) as u16) << 9) | ((date.month() as u16) << 5) | date.day() as u16;
+    (dos_time, dos_date)
+}
+
+struct CentralRecord {
+    name: String,
+    is_dir: bool,
+    crc: u32,
+    size: u64,
+    time: u16,
+    date: u16,
+    offset: u64,
+    zip64: bool,
+}
+
+async fn write_zip<W: AsyncWrite + Unpin>(source: ArchiveSource, entries: Vec<ArchiveEntry>, mut writer: W) -> std::io::Result<()> {
+    let mut records = Vec::with_capacity(entries.len());
+    let mut offset: u64 = 0;
+    let mut buffer = vec![0u8; PIPE_SIZE];
+
+    for entry in entries {
+        let (time, date) = dos_date_time(entry.modified);
+        let name = entry.name.as_bytes();
+
+        // Files are never read past their listed size, so whether the sizes
+        // need 64 bits is known before the data is written.
+        let zip64 = entry.size >= ZIP32_LIMIT;
+        let version = if zip64 { ZIP64_VERSION } else { ZIP_VERSION };
+
+        let mut header = Vec::with_capacity(50 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+        header.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        if zip64 {
+            header.extend_from_slice(&u32::MAX.to_le_bytes());
+            header.extend_from_slice(&u32::MAX.to_le_bytes());
+        } else {
+            header.extend_from_slice(&[0u8; 8]);
+        }
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 20u16 } else { 0 }).to_le_bytes());
+        header.extend_from_slice(name);
+        if zip64 {
+            header.extend_from_slice(&1u16.to_le_bytes());
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&[0u8; 16]);
+        }
+        writer.write_all(&header).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size: u64 = 0;
+        if !entry.is_dir {
+            // A file that grew since it was listed is cut off at the listed size.
+            let mut file = open_entry(&source, &entry).await?.take(entry.size);
+            loop {
+                let read = file.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                writer.write_all(&buffer[..read]).await?;
+                size += read as u64;
+            }
+        }
+        let crc = hasher.finalize();
+
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        if zip64 {
+            descriptor.extend_from_slice(&size.to_le_bytes());
+            descriptor.extend_from_slice(&size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        writer.write_all(&descriptor).await?;
+
+        records.push(CentralRecord { name: entry.name, is_dir: entry.is_dir, crc, size, time, date, offset, zip64 });
+        offset += header.len() as u64 + size + descriptor.len() as u64;
+    }
+
+    let mut directory = Vec::new();
+    for record in &records {
+        let name = record.name.as_bytes();
+
+        // The ZIP64 extra field holds, in order, whichever of the sizes and
+        // the offset didn't fit.
+        let mut extra = Vec::new();
+        if record.zip64 {
+            extra.extend_from_slice(&record.size.to_le_bytes());
+            extra.extend_from_slice(&record.size.to_le_bytes());
+        }
+        if record.offset >= ZIP32_LIMIT {
+            extra.extend_from_slice(&record.offset.to_le_bytes());
+        }
+        let version = if extra.is_empty() { ZIP_VERSION } else { ZIP64_VERSION };
+        let size = if record.zip64 { u32::MAX } else { record.size as u32 };
+
+        directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        directory.extend_from_slice(&version.to_le_bytes());
+        directory.extend_from_slice(&version.to_le_bytes());
+        directory.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&record.time.to_le_bytes());
+        directory.extend_from_slice(&record.date.to_le_bytes());
+        directory.extend_from_slice(&record.crc.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        let extra_len = if extra.is_empty() { 0 } else { extra.len() as u16 + 4 };
+        directory.extend_from_slice(&extra_len.to_le_bytes());
+        directory.extend_from_slice(&[0u8; 6]);
+        let attributes: u32 = if record.is_dir { 0x10 } else { 0 };
+        directory.extend_from_slice(&attributes.to_le_bytes());
+        directory.extend_from_slice(&(record.offset.min(ZIP32_LIMIT) as u32).to_le_bytes());
+        directory.extend_from_slice(name);
+        if !extra.is_empty() {
+            directory.extend_from_slice(&1u16.to_le_bytes());
+            directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&extra);
+        }
+    }
+
+    let directory_len = directory.len() as u64;
+    let mut end = Vec::with_capacity(98);
+    if records.len() >= ZIP32_MAX_ENTRIES || offset >= ZIP32_LIMIT || directory_len >= ZIP32_LIMIT {
+        // The ZIP64 end record and the locator pointing at it.
+        end.extend_from_slice(&0x06064b50u32.to_le_bytes());
+        end.extend_from_slice(&44u64.to_le_bytes());
+        end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+        end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+        end.extend_from_slice(&[0u8; 8]);
+        end.extend_from_slice(&(records.len() as u64).to_le_bytes());
+        end.extend_from_slice(&(records.len() as u64).to_le_bytes());
+        end.extend_from_slice(&directory_len.to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+
+        end.extend_from_slice(&0x07064b50u32.to_le_bytes());
+        end.extend_from_slice(&0u32.to_le_bytes());
+        end.extend_from_slice(&(offset + directory_len).to_le_bytes());
+        end.extend_from_slice(&1u32.to_le_bytes());
+    }
+
+    let count = records.len().min(ZIP32_MAX_ENTRIES) as u16;
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&[0u8; 4]);
+    end.extend_from_slice(&count.to_le_bytes());
+    end.extend_from_slice(&count.to_le_bytes());
+    end.extend_from_slice(&(directory_len.min(ZIP32_LIMIT) as u32).to_le_bytes());
+    end.extend_from_slice(&(offset.min(ZIP32_LIMIT) as u32).to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+
+    writer.write_all(&directory).await?;
+    writer.write_all(&end).await?;
+    writer.shutdown().await
+}
+
+fn tar_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+fn ustar_block(name: &str, kind: u8, size: u64, mtime: u64) -> [u8; TAR_BLOCK] {
+    let mut block = [0u8; TAR_BLOCK];
+    // Cut off names are only a fallback, the PAX header has the real one.
+    let name = name.as_bytes();
+    block[..name.len().min(TAR_NAME_LEN)].copy_from_slice(&name[..name.len().min(TAR_NAME_LEN)]);
+    tar_octal(&mut block[100..108], if kind == b'5' { 0o755 } else { 0o644 });
+    tar_octal(&mut block[108..116], 0);
+    tar_octal(&mut block[116..124], 0);
+    tar_octal(&mut block[124..136], size.min(TAR_MAX_SIZE));
+    tar_octal(&mut block[136..148], mtime);
+    block[148..156].copy_from_slice(b"        ");
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    let checksum: u64 = block.iter().map(|byte| *byte as u64).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    block
+}
+
+// "<length> <key>=<value>\n", where the length counts itself.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut len = rest.len();
+    loop {
+        let total = len.to_string().len() + rest.len();
+        if total == len {
+            return format!("{}{}", len, rest);
+        }
+        len = total;
+    }
+}
+
+fn tar_padding(len: u64) -> usize {
+    (TAR_BLOCK - (len % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+}
+
+fn tar_header(entry: &ArchiveEntry) -> Vec<u8> {
+    let mtime = entry.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut pax = String::new();
+    if entry.name.len() > TAR_NAME_LEN || !entry.name.is_ascii() {
+        pax.push_str(&pax_record("path", &entry.name));
+    }
+    if entry.size > TAR_MAX_SIZE {
+        pax.push_str(&pax_record("size", &entry.size.to_string()));
+    }
+
+    let mut header = Vec::with_capacity(3 * TAR_BLOCK);
+    if !pax.is_empty() {
+        header.extend_from_slice(&ustar_block("././@PaxHeader", b'x', pax.len() as u64, mtime));
+        header.extend_from_slice(pax.as_bytes());
+        header.resize(header.len() + tar_padding(pax.len() as u64), 0);
+    }
+    let kind = if entry.is_dir { b'5' } else { b'0' };
+    header.extend_from_slice(&ustar_block(&entry.name, kind, entry.size, mtime));
+    header
+}
+
+async fn write_tar<W: AsyncWrite + Unpin>(source: ArchiveSource, entries: Vec<ArchiveEntry>, mut writer: W) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; PIPE_SIZE];
+    for entry in entries {
+        writer.write_all(&tar_header(&entry)).await?;
+        if entry.is_dir {
+            continue;
+        }
+
+        let mut file = open_entry(&source, &entry).await?.take(entry.size);
+        let mut size: u64 = 0;
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..read]).await?;
+            size += read as u64;
+        }
+
+        // The size is in the header already, so a file that shrank since it
+        // was listed is padded out to it.
+        let missing = entry.size - size + tar_padding(entry.size) as u64;
+        tokio::io::copy(&mut tokio::io::repeat(0).take(missing), &mut writer).await?;
+    }
+
+    // Two empty blocks end the archive.
+    writer.write_all(&[0u8; 2 * TAR_BLOCK]).await?;
+    writer.shutdown().await
+}
+
+// The gzip layer of a .tar.gz, made of stored deflate blocks: nothing is
+// compressed, like the zip, but any gzip reader takes it. The trailer is only
+// written on shutdown, so an archive that fails part way doesn't look whole.
+struct GzipWriter<W> {
+    inner: W,
+    pending: Vec<u8>,
+    written: usize,
+    hasher: crc32fast::Hasher,
+    len: u64,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> GzipWriter<W> {
+    fn new(inner: W) -> GzipWriter<W> {
+        GzipWriter { inner, pending: GZIP_HEADER.to_vec(), written: 0, hasher: crc32fast::Hasher::new(), len: 0, finished: false }
+    }
+
+    fn push_block(&mut self, data: &[u8], last: bool) {
+        self.pending.push(last as u8);
+        self.pending.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        self.pending.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        self.pending.extend_from_slice(data);
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.pending.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for GzipWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let data = &buf[..buf.len().min(DEFLATE_STORED_BLOCK)];
+        this.hasher.update(data);
+        this.len += data.len() as u64;
+        this.push_block(data, false);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.finished {
+            ready!(this.poll_pending(cx))?;
+            this.push_block(&[], true);
+            let crc = this.hasher.clone().finalize();
+            this.pending.extend_from_slice(&crc.to_le_bytes());
+            this.pending.extend_from_slice(&(this.len as u32).to_le_bytes());
+            this.finished = true;
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Archive {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let (writer, reader): (DuplexStream, DuplexStream) = tokio::io::duplex(PIPE_SIZE);
+        match self.format {
+            ArchiveFormat::Zip => tokio::spawn(write_zip(self.source, self.entries, writer)),
+            ArchiveFormat::Tar => tokio::spawn(write_tar(self.source, self.entries, writer)),
+            ArchiveFormat::TarGz => tokio::spawn(write_tar(self.source, self.entries, GzipWriter::new(writer))),
+        };
+
+        Response::build()
+            .header(self.format.content_type())
+            .header(content_disposition(&self.file_name))
+            .streamed_body(reader)
+            .ok()
+    }
+}
+
+// `?path=` once for a folder, or repeated for a selection. Paths are confined
+// to the user's space like single downloads.
+#[get("/archive?<path>&<format>")]
+pub async fn get_archive(session: AuthenticatedSession, audit: Audit, path: Vec<String>, format: Option<&str>, keys: UserKeys,
+    storage: &State<StorageState>) -> Result<Archive, Status> {
+    audit.record(&session.username, "download", &path.join(", "));
+
+    let format = ArchiveFormat::parse(format)?;
+    if path.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    let mut paths = Vec::with_capacity(path.len());
+    for requested in &path {
+        match storage::relative_path(Path::new(requested.trim_start_matches('/'))) {
+            Some(path) => paths.push(path),
+            None => return Err(Status::Forbidden),
+        }
+    }
+
+    Archive::from_paths(storage, &session.username, keys, &paths, format).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::QuotaTracker;
+    use crate::storage::LocalStorage;
+    use rocket::serde::json::serde_json;
+    use rusqlite::Connection;
+    use std::sync::{Arc, Mutex};
+
+    // A local storage rooted in a fresh temporary directory, with "hello"
+    // in `bob/notes.txt`.
+    fn test_source(name: &str) -> ArchiveSource {
+        let directory = std::env::temp_dir().join(format!("archive-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(directory.join("bob")).unwrap();
+        std::fs::write(directory.join("bob/notes.txt"), b"hello").unwrap();
+
+        let app_config = serde_json::from_value(serde_json::json!({ "directory": directory.to_str().unwrap() })).unwrap();
+        let database = Arc::new(Mutex::new(Connection::open_in_memory().unwrap()));
+        let quota = Arc::new(Mutex::new(QuotaTracker::default()));
+        ArchiveSource { storage: Arc::new(LocalStorage::new(app_config, database, quota)), username: "bob".to_string(), keys: None }
+    }
+
+    fn entry(name: &str, is_dir: bool, size: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            name: name.to_string(),
+            path: PathBuf::from(name.trim_end_matches('/')),
+            is_dir,
+            size,
+            modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            encrypted: false,
+        }
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn tar_checksum_is_valid(block: &[u8]) -> bool {
+        let stored = std::str::from_utf8(&block[148..154]).ok().and_then(|digits| u64::from_str_radix(digits, 8).ok());
+        let sum = block.iter().enumerate()
+            .map(|(index, byte)| if (148..156).contains(&index) { b' ' as u64 } else { *byte as u64 })
+            .sum::<u64>();
+        stored == Some(sum)
+    }
+
+    #[test]
+    fn converts_dos_dates() {
+        // 2023-11-14 22:13:20 UTC.
+        let (time, date) = dos_date_time(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(time, (22 << 11) | (13 << 5) | 10);
+        assert_eq!(date, (43 << 9) | (11 << 5) | 14);
+        // Anything before 1980 becomes its first day.
+        assert_eq!(dos_date_time(UNIX_EPOCH), (0, (1 << 5) | 1));
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+        for len in 80..120 {
+            let record = pax_record("path", &"x".repeat(len));
+            let (length, _) = record.split_once(' ').unwrap();
+            assert_eq!(length.parse::<usize>().unwrap(), record.len());
+        }
+    }
+
+    #[test]
+    fn pads_to_whole_blocks() {
+        assert_eq!(tar_padding(0), 0);
+        assert_eq!(tar_padding(1), 511);
+        assert_eq!(tar_padding(512), 0);
+        assert_eq!(tar_padding(513), 511);
+    }
+
+    #[test]
+    fn writes_ustar_headers() {
+        let header = tar_header(&entry("docs/notes.txt", false, 5));
+        assert_eq!(header.len(), TAR_BLOCK);
+        assert_eq!(&header[..14], b"docs/notes.txt");
+        assert_eq!(&header[124..136], b"00000000005\0");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..265], b"ustar\x0000");
+        assert!(tar_checksum_is_valid(&header));
+
+        let header = tar_header(&entry("docs/", true, 0));
+        assert_eq!(header[156], b'5');
+        assert_eq!(&header[100..108], b"0000755\0");
+    }
+
+    #[test]
+    fn puts_long_names_and_sizes_in_pax_headers() {
+        let name = format!("{}/notes.txt", "a".repeat(120));
+        let header = tar_header(&entry(&name, false, TAR_MAX_SIZE + 1));
+        assert_eq!(header.len(), 3 * TAR_BLOCK);
+        assert_eq!(header[156], b'x');
+        assert!(tar_checksum_is_valid(&header[..TAR_BLOCK]));
+
+        let expected = format!("{}{}", pax_record("path", &name), pax_record("size", &(TAR_MAX_SIZE + 1).to_string()));
+        assert_eq!(&header[TAR_BLOCK..TAR_BLOCK + expected.len()], expected.as_bytes());
+        assert_eq!(&header[124..136], format!("{:011o}\0", expected.len()).as_bytes());
+
+        let ustar = &header[2 * TAR_BLOCK..];
+        assert_eq!(&ustar[..TAR_NAME_LEN], &name.as_bytes()[..TAR_NAME_LEN]);
+        assert_eq!(&ustar[124..136], b"77777777777\0");
+        assert!(tar_checksum_is_valid(ustar));
+
+        // Non-ASCII names get one too, even when they are short.
+        let header = tar_header(&entry("résumé.txt", false, 5));
+        assert_eq!(header[156], b'x');
+        assert!(String::from_utf8_lossy(&header[TAR_BLOCK..]).contains("path=résumé.txt\n"));
+    }
+
+    #[tokio::test]
+    async fn writes_tar_archives() {
+        let source = test_source("tar");
+        let mut output = Vec::new();
+        let entries = vec![entry("notes/", true, 0), entry("notes.txt", false, 5)];
+        write_tar(source, entries, &mut output).await.unwrap();
+
+        assert_eq!(output.len(), 5 * TAR_BLOCK);
+        assert_eq!(&output[..6], b"notes/");
+        assert_eq!(&output[TAR_BLOCK..TAR_BLOCK + 9], b"notes.txt");
+        assert_eq!(&output[2 * TAR_BLOCK..2 * TAR_BLOCK + 5], b"hello");
+        assert!(output[2 * TAR_BLOCK + 5..].iter().all(|byte| *byte == 0));
+    }
+
+    #[tokio::test]
+    async fn pads_files_that_shrank_since_listing() {
+        let source = test_source("tar-shrunk");
+        let mut output = Vec::new();
+        write_tar(source, vec![entry("notes.txt", false, 600)], &mut output).await.unwrap();
+
+        // Header, two blocks of contents and the end of the archive.
+        assert_eq!(output.len(), 5 * TAR_BLOCK);
+        assert_eq!(&output[TAR_BLOCK..TAR_BLOCK + 5], b"hello");
+        assert!(output[TAR_BLOCK + 5..].iter().all(|byte| *byte == 0));
+    }
+
+    #[tokio::test]
+    async fn writes_zip_archives() {
+        let source = test_source("zip");
+        let mut output = Vec::new();
+        let entries = vec![entry("notes/", true, 0), entry("notes.txt", false, 5)];
+        write_zip(source, entries, &mut output).await.unwrap();
+
+        // The folder: a local header and an empty data descriptor.
+        assert_eq!(u32_at(&output, 0), 0x04034b50);
+        assert_eq!(u16_at(&output, 6), ZIP_FLAGS);
+        assert_eq!(&output[30..36], b"notes/");
+        assert_eq!(u32_at(&output, 36), 0x08074b50);
+
+        // The file, with its CRC and sizes in the descriptor after it.
+        let file = 36 + 16;
+        assert_eq!(u32_at(&output, file), 0x04034b50);
+        assert_eq!(&output[file + 30..file + 39], b"notes.txt");
+        assert_eq!(&output[file + 39..file + 44], b"hello");
+        let descriptor = file + 44;
+        assert_eq!(u32_at(&output, descriptor), 0x08074b50);
+        assert_eq!(u32_at(&output, descriptor + 4), crc32fast::hash(b"hello"));
+        assert_eq!(u32_at(&output, descriptor + 8), 5);
+        assert_eq!(u32_at(&output, descriptor + 12), 5);
+
+        let end = output.len() - 22;
+        assert_eq!(u32_at(&output, end), 0x06054b50);
+        assert_eq!(u16_at(&output, end + 10), 2);
+        let directory = u32_at(&output, end + 16) as usize;
+        assert_eq!(directory, descriptor + 16);
+        assert_eq!(u32_at(&output, end + 12) as usize, end - directory);
+
+        assert_eq!(u32_at(&output, directory), 0x02014b50);
+        assert_eq!(u32_at(&output, directory + 38), 0x10);
+        assert_eq!(u32_at(&output, directory + 42), 0);
+        let second = directory + 46 + 6;
+        assert_eq!(u32_at(&output, second), 0x02014b50);
+        assert_eq!(u32_at(&output, second + 16), crc32fast::hash(b"hello"));
+        assert_eq!(u32_at(&output, second + 42) as usize, file);
+    }
+
+    #[tokio::test]
+    async fn writes_zip64_sizes_for_large_files() {
+        // Listed at the ZIP64 limit, the file is cut short when read, but the
+        // records still have to be the ZIP64 ones.
+        let source = test_source("zip64-size");
+        let mut output = Vec::new();
+        write_zip(source, vec![entry("notes.txt", false, ZIP32_LIMIT)], &mut output).await.unwrap();
+
+        assert_eq!(u16_at(&output, 4), ZIP64_VERSION);
+        assert_eq!(u32_at(&output, 18), u32::MAX);
+        assert_eq!(u32_at(&output, 22), u32::MAX);
+        assert_eq!(u16_at(&output, 28), 20);
+        assert_eq!(u16_at(&output, 39), 1);
+        assert_eq!(u16_at(&output, 41), 16);
+
+        let descriptor = 39 + 20 + 5;
+        assert_eq!(u32_at(&output, descriptor), 0x08074b50);
+        assert_eq!(u32_at(&output, descriptor + 4), crc32fast::hash(b"hello"));
+        assert_eq!(u64_at(&output, descriptor + 8), 5);
+        assert_eq!(u64_at(&output, descriptor + 16), 5);
+
+        let directory = descriptor + 24;
+        assert_eq!(u32_at(&output, directory), 0x02014b50);
+        assert_eq!(u16_at(&output, directory + 6), ZIP64_VERSION);
+        assert_eq!(u32_at(&output, directory + 20), u32::MAX);
+        assert_eq!(u16_at(&output, directory + 30), 20);
+        let extra = directory + 46 + 9;
+        assert_eq!(u16_at(&output, extra), 1);
+        assert_eq!(u16_at(&output, extra + 2), 16);
+        assert_eq!(u64_at(&output, extra + 4), 5);
+        assert_eq!(u64_at(&output, extra + 12), 5);
+    }
+
+    #[tokio::test]
+    async fn writes_zip64_end_records_for_many_entries() {
+        let source = test_source("zip64");
+        let mut output = Vec::new();
+        let entries = (0..ZIP32_MAX_ENTRIES).map(|index| entry(&format!("{}/", index), true, 0)).collect();
+        write_zip(source, entries, &mut output).await.unwrap();
+
+        let end = output.len() - 22;
+        assert_eq!(u32_at(&output, end), 0x06054b50);
+        assert_eq!(u16_at(&output, end + 10), 0xFFFF);
+
+        let locator = end - 20;
+        assert_eq!(u32_at(&output, locator), 0x07064b50);
+        let zip64_end = u64_at(&output, locator + 8) as usize;
+        assert_eq!(zip64_end, locator - 56);
+        assert_eq!(u32_at(&output, zip64_end), 0x06064b50);
+        assert_eq!(u64_at(&output, zip64_end + 32), ZIP32_MAX_ENTRIES as u64);
+
+        let directory = u64_at(&output, zip64_end + 48) as usize;
+        assert_eq!(directory + u64_at(&output, zip64_end + 40) as usize, zip64_end);
+        assert_eq!(u32_at(&output, directory), 0x02014b50);
+    }
+
+    #[tokio::test]
+    async fn writes_gzip_streams() {
+        let mut output = Vec::new();
+        let data = vec![7u8; DEFLATE_STORED_BLOCK + 10];
+        let mut writer = GzipWriter::new(&mut output);
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        assert_eq!(&output[..10], &GZIP_HEADER);
+        let mut position = 10;
+        let mut inflated = Vec::new();
+        loop {
+            let last = output[position] == 1;
+            let len = u16_at(&output, position + 1);
+            assert_eq!(u16_at(&output, position + 3), !len);
+            position += 5;
+            inflated.extend_from_slice(&output[position..position + len as usize]);
+            position += len as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(inflated, data);
+        assert_eq!(u32_at(&output, position), crc32fast::hash(&data));
+        assert_eq!(u32_at(&output, position + 4), data.len() as u32);
+        assert_eq!(output.len(), position + 8);
+    }
+}
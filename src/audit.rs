@@ -0,0 +1,206 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::{serde_json, Json};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, Request, Response, State};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::logging;
+use crate::users::AdminSession;
+use crate::{unix_timestamp, AuthenticatedSession, MyAppConfig};
+
+// Append-only record of logins and file and account changes, one JSON object
+// per line in `audit_log`. Once the file reaches `audit_log_max_bytes` it is
+// renamed to `<audit_log>.1`, older files move up by one and the oldest
+// beyond `audit_log_files` is dropped.
+//
+// Handlers take an `Audit` guard and say what they are about to do; the
+// fairing writes the line once the response status is known, so early
+// returns are recorded with their status too.
+pub type AuditState = Arc<AuditLog>;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub time: u64,
+    pub username: String,
+    pub ip: Option<String>,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    files: usize,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(app_config: &MyAppConfig) -> AuditLog {
+        AuditLog {
+            path: PathBuf::from(&app_config.audit_log),
+            max_bytes: app_config.audit_log_max_bytes,
+            files: app_config.audit_log_files,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.path.clone(),
+            _ => PathBuf::from(format!("{}.{}", self.path.display(), index)),
+        }
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        let _ = std::fs::remove_file(self.rotated_path(self.files));
+        for index in (0..self.files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, event: &AuditEvent) {
+        let mut line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push('\n');
+
+        let _lock = self.lock.lock();
+        let size = std::fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes && self.rotate().is_err() {
+            tracing::error!(path = %self.path.display(), "Failed to rotate the audit log");
+        }
+
+        let written = OpenOptions::new().create(true).append(true).open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(error) = written {
+            tracing::error!(path = %self.path.display(), %error, "Failed to write to the audit log");
+        }
+    }
+
+    // Newest first, across the rotated files.
+    pub fn read(&self, offset: usize, limit: usize, filter: impl Fn(&AuditEvent) -> bool) -> Vec<AuditEvent> {
+        let _lock = self.lock.lock();
+        let mut events = Vec::new();
+        let mut skipped = 0;
+        for index in 0..=self.files {
+            let contents = match std::fs::read_to_string(self.rotated_path(index)) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            for line in contents.lines().rev() {
+                let event = match serde_json::from_str::<AuditEvent>(line) {
+                    Ok(event) if filter(&event) => event,
+                    _ => continue,
+                };
+                if skipped < offset {
+                    skipped += 1;
+                    continue;
+                }
+                events.push(event);
+                if events.len() >= limit {
+                    return events;
+                }
+            }
+        }
+        events
+    }
+}
+
+#[derive(Default)]
+struct PendingEvent(Arc<Mutex<Option<AuditEvent>>>);
+
+pub struct Audit(Arc<Mutex<Option<AuditEvent>>>);
+
+impl Audit {
+    pub fn record(&self, username: &str, action: &str, path: &str) {
+        self.record_event(username, action, path, None);
+    }
+
+    pub fn record_with_target(&self, username: &str, action: &str, path: &str, target: &str) {
+        self.record_event(username, action, path, Some(target.to_string()));
+    }
+
+    fn record_event(&self, username: &str, action: &str, path: &str, target: Option<String>) {
+        if let Ok(mut pending) = self.0.lock() {
+            *pending = Some(AuditEvent {
+                time: 0,
+                username: username.to_string(),
+                ip: None,
+                action: action.to_string(),
+                path: path.to_string(),
+                target,
+                status: 0,
+                request_id: None,
+            });
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(Audit(request.local_cache(PendingEvent::default).0.clone()))
+    }
+}
+
+pub struct AuditFairing(pub AuditState);
+
+#[rocket::async_trait]
+impl Fairing for AuditFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let pending = request.local_cache(PendingEvent::default).0.lock().ok().and_then(|mut pending| pending.take());
+        if let Some(mut event) = pending {
+            event.time = unix_timestamp();
+            event.ip = request.client_ip().map(|ip| ip.to_string());
+            event.status = response.status().code;
+            event.request_id = Some(logging::request_id(request));
+            self.0.write(&event);
+        }
+    }
+}
+
+fn page_size(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+#[get("/audit?<offset>&<limit>")]
+pub async fn get_audit(session: AuthenticatedSession, offset: Option<usize>, limit: Option<usize>,
+    audit_log: &State<AuditState>) -> Json<Vec<AuditEvent>> {
+    Json(audit_log.read(offset.unwrap_or(0), page_size(limit), |event| event.username == session.username))
+}
+
+#[get("/admin/audit?<username>&<action>&<offset>&<limit>")]
+pub async fn get_admin_audit(_admin: AdminSession, username: Option<String>, action: Option<String>,
+    offset: Option<usize>, limit: Option<usize>, audit_log: &State<AuditState>) -> Json<Vec<AuditEvent>> {
+    Json(audit_log.read(offset.unwrap_or(0), page_size(limit), |event| {
+        username.as_ref().map(|username| &event.username == username).unwrap_or(true)
+            && action.as_ref().map(|action| &event.action == action).unwrap_or(true)
+    }))
+}
@@ -0,0 +1,166 @@
+use rocket::http::Status;
+use rocket::serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::filenames;
+use crate::storage::StorageState;
+use crate::tokens::TokenScope;
+
+// What to do when a file is uploaded, renamed or moved onto an existing one,
+// chosen with `?conflict=`. A name that only differs from an existing one in
+// case or Unicode normalization counts as the same file, and existing folder
+// names are reused the same way. Without the parameter each handler keeps
+// its old behavior: uploads and moves overwrite, renames fail.
+const MAX_NUMBERED_NAMES: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    Fail,
+    Overwrite,
+    // Stores under "name (1).ext", the first number that is free.
+    KeepBoth,
+    // Overwrites only when what comes in was modified after the existing
+    // file, and otherwise leaves both alone.
+    IfNewer,
+}
+
+impl ConflictPolicy {
+    // An unknown policy is refused rather than ignored, a typo shouldn't
+    // turn into an overwrite.
+    pub fn parse(conflict: Option<&str>, default: ConflictPolicy) -> Result<ConflictPolicy, Status> {
+        match conflict {
+            None => Ok(default),
+            Some("fail") => Ok(ConflictPolicy::Fail),
+            Some("overwrite") => Ok(ConflictPolicy::Overwrite),
+            Some("keep-both") => Ok(ConflictPolicy::KeepBoth),
+            Some("if-newer") => Ok(ConflictPolicy::IfNewer),
+            Some(_) => Err(Status::BadRequest),
+        }
+    }
+
+    // Upload tokens may only add files, so whatever would overwrite one
+    // fails instead.
+    pub fn for_scope(self, scope: Option<TokenScope>) -> ConflictPolicy {
+        match (scope, self) {
+            (Some(TokenScope::Upload), ConflictPolicy::Overwrite | ConflictPolicy::IfNewer) => ConflictPolicy::Fail,
+            _ => self,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolution {
+    None,
+    Overwritten,
+    KeptBoth,
+    Skipped,
+    // A folder copied into an existing one.
+    Merged,
+}
+
+// "report (2).pdf" for "report.pdf", and "notes (2)" for "notes" or ".notes".
+pub fn numbered_name(name: &str, number: u32) -> String {
+    match name.rfind('.') {
+        Some(index) if index > 0 => format!("{} ({}){}", &name[..index], number, &name[index..]),
+        _ => format!("{} ({})", name, number),
+    }
+}
+
+async fn is_taken(storage: &StorageState, username: &str, path: &Path) -> bool {
+    storage.metadata(username, path).await.is_ok() || filenames::find_collision(storage, username, path).await.is_some()
+}
+
+async fn free_path(storage: &StorageState, username: &str, path: &Path) -> Result<PathBuf, Status> {
+    let name = path.file_name().map(|name| name.to_string_lossy().to_string()).ok_or(Status::BadRequest)?;
+    for number in 1..=MAX_NUMBERED_NAMES {
+        let candidate = path.with_file_name(numbered_name(&name, number));
+        if !is_taken(storage, username, &candidate).await {
+            return Ok(candidate);
+        }
+    }
+    Err(Status::Conflict)
+}
+
+// `path` with any folder or name it collides with spelled the way it exists.
+async fn existing_path(storage: &StorageState, username: &str, path: &Path) -> PathBuf {
+    match filenames::find_collision(storage, username, path).await {
+        Some(mut collision) => {
+            for component in path.iter().skip(collision.iter().count()) {
+                collision.push(component);
+            }
+            collision
+        },
+        None => path.to_path_buf(),
+    }
+}
+
+// Where a file going to `path` should be written, and how a conflict was
+// settled. `Skipped` means nothing should be written. `source` is the file
+// being renamed or moved, which never conflicts with itself, and `modified`
+// the modification time of what comes in, in seconds.
+pub async fn resolve(storage: &StorageState, username: &str, path: &Path, policy: ConflictPolicy,
+    source: Option<&Path>, modified: Option<u64>) -> Result<(PathBuf, Resolution), Status> {
+    let existing = existing_path(storage, username, path).await;
+    if Some(existing.as_path()) == source {
+        return Ok((path.to_path_buf(), Resolution::None));
+    }
+
+    let entry = match storage.metadata(username, &existing).await {
+        Ok(entry) => entry,
+        Err(_) => return Ok((existing, Resolution::None)),
+    };
+
+    match policy {
+        ConflictPolicy::KeepBoth => Ok((free_path(storage, username, &existing).await?, Resolution::KeptBoth)),
+        _ if entry.is_dir => Err(Status::Conflict),
+        ConflictPolicy::Fail => Err(Status::Conflict),
+        ConflictPolicy::Overwrite => Ok((existing, Resolution::Overwritten)),
+        ConflictPolicy::IfNewer if modified.unwrap_or(u64::MAX) > entry.modified => Ok((existing, Resolution::Overwritten)),
+        ConflictPolicy::IfNewer => Ok((existing, Resolution::Skipped)),
+    }
+}
+
+// The same for a folder being copied to `path`. With `overwrite` and
+// `if-newer` an existing folder is merged into, and each file in it then
+// follows the policy on its own.
+pub async fn resolve_folder(storage: &StorageState, username: &str, path: &Path, policy: ConflictPolicy) -> Result<(PathBuf, Resolution), Status> {
+    let existing = existing_path(storage, username, path).await;
+    let entry = match storage.metadata(username, &existing).await {
+        Ok(entry) => entry,
+        Err(_) => return Ok((existing, Resolution::None)),
+    };
+
+    match policy {
+        ConflictPolicy::KeepBoth => Ok((free_path(storage, username, &existing).await?, Resolution::KeptBoth)),
+        _ if !entry.is_dir => Err(Status::Conflict),
+        ConflictPolicy::Fail => Err(Status::Conflict),
+        ConflictPolicy::Overwrite | ConflictPolicy::IfNewer => Ok((existing, Resolution::Merged)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_names_before_the_extension() {
+        assert_eq!(numbered_name("report.pdf", 1), "report (1).pdf");
+        assert_eq!(numbered_name("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(numbered_name("notes", 3), "notes (3)");
+        assert_eq!(numbered_name(".notes", 4), ".notes (4)");
+        assert_eq!(numbered_name("photo (1).jpg", 2), "photo (1) (2).jpg");
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(ConflictPolicy::parse(None, ConflictPolicy::Fail), Ok(ConflictPolicy::Fail));
+        assert_eq!(ConflictPolicy::parse(Some("overwrite"), ConflictPolicy::Fail), Ok(ConflictPolicy::Overwrite));
+        assert_eq!(ConflictPolicy::parse(Some("keep-both"), ConflictPolicy::Fail), Ok(ConflictPolicy::KeepBoth));
+        assert_eq!(ConflictPolicy::parse(Some("if-newer"), ConflictPolicy::Fail), Ok(ConflictPolicy::IfNewer));
+        assert_eq!(ConflictPolicy::parse(Some("fail"), ConflictPolicy::Overwrite), Ok(ConflictPolicy::Fail));
+        assert_eq!(ConflictPolicy::parse(Some("Overwrite"), ConflictPolicy::Fail), Err(Status::BadRequest));
+        assert_eq!(ConflictPolicy::parse(Some(""), ConflictPolicy::Fail), Err(Status::BadRequest));
+    }
+}
@@ -0,0 +1,386 @@
+use async_recursion::async_recursion;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{delete, get, post, State};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::conflicts::{self, ConflictPolicy, Resolution};
+use crate::filenames::{self, StoredPath};
+use crate::quota::UserQuota;
+use crate::storage::{self, StorageState, StoredEntry};
+use crate::{unix_timestamp, AuthenticatedSession};
+
+// Server-side copies within a user's space. A file is copied while the
+// request waits. A folder is copied by a background job: the request answers
+// right away with the job, `GET /copy/<id>` follows its progress and
+// `DELETE /copy/<id>` stops it after the file being copied, keeping what was
+// copied so far. Copies default to `?conflict=fail`.
+pub type CopyJobsState = Arc<Mutex<HashMap<String, Arc<CopyJob>>>>;
+
+// Finished jobs can still be looked at for a while.
+const FINISHED_JOB_TTL_SECONDS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+pub struct CopyProgress {
+    pub id: String,
+    pub source: String,
+    pub target: String,
+    pub conflict: Resolution,
+    pub state: JobState,
+    // The totals are filled in once the source folder has been walked.
+    pub files: u64,
+    pub bytes: u64,
+    pub copied_files: u64,
+    pub copied_bytes: u64,
+    // Files `if-newer` left alone, and files that could not be copied.
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+    // Why a failed job stopped, as an HTTP status.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip)]
+    finished_at: Option<u64>,
+}
+
+pub struct CopyJob {
+    username: String,
+    cancelled: AtomicBool,
+    progress: Mutex<CopyProgress>,
+}
+
+impl CopyJob {
+    fn report(&self) -> Option<CopyProgress> {
+        self.progress.lock().ok().map(|progress| progress.clone())
+    }
+
+    fn update(&self, change: impl FnOnce(&mut CopyProgress)) {
+        if let Ok(mut progress) = self.progress.lock() {
+            change(&mut progress);
+        }
+    }
+
+    fn finish(&self, state: JobState, status: Option<Status>) {
+        self.update(|progress| {
+            progress.state = state;
+            progress.status = status.map(|status| status.code);
+            progress.finished_at = Some(unix_timestamp());
+        });
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// A reflink when the filesystem can share the blocks (btrfs, XFS), and
+// otherwise a plain copy, which std does with copy_file_range on Linux so the
+// data stays in the kernel.
+pub async fn clone_file(source: &Path, target: &Path) -> std::io::Result<u64> {
+    let source = source.to_path_buf();
+    let target = target.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut source = std::fs::File::open(source)?;
+        let mut target = std::fs::File::create(target)?;
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            // SAFETY: both files stay open for the duration of the call.
+            if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+                return Ok(source.metadata()?.len());
+            }
+        }
+        std::io::copy(&mut source, &mut target)
+    }).await?
+}
+
+#[post("/file/copy/<file_path..>?<conflict>&<new_file_path>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file(session: AuthenticatedSession, audit: Audit, file_path: PathBuf, conflict: Option<&str>, new_file_path: String,
+    storage: &State<StorageState>, quota: UserQuota) -> Result<Json<StoredPath>, Status> {
+    audit.record_with_target(&session.username, "copy", &file_path.to_string_lossy(), &new_file_path);
+
+    let source = match storage::relative_path(&file_path) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
+    let entry = match storage.metadata(&session.username, &source).await {
+        Ok(entry) if !entry.is_dir => entry,
+        _ => return Err(Status::NoContent),
+    };
+
+    let target = match filenames::check_relative_path(&new_file_path).and_then(|path| storage::relative_path(&path)) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
+
+    let policy = ConflictPolicy::parse(conflict, ConflictPolicy::Fail)?;
+    let (target, resolution) = conflicts::resolve(storage, &session.username, &target, policy, None, Some(entry.modified)).await?;
+    if resolution == Resolution::Skipped {
+        return Ok(StoredPath::resolved(&target, resolution));
+    }
+    if target == source {
+        return Err(Status::BadRequest);
+    }
+
+    let (bytes, files) = usage_change(storage, &quota, &session.username, &target, &entry, resolution).await;
+    if !quota.allows(bytes.max(0) as u64, files.max(0) as u64) {
+        return Err(Status::InsufficientStorage);
+    }
+
+    if storage.copy(&session.username, &source, &target).await.is_err() {
+        return Err(Status::ExpectationFailed);
+    }
+    quota.record(bytes, files);
+
+    Ok(StoredPath::resolved(&target, resolution))
+}
+
+// What copying `entry` to `target` adds to the user's usage.
+async fn usage_change(storage: &StorageState, quota: &UserQuota, username: &str, target: &Path, entry: &StoredEntry,
+    resolution: Resolution) -> (i64, i64) {
+    let replaced = match resolution {
+        Resolution::Overwritten => storage.metadata(username, target).await.ok().map(|existing| existing.size),
+        _ => None,
+    };
+    match replaced {
+        Some(replaced) => (entry.size as i64 - quota.freed_by_replacing(replaced) as i64, 0),
+        None => (entry.size as i64, 1),
+    }
+}
+
+#[post("/folder/copy/<folder_path..>?<conflict>&<new_folder_path>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_folder(session: AuthenticatedSession, audit: Audit, folder_path: PathBuf, conflict: Option<&str>, new_folder_path: Option<String>,
+    storage: &State<StorageState>, jobs: &State<CopyJobsState>, quota: UserQuota) -> Result<(Status, Json<CopyProgress>), Status> {
+    // Taken as optional so a copy without a target is refused here instead of
+    // falling through to `POST /folder/<path..>` and creating "copy/<path>".
+    let new_folder_path = new_folder_path.ok_or(Status::BadRequest)?;
+    audit.record_with_target(&session.username, "copy", &folder_path.to_string_lossy(), &new_folder_path);
+
+    let source = match storage::relative_path(&folder_path) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
+    match storage.metadata(&session.username, &source).await {
+        Ok(entry) if entry.is_dir => (),
+        _ => return Err(Status::NoContent),
+    }
+
+    let target = match filenames::check_relative_path(&new_folder_path).and_then(|path| storage::relative_path(&path)) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
+
+    let policy = ConflictPolicy::parse(conflict, ConflictPolicy::Fail)?;
+    let (target, resolution) = conflicts::resolve_folder(storage, &session.username, &target, policy).await?;
+
+    // A folder can't be copied into itself.
+    if target.starts_with(&source) {
+        return Err(Status::BadRequest);
+    }
+
+    let job = Arc::new(CopyJob {
+        username: session.username.clone(),
+        cancelled: AtomicBool::new(false),
+        progress: Mutex::new(CopyProgress {
+            id: hex::encode(rand::thread_rng().gen::<[u8; 16]>()),
+            source: source.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            conflict: resolution,
+            state: JobState::Running,
+            files: 0,
+            bytes: 0,
+            copied_files: 0,
+            copied_bytes: 0,
+            skipped: Vec::new(),
+            failed: Vec::new(),
+            status: None,
+            finished_at: None,
+        }),
+    });
+    let progress = job.report().ok_or(Status::InternalServerError)?;
+    match jobs.lock() {
+        Ok(mut jobs) => jobs.insert(progress.id.clone(), job.clone()),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    tokio::spawn(copy_tree(job, storage.inner().clone(), quota, source, target, policy, resolution == Resolution::Merged));
+    Ok((Status::Accepted, Json(progress)))
+}
+
+// What is in the source folder, relative to it.
+#[derive(Default)]
+struct SourceTree {
+    folders: Vec<PathBuf>,
+    files: Vec<(PathBuf, StoredEntry)>,
+}
+
+#[async_recursion]
+async fn walk(storage: &StorageState, username: &str, root: &Path, relative: &Path, tree: &mut SourceTree, job: &CopyJob) -> std::io::Result<()> {
+    for name in storage.entry_names(username, &root.join(relative)).await? {
+        if job.is_cancelled() {
+            return Ok(());
+        }
+
+        let path = relative.join(name);
+        let entry = storage.metadata(username, &root.join(&path)).await?;
+        if entry.is_dir {
+            tree.folders.push(path.clone());
+            walk(storage, username, root, &path, tree, job).await?;
+        } else {
+            tree.files.push((path, entry));
+        }
+    }
+    Ok(())
+}
+
+async fn copy_tree(job: Arc<CopyJob>, storage: StorageState, quota: UserQuota, source: PathBuf, target: PathBuf, policy: ConflictPolicy, merging: bool) {
+    let username = quota.username.clone();
+    let mut tree = SourceTree::default();
+    if let Err(error) = walk(&storage, &username, &source, Path::new(""), &mut tree, &job).await {
+        tracing::warn!(path = %source.display(), username, %error, "Could not read folder to copy");
+        job.finish(JobState::Failed, Some(Status::ExpectationFailed));
+        return;
+    }
+    if job.is_cancelled() {
+        job.finish(JobState::Cancelled, None);
+        return;
+    }
+
+    let files = tree.files.len() as u64;
+    let bytes = tree.files.iter().map(|(_, entry)| entry.size).sum();
+    job.update(|progress| {
+        progress.files = files;
+        progress.bytes = bytes;
+    });
+
+    // A new folder that can't fit isn't started. Merging may replace files,
+    // so that is only checked file by file.
+    if !merging && !quota.allows(bytes, files) {
+        job.finish(JobState::Failed, Some(Status::InsufficientStorage));
+        return;
+    }
+
+    for folder in std::iter::once(PathBuf::new()).chain(tree.folders) {
+        if let Err(error) = storage.create_folder(&username, &target.join(&folder)).await {
+            tracing::warn!(path = %target.join(&folder).display(), username, %error, "Could not create folder");
+            job.finish(JobState::Failed, Some(Status::ExpectationFailed));
+            return;
+        }
+    }
+
+    for (path, entry) in tree.files {
+        if job.is_cancelled() {
+            job.finish(JobState::Cancelled, None);
+            return;
+        }
+
+        let name = path.to_string_lossy().to_string();
+        let (to, resolution) = match conflicts::resolve(&storage, &username, &target.join(&path), policy, None, Some(entry.modified)).await {
+            Ok((_, Resolution::Skipped)) => {
+                job.update(|progress| progress.skipped.push(name));
+                continue;
+            },
+            Ok(resolved) => resolved,
+            Err(_) => {
+                job.update(|progress| progress.failed.push(name));
+                continue;
+            },
+        };
+
+        let (bytes, files) = usage_change(&storage, &quota, &username, &to, &entry, resolution).await;
+        if !quota.allows(bytes.max(0) as u64, files.max(0) as u64) {
+            job.finish(JobState::Failed, Some(Status::InsufficientStorage));
+            return;
+        }
+
+        match storage.copy(&username, &source.join(&path), &to).await {
+            Ok(_) => {
+                quota.record(bytes, files);
+                job.update(|progress| {
+                    progress.copied_files += 1;
+                    progress.copied_bytes += entry.size;
+                });
+            },
+            Err(error) => {
+                tracing::warn!(path = %source.join(&path).display(), username, %error, "Could not copy");
+                job.update(|progress| progress.failed.push(name));
+            },
+        }
+    }
+
+    if job.is_cancelled() {
+        job.finish(JobState::Cancelled, None);
+    } else {
+        job.finish(JobState::Done, None);
+    }
+}
+
+fn find_job(jobs: &CopyJobsState, username: &str, id: &str) -> Option<Arc<CopyJob>> {
+    let jobs = jobs.lock().ok()?;
+    jobs.get(id).filter(|job| job.username == username).cloned()
+}
+
+#[get("/copy")]
+pub async fn get_copy_jobs(session: AuthenticatedSession, jobs: &State<CopyJobsState>) -> Result<Json<Vec<CopyProgress>>, Status> {
+    let jobs = jobs.lock().map_err(|_| Status::InternalServerError)?;
+    let mut reports: Vec<CopyProgress> = jobs.values()
+        .filter(|job| job.username == session.username)
+        .filter_map(|job| job.report())
+        .collect();
+    reports.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(Json(reports))
+}
+
+#[get("/copy/<id>")]
+pub async fn get_copy_job(session: AuthenticatedSession, id: String, jobs: &State<CopyJobsState>) -> Result<Json<CopyProgress>, Status> {
+    find_job(jobs, &session.username, &id)
+        .and_then(|job| job.report())
+        .map(Json)
+        .ok_or(Status::NotFound)
+}
+
+// Stops a running job, or forgets a finished one.
+#[delete("/copy/<id>")]
+pub async fn cancel_copy_job(session: AuthenticatedSession, audit: Audit, id: String, jobs: &State<CopyJobsState>) -> Status {
+    audit.record(&session.username, "cancel_copy", &id);
+
+    let job = match find_job(jobs, &session.username, &id) {
+        Some(job) => job,
+        None => return Status::NotFound,
+    };
+
+    match job.report() {
+        Some(progress) if progress.state == JobState::Running => job.cancelled.store(true, Ordering::Relaxed),
+        _ => if let Ok(mut jobs) = jobs.lock() {
+            jobs.remove(&id);
+        },
+    }
+    Status::Ok
+}
+
+pub fn purge_finished(jobs: &CopyJobsState) {
+    let now = unix_timestamp();
+    if let Ok(mut jobs) = jobs.lock() {
+        jobs.retain(|_, job| match job.report().and_then(|progress| progress.finished_at) {
+            Some(finished_at) => finished_at + FINISHED_JOB_TTL_SECONDS > now,
+            None => true,
+        });
+    }
+}
@@ -0,0 +1,113 @@
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+
+pub type DatabaseState = Arc<Mutex<Connection>>;
+
+// Each entry is applied once, in order, and recorded in `PRAGMA user_version`.
+// Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE sessions (
+        session_id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+    );",
+    "CREATE TABLE users (
+        username TEXT PRIMARY KEY,
+        password TEXT NOT NULL,
+        is_admin INTEGER NOT NULL DEFAULT 0,
+        disabled INTEGER NOT NULL DEFAULT 0,
+        created_at INTEGER NOT NULL
+    );",
+    "ALTER TABLE users ADD COLUMN quota_bytes INTEGER;
+    ALTER TABLE users ADD COLUMN quota_files INTEGER;",
+    "CREATE TABLE trash (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        original_path TEXT NOT NULL,
+        deleted_at INTEGER NOT NULL,
+        is_dir INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        files INTEGER NOT NULL
+    );
+    CREATE INDEX trash_username ON trash (username);",
+    "CREATE TABLE shares (
+        token TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        path TEXT NOT NULL,
+        is_dir INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        password TEXT,
+        max_downloads INTEGER,
+        downloads INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX shares_username ON shares (username);",
+    "CREATE TABLE versions (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        path TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        size INTEGER NOT NULL
+    );
+    CREATE INDEX versions_path ON versions (username, path);",
+    "CREATE TABLE encryption (
+        username TEXT PRIMARY KEY,
+        salt TEXT NOT NULL,
+        current_key_id INTEGER NOT NULL
+    );
+    CREATE TABLE encryption_keys (
+        username TEXT NOT NULL,
+        key_id INTEGER NOT NULL,
+        wrapped_key TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (username, key_id)
+    );",
+    "ALTER TABLE users ADD COLUMN totp_secret TEXT;
+    ALTER TABLE users ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN recovery_codes TEXT NOT NULL DEFAULT '';",
+    "CREATE TABLE api_tokens (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        name TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE,
+        scope TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER,
+        last_used_at INTEGER
+    );
+    CREATE INDEX api_tokens_username ON api_tokens (username);",
+    "CREATE TABLE login_throttle (
+        kind TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure_at INTEGER NOT NULL,
+        locked_until INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (kind, subject)
+    );
+    CREATE TABLE login_failures (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL,
+        ip TEXT,
+        reason TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX login_failures_created_at ON login_failures (created_at);",
+];
+
+pub fn open_database(path: &str) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+    migrate(&connection)?;
+    Ok(connection)
+}
+
+fn migrate(connection: &Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        connection.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", migration, index + 1))?;
+    }
+
+    Ok(())
+}
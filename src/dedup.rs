@@ -0,0 +1,295 @@
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use rand::prelude::*;
+
+use crate::MyAppConfig;
+
+// With `storage_mode = "dedup"` every stored file is a hard link to a blob
+// under `<directory>/.blobs/<first two hex digits>/<sha256>`, so identical
+// content takes disk space once no matter how many users, versions or trash
+// entries refer to it. The link count is the reference count: a blob whose
+// only remaining link is the one in the blob store is unused and gets
+// removed by `collect_garbage`.
+//
+// This only works because files are never modified in place; uploads are
+// always written to a staging file and renamed over the target. Linked files
+// share one inode, so they also share a modification time. A file is only
+// linked to a blob with the same time; otherwise it stays a plain copy, so it
+// keeps the time it was uploaded with for listings, `?conflict=if-newer` and
+// conditional requests.
+const BLOB_FOLDER: &str = ".blobs";
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct MigrationReport {
+    pub files: u64,
+    pub deduplicated: u64,
+    pub bytes_saved: u64,
+}
+
+pub fn is_enabled(app_config: &MyAppConfig) -> bool {
+    app_config.storage_mode == "dedup"
+}
+
+fn blob_directory(directory: &str) -> PathBuf {
+    Path::new(directory).join(BLOB_FOLDER)
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Makes `path` a link to the blob with the same content and modification
+// time, adding a new blob if there isn't one yet. Returns true when an
+// existing blob was reused.
+pub fn intern(directory: &str, path: &Path) -> std::io::Result<bool> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_file() {
+        return Ok(false);
+    }
+
+    let hash = hash_file(path)?;
+    let blobs = blob_directory(directory);
+    let blob = blobs.join(&hash[..2]).join(&hash);
+
+    match std::fs::metadata(&blob) {
+        Ok(blob_metadata) if blob_metadata.dev() == metadata.dev() && blob_metadata.ino() == metadata.ino() => Ok(false),
+        Ok(blob_metadata) if blob_metadata.modified()? != metadata.modified()? => Ok(false),
+        Ok(_) => {
+            // Link the blob next to the target first so the swap is a single rename.
+            let temporary = blobs.join(format!(".link-{}", hex::encode(rand::thread_rng().gen::<[u8; 8]>())));
+            std::fs::hard_link(&blob, &temporary)?;
+            if let Err(error) = std::fs::rename(&temporary, path) {
+                let _ = std::fs::remove_file(&temporary);
+                return Err(error);
+            }
+            Ok(true)
+        },
+        Err(_) => {
+            std::fs::create_dir_all(blob.parent().unwrap())?;
+            match std::fs::hard_link(path, &blob) {
+                Ok(_) => Ok(false),
+                // Someone else stored the same content in the meantime.
+                Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => intern(directory, path),
+                Err(error) => Err(error),
+            }
+        },
+    }
+}
+
+// Called after a file has been written in its final place. Failing to
+// deduplicate is not an error; the file just stays a plain copy.
+pub async fn store(app_config: &MyAppConfig, path: &Path) {
+    if !is_enabled(app_config) {
+        return;
+    }
+
+    let directory = app_config.directory.clone();
+    let path = path.to_path_buf();
+    let _ = tokio::task::spawn_blocking(move || {
+        if std::fs::metadata(&path).map(|metadata| metadata.is_dir()).unwrap_or(false) {
+            intern_tree(&directory, &path, &mut MigrationReport::default())
+        } else {
+            intern(&directory, &path).map(|_| ())
+        }
+    }).await;
+}
+
+fn intern_tree(directory: &str, path: &Path, report: &mut MigrationReport) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(path)?.flatten() {
+        let entry_path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            intern_tree(directory, &entry_path, report)?;
+        } else if metadata.is_file() {
+            report.files += 1;
+            match intern(directory, &entry_path) {
+                Ok(true) => {
+                    report.deduplicated += 1;
+                    report.bytes_saved += metadata.len();
+                },
+                Ok(false) => (),
+                Err(error) => tracing::warn!(path = %entry_path.display(), %error, "Could not deduplicate"),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Offline conversion of an existing plain layout. Everything under the
+// storage directory is linked into the blob store except the blob store
+// itself and unfinished uploads.
+pub fn migrate(directory: &str) -> std::io::Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+    std::fs::create_dir_all(blob_directory(directory))?;
+
+    for entry in std::fs::read_dir(directory)?.flatten() {
+        let name = entry.file_name();
+        if name == BLOB_FOLDER || name == ".uploads" {
+            continue;
+        }
+
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => intern_tree(directory, &entry.path(), &mut report)?,
+            _ => (),
+        }
+    }
+
+    Ok(report)
+}
+
+// Removes blobs nobody links to any more and returns the bytes freed.
+pub fn collect_garbage(directory: &str) -> u64 {
+    let mut freed = 0;
+    let prefixes = match std::fs::read_dir(blob_directory(directory)) {
+        Ok(prefixes) => prefixes,
+        Err(_) => return 0,
+    };
+
+    for prefix in prefixes.flatten() {
+        let blobs = match std::fs::read_dir(prefix.path()) {
+            Ok(blobs) => blobs,
+            Err(_) => continue,
+        };
+
+        for blob in blobs.flatten() {
+            let metadata = match blob.metadata() {
+                Ok(metadata) if metadata.is_file() && metadata.nlink() == 1 => metadata,
+                _ => continue,
+            };
+            if std::fs::remove_file(blob.path()).is_ok() {
+                freed += metadata.len();
+            }
+        }
+    }
+
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("dedup-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join("alice/docs")).unwrap();
+        std::fs::create_dir_all(directory.join("bob")).unwrap();
+        directory
+    }
+
+    fn write_file(path: &Path, contents: &[u8], modified: u64) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+    }
+
+    fn modified(path: &Path) -> SystemTime {
+        std::fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    fn inode(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn links_identical_files_to_one_blob() {
+        let directory = test_directory("intern");
+        let root = directory.to_str().unwrap();
+        let first = directory.join("alice/docs/a.txt");
+        let second = directory.join("bob/b.txt");
+        write_file(&first, b"same", 1_700_000_000);
+        write_file(&second, b"same", 1_700_000_000);
+
+        assert!(!intern(root, &first).unwrap());
+        assert!(intern(root, &second).unwrap());
+        assert_eq!(inode(&first), inode(&second));
+        assert_eq!(std::fs::metadata(&first).unwrap().nlink(), 3);
+        // Interning again changes nothing.
+        assert!(!intern(root, &second).unwrap());
+        assert_eq!(std::fs::read(&second).unwrap(), b"same");
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn keeps_files_with_other_times_apart() {
+        let directory = test_directory("times");
+        let root = directory.to_str().unwrap();
+        let older = directory.join("alice/docs/a.txt");
+        let newer = directory.join("bob/b.txt");
+        write_file(&older, b"same", 1_600_000_000);
+        write_file(&newer, b"same", 1_700_000_000);
+
+        assert!(!intern(root, &older).unwrap());
+        assert!(!intern(root, &newer).unwrap());
+        assert_ne!(inode(&older), inode(&newer));
+        assert_eq!(modified(&older), UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert_eq!(modified(&newer), UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn migrates_existing_trees() {
+        let directory = test_directory("migrate");
+        let root = directory.to_str().unwrap();
+        write_file(&directory.join("alice/docs/a.txt"), b"shared", 1_700_000_000);
+        write_file(&directory.join("alice/b.txt"), b"shared", 1_700_000_000);
+        write_file(&directory.join("bob/c.txt"), b"shared", 1_700_000_000);
+        write_file(&directory.join("bob/d.txt"), b"other", 1_700_000_000);
+        std::fs::create_dir_all(directory.join(".uploads/bob")).unwrap();
+        write_file(&directory.join(".uploads/bob/partial"), b"shared", 1_700_000_000);
+
+        let report = migrate(root).unwrap();
+        assert_eq!(report.files, 4);
+        assert_eq!(report.deduplicated, 2);
+        assert_eq!(report.bytes_saved, 12);
+        assert_eq!(inode(&directory.join("alice/docs/a.txt")), inode(&directory.join("bob/c.txt")));
+        assert_ne!(inode(&directory.join("alice/docs/a.txt")), inode(&directory.join(".uploads/bob/partial")));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn collects_only_unused_blobs() {
+        let directory = test_directory("garbage");
+        let root = directory.to_str().unwrap();
+        let first = directory.join("alice/a.txt");
+        let second = directory.join("bob/b.txt");
+        write_file(&first, b"kept", 1_700_000_000);
+        write_file(&second, b"dropped!", 1_700_000_000);
+        intern_tree(root, &directory.join("alice"), &mut MigrationReport::default()).unwrap();
+        intern_tree(root, &directory.join("bob"), &mut MigrationReport::default()).unwrap();
+
+        assert_eq!(collect_garbage(root), 0);
+        std::fs::remove_file(&second).unwrap();
+        assert_eq!(collect_garbage(root), 8);
+        assert_eq!(collect_garbage(root), 0);
+
+        let blobs: Vec<PathBuf> = std::fs::read_dir(blob_directory(root)).unwrap().flatten()
+            .flat_map(|prefix| std::fs::read_dir(prefix.path()).unwrap().flatten().map(|blob| blob.path()))
+            .collect();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(inode(&blobs[0]), inode(&first));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,852 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rocket::data::DataStream;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, post, Request, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::quota::UserQuota;
+use crate::range::{content_disposition, BoxedReader};
+use crate::storage::StorageState;
+use crate::upload;
+use crate::users;
+use crate::{unix_timestamp, AuthenticatedSession, MyAppConfig};
+
+// Optional encryption at rest. Each user who turns it on gets random data
+// keys, stored wrapped (XChaCha20-Poly1305) under a key derived from their
+// password with Argon2. The data keys are unwrapped at login and only kept in
+// memory for the sessions of that login, so the disk alone is not enough to
+// read the files.
+//
+// Encrypted files start with a header naming their key and are then a STREAM
+// of XChaCha20-Poly1305 segments, so they can be written and read without
+// holding the file in memory, and truncation or reordering is caught.
+// Rotating adds a new key; older ones are kept so versions and trashed files
+// stay readable. Key ids are random so a key created after an admin discarded
+// the old ones is never mistaken for them.
+pub type KeyringState = Arc<Mutex<Keyring>>;
+
+const MAGIC: &[u8; 8] = b"MYDRVENC";
+const NONCE_PREFIX_LEN: usize = 19;
+pub const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_PREFIX_LEN;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const PIPE_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+pub struct PasswordConfirmation {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct EncryptionStatus {
+    pub enabled: bool,
+    pub unlocked: bool,
+    pub key_id: Option<u32>,
+    pub rewriting: bool,
+}
+
+#[derive(Clone)]
+pub struct UnlockedKeys {
+    pub current: u32,
+    keys: HashMap<u32, Key>,
+}
+
+impl UnlockedKeys {
+    fn current_key(&self) -> Key {
+        self.keys[&self.current]
+    }
+
+    pub fn contains(&self, key_id: u32) -> bool {
+        self.keys.contains_key(&key_id)
+    }
+}
+
+// Unwrapped keys per session, plus the users whose files are being rewritten
+// after enabling encryption or rotating.
+#[derive(Default)]
+pub struct Keyring {
+    sessions: HashMap<u64, (String, UnlockedKeys)>,
+    rewriting: HashSet<String>,
+}
+
+impl Keyring {
+    pub fn insert(&mut self, session_id: u64, username: &str, keys: UnlockedKeys) {
+        self.sessions.insert(session_id, (username.to_string(), keys));
+    }
+
+    pub fn get(&self, session_id: u64) -> Option<UnlockedKeys> {
+        self.sessions.get(&session_id).map(|(_, keys)| keys.clone())
+    }
+
+    pub fn remove(&mut self, session_id: u64) {
+        self.sessions.remove(&session_id);
+    }
+
+    pub fn remove_user(&mut self, username: &str) {
+        self.sessions.retain(|_, (owner, _)| owner != username);
+    }
+
+    pub fn find_user(&self, username: &str) -> Option<UnlockedKeys> {
+        self.sessions.values().find(|(owner, _)| owner == username).map(|(_, keys)| keys.clone())
+    }
+
+    pub fn retain_sessions(&mut self, mut is_active: impl FnMut(u64) -> bool) {
+        self.sessions.retain(|session_id, _| is_active(*session_id));
+    }
+
+    fn update_user(&mut self, username: &str, keys: &UnlockedKeys) {
+        for (owner, unlocked) in self.sessions.values_mut() {
+            if owner == username {
+                *unlocked = keys.clone();
+            }
+        }
+    }
+}
+
+pub enum UserKeys {
+    Disabled,
+    Locked,
+    Unlocked(UnlockedKeys),
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserKeys {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let session = match request.guard::<AuthenticatedSession>().await {
+            request::Outcome::Success(session) => session,
+            request::Outcome::Failure(failure) => return request::Outcome::Failure(failure),
+            request::Outcome::Forward(forward) => return request::Outcome::Forward(forward),
+        };
+
+        let enabled = match request.rocket().state::<DatabaseState>().unwrap().lock() {
+            Ok(connection) => is_enabled(&connection, &session.username),
+            Err(_) => return request::Outcome::Failure((Status::InternalServerError, ())),
+        };
+        if !enabled {
+            return request::Outcome::Success(UserKeys::Disabled);
+        }
+
+        match request.rocket().state::<KeyringState>().unwrap().lock() {
+            Ok(keyring) => match keyring.get(session.session_id) {
+                Some(keys) => request::Outcome::Success(UserKeys::Unlocked(keys)),
+                None => request::Outcome::Success(UserKeys::Locked),
+            },
+            Err(_) => request::Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+fn derive_wrapping_key(password: &str, salt: &[u8]) -> Option<Key> {
+    let mut key = Key::default();
+    Argon2::default().hash_password_into(password.as_bytes(), salt, &mut key).ok()?;
+    Some(key)
+}
+
+// The username and key id are bound in so wrapped keys can't be swapped around.
+fn wrap_key(wrapping_key: &Key, username: &str, key_id: u32, key: &Key) -> Option<String> {
+    let nonce: [u8; 24] = rand::thread_rng().gen();
+    let aad = format!("{}:{}", username, key_id);
+    let wrapped = XChaCha20Poly1305::new(wrapping_key)
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: key, aad: aad.as_bytes() })
+        .ok()?;
+    Some(format!("{}{}", hex::encode(nonce), hex::encode(wrapped)))
+}
+
+fn unwrap_key(wrapping_key: &Key, username: &str, key_id: u32, wrapped: &str) -> Option<Key> {
+    let bytes = hex::decode(wrapped).ok()?;
+    if bytes.len() < 24 {
+        return None;
+    }
+    let aad = format!("{}:{}", username, key_id);
+    let key = XChaCha20Poly1305::new(wrapping_key)
+        .decrypt(XNonce::from_slice(&bytes[..24]), Payload { msg: &bytes[24..], aad: aad.as_bytes() })
+        .ok()?;
+    (key.len() == 32).then(|| *Key::from_slice(&key))
+}
+
+fn generate_key() -> Key {
+    Key::from(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+pub fn is_enabled(connection: &Connection, username: &str) -> bool {
+    connection.query_row("SELECT 1 FROM encryption WHERE username = ?1", params![username], |_| Ok(()))
+        .optional()
+        .map(|found| found.is_some())
+        .unwrap_or(false)
+}
+
+// The stored, still wrapped keys of a user. Unwrapping and wrapping derive a
+// key with Argon2, which is slow, so it's done between reading these and
+// writing them back rather than with the database locked.
+pub struct WrappedKeys {
+    salt: Vec<u8>,
+    current: u32,
+    keys: Vec<(u32, String)>,
+}
+
+// None if encryption is off for the user.
+pub fn load_wrapped(connection: &Connection, username: &str) -> Option<WrappedKeys> {
+    let (salt, current): (String, u32) = connection.query_row(
+        "SELECT salt, current_key_id FROM encryption WHERE username = ?1",
+        params![username],
+        |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional().ok()??;
+
+    let mut statement = connection.prepare("SELECT key_id, wrapped_key FROM encryption_keys WHERE username = ?1").ok()?;
+    let keys = statement.query_map(params![username], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))).ok()?
+        .collect::<rusqlite::Result<Vec<_>>>().ok()?;
+    Some(WrappedKeys { salt: hex::decode(salt).ok()?, current, keys })
+}
+
+fn unwrap_all(wrapping_key: &Key, username: &str, wrapped: &WrappedKeys) -> Option<UnlockedKeys> {
+    let mut keys = HashMap::new();
+    for (key_id, wrapped_key) in &wrapped.keys {
+        keys.insert(*key_id, unwrap_key(wrapping_key, username, *key_id, wrapped_key)?);
+    }
+    keys.contains_key(&wrapped.current).then_some(UnlockedKeys { current: wrapped.current, keys })
+}
+
+// Unwraps every key of the user, or None if the password doesn't fit.
+pub fn unlock(wrapped: &WrappedKeys, username: &str, password: &str) -> Option<UnlockedKeys> {
+    unwrap_all(&derive_wrapping_key(password, &wrapped.salt)?, username, wrapped)
+}
+
+// Wraps all keys again under a new password, with a fresh salt, for `save_wrapped`.
+pub fn rewrap(keys: &UnlockedKeys, username: &str, password: &str) -> Option<WrappedKeys> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let wrapping_key = derive_wrapping_key(password, &salt)?;
+    let keys_wrapped = keys.keys.iter()
+        .map(|(key_id, key)| wrap_key(&wrapping_key, username, *key_id, key).map(|wrapped| (*key_id, wrapped)))
+        .collect::<Option<Vec<_>>>()?;
+    Some(WrappedKeys { salt: salt.to_vec(), current: keys.current, keys: keys_wrapped })
+}
+
+// Stores keys from `rewrap`. Returns false, changing nothing, if the user's
+// keys were rotated or discarded since they were unwrapped.
+pub fn save_wrapped(connection: &Connection, username: &str, wrapped: &WrappedKeys) -> rusqlite::Result<bool> {
+    connection.execute_batch("BEGIN")?;
+    let result = (|| {
+        let stored = match load_wrapped(connection, username) {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+        let mut stored_ids: Vec<u32> = stored.keys.iter().map(|(key_id, _)| *key_id).collect();
+        let mut key_ids: Vec<u32> = wrapped.keys.iter().map(|(key_id, _)| *key_id).collect();
+        stored_ids.sort_unstable();
+        key_ids.sort_unstable();
+        if stored.current != wrapped.current || stored_ids != key_ids {
+            return Ok(false);
+        }
+
+        connection.execute("UPDATE encryption SET salt = ?2 WHERE username = ?1", params![username, hex::encode(&wrapped.salt)])?;
+        for (key_id, wrapped_key) in &wrapped.keys {
+            connection.execute(
+                "UPDATE encryption_keys SET wrapped_key = ?3 WHERE username = ?1 AND key_id = ?2",
+                params![username, key_id, wrapped_key])?;
+        }
+        Ok(true)
+    })();
+    match result {
+        Ok(true) => connection.execute_batch("COMMIT").map(|_| true),
+        Ok(false) => connection.execute_batch("ROLLBACK").map(|_| false),
+        Err(error) => {
+            let _ = connection.execute_batch("ROLLBACK");
+            Err(error)
+        },
+    }
+}
+
+// A new random key, wrapped and ready for `add_key`.
+struct NewKey {
+    salt: Vec<u8>,
+    key_id: u32,
+    key: Key,
+    wrapped: String,
+}
+
+// Makes a key to add to `keys`, wrapped under the password with the salt the
+// other keys use, or a new one when encryption is being turned on.
+fn new_key(username: &str, password: &str, wrapped: Option<&WrappedKeys>, keys: Option<&UnlockedKeys>) -> Option<NewKey> {
+    let salt = match wrapped {
+        Some(wrapped) => wrapped.salt.clone(),
+        None => rand::thread_rng().gen::<[u8; 16]>().to_vec(),
+    };
+    let wrapping_key = derive_wrapping_key(password, &salt)?;
+    let key_id = loop {
+        let key_id: u32 = rand::thread_rng().gen();
+        if !keys.is_some_and(|keys| keys.keys.contains_key(&key_id)) {
+            break key_id;
+        }
+    };
+    let key = generate_key();
+    let wrapped = wrap_key(&wrapping_key, username, key_id, &key)?;
+    Some(NewKey { salt, key_id, key, wrapped })
+}
+
+// Adds a key from `new_key` and makes it the current one. Returns None,
+// changing nothing, if encryption was turned on or the salt changed since.
+fn add_key(connection: &Connection, username: &str, new_key: NewKey, keys: Option<UnlockedKeys>) -> rusqlite::Result<Option<UnlockedKeys>> {
+    connection.execute_batch("BEGIN")?;
+    let result = (|| {
+        let stored_salt = load_wrapped(connection, username).map(|stored| stored.salt);
+        let expected_salt = keys.as_ref().map(|_| new_key.salt.clone());
+        if stored_salt != expected_salt {
+            return Ok(false);
+        }
+
+        connection.execute(
+            "INSERT INTO encryption_keys (username, key_id, wrapped_key, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![username, new_key.key_id, new_key.wrapped, unix_timestamp() as i64])?;
+        connection.execute(
+            "INSERT INTO encryption (username, salt, current_key_id) VALUES (?1, ?2, ?3)
+            ON CONFLICT (username) DO UPDATE SET current_key_id = excluded.current_key_id",
+            params![username, hex::encode(&new_key.salt), new_key.key_id])?;
+        Ok(true)
+    })();
+    match result {
+        Ok(true) => connection.execute_batch("COMMIT")?,
+        Ok(false) => {
+            connection.execute_batch("ROLLBACK")?;
+            return Ok(None);
+        },
+        Err(error) => {
+            let _ = connection.execute_batch("ROLLBACK");
+            return Err(error);
+        },
+    }
+
+    let mut keys = keys.unwrap_or(UnlockedKeys { current: 0, keys: HashMap::new() });
+    keys.keys.insert(new_key.key_id, new_key.key);
+    keys.current = new_key.key_id;
+    Ok(Some(keys))
+}
+
+// Forgets the user's keys. Whatever is still encrypted with them can't be read again.
+pub fn discard_keys(connection: &Connection, username: &str) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM encryption_keys WHERE username = ?1", params![username])?;
+    connection.execute("DELETE FROM encryption WHERE username = ?1", params![username])?;
+    Ok(())
+}
+
+// Key id of an encrypted file, from its first `HEADER_LEN` bytes.
+pub fn header_key_id(header: &[u8]) -> Option<u32> {
+    if header.len() < HEADER_LEN || !header.starts_with(MAGIC) {
+        return None;
+    }
+    Some(u32::from_be_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into().ok()?))
+}
+
+pub async fn file_key_id(path: &Path) -> Option<u32> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path).await.ok()?.take(HEADER_LEN as u64).read_to_end(&mut header).await.ok()?;
+    header_key_id(&header)
+}
+
+// Size of the contents of an encrypted file of `size` bytes.
+pub fn plaintext_len(size: u64) -> u64 {
+    let body = size.saturating_sub(HEADER_LEN as u64);
+    let segments = body.div_ceil((SEGMENT_SIZE + TAG_LEN) as u64).max(1);
+    body.saturating_sub(segments * TAG_LEN as u64)
+}
+
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    let mut filled = 0;
+    while filled < len {
+        let read = reader.read(&mut buffer[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    buffer.truncate(filled);
+    Ok(buffer)
+}
+
+fn crypto_error(_: chacha20poly1305::aead::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "encrypted file failed authentication")
+}
+
+// Encrypts everything `reader` yields into `writer` and returns the number of
+// bytes written. A segment is only known to be the last one once the next
+// read comes back empty, so one segment is always held back.
+pub async fn encrypt_stream<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(key: Key, key_id: u32, mut reader: R, mut writer: W) -> std::io::Result<u64> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&key_id.to_be_bytes());
+    header.extend_from_slice(&rand::thread_rng().gen::<[u8; NONCE_PREFIX_LEN]>());
+    writer.write_all(&header).await?;
+    let mut written = header.len() as u64;
+
+    let mut encryptor = EncryptorBE32::<XChaCha20Poly1305>::new(&key, GenericArray::from_slice(&header[MAGIC.len() + 4..]));
+    let mut current = read_full(&mut reader, SEGMENT_SIZE).await?;
+    loop {
+        let next = match current.len() {
+            SEGMENT_SIZE => read_full(&mut reader, SEGMENT_SIZE).await?,
+            _ => Vec::new(),
+        };
+        if next.is_empty() {
+            let segment = encryptor.encrypt_last(Payload { msg: &current, aad: &header }).map_err(crypto_error)?;
+            writer.write_all(&segment).await?;
+            written += segment.len() as u64;
+            break;
+        }
+
+        let segment = encryptor.encrypt_next(Payload { msg: &current, aad: &header }).map_err(crypto_error)?;
+        writer.write_all(&segment).await?;
+        written += segment.len() as u64;
+        current = next;
+    }
+
+    writer.flush().await?;
+    Ok(written)
+}
+
+pub async fn decrypt_stream<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(keys: UnlockedKeys, mut reader: R, mut writer: W) -> std::io::Result<()> {
+    let header = read_full(&mut reader, HEADER_LEN).await?;
+    let key = header_key_id(&header)
+        .and_then(|key_id| keys.keys.get(&key_id))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "no key for encrypted file"))?;
+
+    let mut decryptor = DecryptorBE32::<XChaCha20Poly1305>::new(key, GenericArray::from_slice(&header[MAGIC.len() + 4..]));
+    let mut current = read_full(&mut reader, SEGMENT_SIZE + TAG_LEN).await?;
+    loop {
+        let next = match current.len() {
+            length if length == SEGMENT_SIZE + TAG_LEN => read_full(&mut reader, SEGMENT_SIZE + TAG_LEN).await?,
+            _ => Vec::new(),
+        };
+        if next.is_empty() {
+            let segment = decryptor.decrypt_last(Payload { msg: &current, aad: &header }).map_err(crypto_error)?;
+            writer.write_all(&segment).await?;
+            break;
+        }
+
+        let segment = decryptor.decrypt_next(Payload { msg: &current, aad: &header }).map_err(crypto_error)?;
+        writer.write_all(&segment).await?;
+        current = next;
+    }
+
+    writer.shutdown().await
+}
+
+// Streams an upload through the encryptor into the staging file. Returns
+// whether the upload was complete and the size written to disk.
+pub async fn stream_encrypted(data: DataStream<'_>, keys: &UnlockedKeys, staging_file: File) -> std::io::Result<(bool, u64)> {
+    let (writer, reader): (DuplexStream, DuplexStream) = tokio::io::duplex(PIPE_SIZE);
+    let (streamed, encrypted) = tokio::join!(
+        data.stream_to(writer),
+        encrypt_stream(keys.current_key(), keys.current, reader, staging_file));
+    Ok((streamed?.complete, encrypted?))
+}
+
+// Like `stream_encrypted`, for an upload that already sits in a file.
+pub async fn encrypt_file(source: &Path, keys: &UnlockedKeys, staging_file: File) -> std::io::Result<u64> {
+    let reader = File::open(source).await?;
+    encrypt_stream(keys.current_key(), keys.current, reader, staging_file).await
+}
+
+// A decrypted download. Encrypted files can't be read from an arbitrary
+// offset cheaply, so range requests get the whole file.
+pub struct DecryptedFile {
+    keys: UnlockedKeys,
+    reader: BoxedReader,
+    len: u64,
+    file_name: String,
+}
+
+impl DecryptedFile {
+    pub fn new(keys: UnlockedKeys, reader: BoxedReader, size: u64, file_name: &str) -> DecryptedFile {
+        DecryptedFile { keys, reader, len: plaintext_len(size), file_name: file_name.to_string() }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for DecryptedFile {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = Path::new(&self.file_name).extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Binary);
+
+        // If the file fails authentication part way the body ends short of
+        // Content-Length and the client sees a failed download.
+        let (writer, reader): (DuplexStream, DuplexStream) = tokio::io::duplex(PIPE_SIZE);
+        tokio::spawn(decrypt_stream(self.keys, self.reader, writer));
+
+        Response::build()
+            .header(content_type)
+            .header(Header::new("Content-Length", self.len.to_string()))
+            .header(content_disposition(&self.file_name))
+            .streamed_body(reader)
+            .ok()
+    }
+}
+
+// Brings every current file of the user onto the current key, encrypting
+// plain files and re-encrypting older keys. Trash and versions keep
+// whatever they had.
+async fn rewrite_files(storage: StorageState, app_config: MyAppConfig, keyring: KeyringState, quota: UserQuota, keys: UnlockedKeys) {
+    let username = quota.username.clone();
+    let files = storage.list_files(&username).await.unwrap_or_default();
+    for file in files {
+        let path = PathBuf::from(file);
+        if let Err(error) = rewrite_file(&storage, &app_config, &quota, &keys, &path).await {
+            tracing::warn!(path = %path.display(), username, %error, "Could not encrypt");
+        }
+    }
+
+    if let Ok(mut keyring) = keyring.lock() {
+        keyring.rewriting.remove(&username);
+    }
+}
+
+async fn rewrite_file(storage: &StorageState, app_config: &MyAppConfig, quota: &UserQuota, keys: &UnlockedKeys, path: &Path) -> std::io::Result<()> {
+    let username = &quota.username;
+    let header = storage.read_prefix(username, path, HEADER_LEN).await?;
+    let key_id = header_key_id(&header);
+    if key_id == Some(keys.current) {
+        return Ok(());
+    }
+
+    let before = storage.metadata(username, path).await?.size;
+    let (staging_path, staging_file) = upload::create_staging_file(&app_config.directory, username).await?;
+    let reader = storage.reader(username, path).await?;
+    let written = match key_id {
+        Some(_) => {
+            let (writer, plain) = tokio::io::duplex(PIPE_SIZE);
+            let (decrypted, encrypted) = tokio::join!(
+                decrypt_stream(keys.clone(), reader, writer),
+                encrypt_stream(keys.current_key(), keys.current, plain, staging_file));
+            decrypted.and(encrypted)
+        },
+        None => encrypt_stream(keys.current_key(), keys.current, reader, staging_file).await,
+    };
+
+    // Leave the file alone if it failed, came back short or was replaced in
+    // the meantime.
+    let expected = match key_id {
+        Some(_) => plaintext_len(before),
+        None => before,
+    };
+    let written = match written {
+        Ok(written) if plaintext_len(written) == expected
+            && storage.metadata(username, path).await.map(|entry| entry.size).ok() == Some(before) => written,
+        Ok(_) => {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            return Ok(());
+        },
+        Err(error) => {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            return Err(error);
+        },
+    };
+
+    if let Err(error) = storage.replace(username, path, &staging_path).await {
+        let _ = tokio::fs::remove_file(&staging_path).await;
+        return Err(error);
+    }
+    quota.record(written as i64 - before as i64, 0);
+    Ok(())
+}
+
+fn start_rewrite(storage: &StorageState, app_config: &MyAppConfig, keyring: &KeyringState, quota: UserQuota, keys: UnlockedKeys) {
+    tokio::spawn(rewrite_files(storage.clone(), app_config.clone(), keyring.clone(), quota, keys));
+}
+
+fn check_password(database: &DatabaseState, username: &str, password: &str) -> Result<(), Status> {
+    let password_hash = match database.lock() {
+        Ok(connection) => users::find_password_hash(&connection, username),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    match password_hash {
+        Some(password_hash) if users::verify_password(password, &password_hash) => Ok(()),
+        _ => Err(Status::Forbidden),
+    }
+}
+
+#[get("/encryption")]
+pub async fn get_encryption(session: AuthenticatedSession, keys: UserKeys, database: &State<DatabaseState>,
+    keyring: &State<KeyringState>) -> Result<Json<EncryptionStatus>, Status> {
+    let key_id = match database.lock() {
+        Ok(connection) => connection.query_row(
+            "SELECT current_key_id FROM encryption WHERE username = ?1", params![session.username], |row| row.get(0))
+            .optional()
+            .map_err(|_| Status::InternalServerError)?,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let rewriting = keyring.lock().map(|keyring| keyring.rewriting.contains(&session.username)).unwrap_or(false);
+
+    Ok(Json(EncryptionStatus {
+        enabled: key_id.is_some(),
+        unlocked: matches!(keys, UserKeys::Unlocked(_)),
+        key_id,
+        rewriting,
+    }))
+}
+
+// Turns encryption on and encrypts the user's existing files in the background.
+#[post("/encryption", data = "<confirmation>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn enable_encryption(session: AuthenticatedSession, audit: Audit, confirmation: Json<PasswordConfirmation>, quota: UserQuota,
+    app_config: &State<MyAppConfig>, database: &State<DatabaseState>, keyring: &State<KeyringState>,
+    storage: &State<StorageState>) -> Status {
+    audit.record(&session.username, "enable_encryption", "");
+
+    if let Err(status) = check_password(database, &session.username, &confirmation.password) {
+        return status;
+    }
+
+    let new_key = match new_key(&session.username, &confirmation.password, None, None) {
+        Some(new_key) => new_key,
+        None => return Status::InternalServerError,
+    };
+    let keys = match database.lock() {
+        Ok(connection) if is_enabled(&connection, &session.username) => return Status::Conflict,
+        Ok(connection) => add_key(&connection, &session.username, new_key, None),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    let keys = match keys {
+        Ok(Some(keys)) => keys,
+        Ok(None) => return Status::Conflict,
+        Err(_) => return Status::InternalServerError,
+    };
+    match keyring.lock() {
+        Ok(mut keyring) => {
+            keyring.insert(session.session_id, &session.username, keys.clone());
+            keyring.rewriting.insert(session.username.clone());
+        },
+        Err(_) => return Status::InternalServerError,
+    }
+
+    start_rewrite(storage, app_config, keyring, quota, keys);
+    Status::Ok
+}
+
+// Starts a new data key for everything written from now on and re-encrypts
+// the current files with it in the background.
+#[post("/encryption/rotate", data = "<confirmation>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn rotate_keys(session: AuthenticatedSession, audit: Audit, confirmation: Json<PasswordConfirmation>, quota: UserQuota,
+    app_config: &State<MyAppConfig>, database: &State<DatabaseState>, keyring: &State<KeyringState>,
+    storage: &State<StorageState>) -> Status {
+    audit.record(&session.username, "rotate_keys", "");
+
+    if let Err(status) = check_password(database, &session.username, &confirmation.password) {
+        return status;
+    }
+
+    match keyring.lock() {
+        Ok(keyring) if keyring.rewriting.contains(&session.username) => return Status::Conflict,
+        Ok(_) => (),
+        Err(_) => return Status::InternalServerError,
+    }
+
+    let wrapped = match database.lock() {
+        Ok(connection) => load_wrapped(&connection, &session.username),
+        Err(_) => return Status::InternalServerError,
+    };
+    let (keys, new_key) = match wrapped.as_ref().and_then(|wrapped| unlock(wrapped, &session.username, &confirmation.password)) {
+        Some(keys) => match new_key(&session.username, &confirmation.password, wrapped.as_ref(), Some(&keys)) {
+            Some(new_key) => (keys, new_key),
+            None => return Status::InternalServerError,
+        },
+        None => return Status::Conflict,
+    };
+
+    let keys = match database.lock() {
+        Ok(connection) => add_key(&connection, &session.username, new_key, Some(keys)),
+        Err(_) => return Status::InternalServerError,
+    };
+    let keys = match keys {
+        Ok(Some(keys)) => keys,
+        Ok(None) => return Status::Conflict,
+        Err(_) => return Status::InternalServerError,
+    };
+    match keyring.lock() {
+        Ok(mut keyring) => {
+            keyring.update_user(&session.username, &keys);
+            keyring.insert(session.session_id, &session.username, keys.clone());
+            keyring.rewriting.insert(session.username.clone());
+        },
+        Err(_) => return Status::InternalServerError,
+    }
+
+    start_rewrite(storage, app_config, keyring, quota, keys);
+    Status::Ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::open_database;
+
+    fn test_keys() -> UnlockedKeys {
+        UnlockedKeys { current: 7, keys: HashMap::from([(7, generate_key()), (3, generate_key())]) }
+    }
+
+    async fn encrypt(keys: &UnlockedKeys, plain: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        let written = encrypt_stream(keys.current_key(), keys.current, plain, &mut encrypted).await.unwrap();
+        assert_eq!(written, encrypted.len() as u64);
+        encrypted
+    }
+
+    async fn decrypt(keys: &UnlockedKeys, encrypted: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        decrypt_stream(keys.clone(), encrypted, &mut plain).await?;
+        Ok(plain)
+    }
+
+    #[tokio::test]
+    async fn round_trips_at_segment_boundaries() {
+        let keys = test_keys();
+        for len in [0, 1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 1] {
+            let plain: Vec<u8> = (0..len).map(|index| index as u8).collect();
+            let encrypted = encrypt(&keys, &plain).await;
+            assert_eq!(header_key_id(&encrypted), Some(7));
+            assert_eq!(plaintext_len(encrypted.len() as u64), len as u64, "for {} bytes", len);
+            assert_eq!(decrypt(&keys, &encrypted).await.unwrap(), plain, "for {} bytes", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_files_of_older_keys() {
+        let keys = test_keys();
+        let older = UnlockedKeys { current: 3, keys: keys.keys.clone() };
+        let encrypted = encrypt(&older, b"old").await;
+        assert_eq!(header_key_id(&encrypted), Some(3));
+        assert_eq!(decrypt(&keys, &encrypted).await.unwrap(), b"old");
+
+        let without = UnlockedKeys { current: 7, keys: HashMap::from([(7, keys.keys[&7])]) };
+        assert!(decrypt(&without, &encrypted).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncation() {
+        let keys = test_keys();
+        let plain = vec![1u8; 2 * SEGMENT_SIZE + 1];
+        let encrypted = encrypt(&keys, &plain).await;
+
+        // Cut inside the last segment, and right after a whole one so the
+        // earlier segment has to pass for the last.
+        let cut = HEADER_LEN + 2 * (SEGMENT_SIZE + TAG_LEN);
+        assert!(decrypt(&keys, &encrypted[..encrypted.len() - 1]).await.is_err());
+        assert!(decrypt(&keys, &encrypted[..cut]).await.is_err());
+        assert!(decrypt(&keys, &encrypted[..HEADER_LEN]).await.is_err());
+        assert!(decrypt(&keys, &encrypted[..HEADER_LEN - 1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_on_changed_bytes() {
+        let keys = test_keys();
+        let encrypted = encrypt(&keys, &vec![1u8; SEGMENT_SIZE + 10]).await;
+
+        for position in [HEADER_LEN - 1, HEADER_LEN + 5, encrypted.len() - 1] {
+            let mut changed = encrypted.clone();
+            changed[position] ^= 1;
+            assert!(decrypt(&keys, &changed).await.is_err(), "at {}", position);
+        }
+    }
+
+    #[test]
+    fn computes_plaintext_len() {
+        let segment = (SEGMENT_SIZE + TAG_LEN) as u64;
+        let header = HEADER_LEN as u64;
+        assert_eq!(plaintext_len(header + TAG_LEN as u64), 0);
+        assert_eq!(plaintext_len(header + segment), SEGMENT_SIZE as u64);
+        assert_eq!(plaintext_len(header + segment + TAG_LEN as u64 + 1), SEGMENT_SIZE as u64 + 1);
+        // Files too short to be encrypted don't underflow.
+        assert_eq!(plaintext_len(0), 0);
+        assert_eq!(plaintext_len(header + 3), 0);
+    }
+
+    #[test]
+    fn reads_header_key_ids() {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&0xdeadbeefu32.to_be_bytes());
+        header.extend_from_slice(&[0u8; NONCE_PREFIX_LEN]);
+        assert_eq!(header_key_id(&header), Some(0xdeadbeef));
+        assert_eq!(header_key_id(&header[..HEADER_LEN - 1]), None);
+
+        header[0] = b'X';
+        assert_eq!(header_key_id(&header), None);
+    }
+
+    #[test]
+    fn wraps_keys_for_one_user_and_id() {
+        let wrapping_key = generate_key();
+        let key = generate_key();
+        let wrapped = wrap_key(&wrapping_key, "alice", 1, &key).unwrap();
+
+        assert_eq!(unwrap_key(&wrapping_key, "alice", 1, &wrapped), Some(key));
+        assert_eq!(unwrap_key(&generate_key(), "alice", 1, &wrapped), None);
+        assert_eq!(unwrap_key(&wrapping_key, "bob", 1, &wrapped), None);
+        assert_eq!(unwrap_key(&wrapping_key, "alice", 2, &wrapped), None);
+        assert_eq!(unwrap_key(&wrapping_key, "alice", 1, &wrapped[..40]), None);
+    }
+
+    #[test]
+    fn unlocks_only_with_the_password() {
+        let keys = test_keys();
+        let wrapped = rewrap(&keys, "alice", "correct horse").unwrap();
+        assert_eq!(wrapped.current, 7);
+
+        let unlocked = unlock(&wrapped, "alice", "correct horse").unwrap();
+        assert_eq!(unlocked.current, 7);
+        assert_eq!(unlocked.keys, keys.keys);
+        assert!(unlock(&wrapped, "alice", "battery staple").is_none());
+        assert!(unlock(&wrapped, "bob", "correct horse").is_none());
+    }
+
+    #[test]
+    fn stores_added_and_rewrapped_keys() {
+        let connection = open_database(":memory:").unwrap();
+        let new = new_key("alice", "first", None, None).unwrap();
+        let keys = add_key(&connection, "alice", new, None).unwrap().unwrap();
+        assert!(is_enabled(&connection, "alice"));
+
+        // Turning it on a second time is refused.
+        let again = new_key("alice", "first", None, None).unwrap();
+        assert!(add_key(&connection, "alice", again, None).unwrap().is_none());
+
+        let wrapped = load_wrapped(&connection, "alice").unwrap();
+        let rewrapped = rewrap(&unlock(&wrapped, "alice", "first").unwrap(), "alice", "second").unwrap();
+        assert!(save_wrapped(&connection, "alice", &rewrapped).unwrap());
+        let stored = load_wrapped(&connection, "alice").unwrap();
+        assert!(unlock(&stored, "alice", "first").is_none());
+        assert_eq!(unlock(&stored, "alice", "second").unwrap().keys, keys.keys);
+
+        // A key added with the old salt, or rewrapped keys missing the new
+        // one, would leave some keys under the wrong password.
+        let stale = new_key("alice", "first", Some(&wrapped), Some(&keys)).unwrap();
+        assert!(add_key(&connection, "alice", stale, Some(keys.clone())).unwrap().is_none());
+        let rotated = new_key("alice", "second", Some(&stored), Some(&keys)).unwrap();
+        let rotated = add_key(&connection, "alice", rotated, Some(keys.clone())).unwrap().unwrap();
+        assert_eq!(rotated.keys.len(), 2);
+        assert!(!save_wrapped(&connection, "alice", &rewrapped).unwrap());
+        assert_eq!(unlock(&load_wrapped(&connection, "alice").unwrap(), "alice", "second").unwrap().keys, rotated.keys);
+
+        discard_keys(&connection, "alice").unwrap();
+        assert!(!is_enabled(&connection, "alice"));
+        assert!(load_wrapped(&connection, "alice").is_none());
+    }
+}
@@ -0,0 +1,165 @@
+use rocket::http::RawStr;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::conflicts::Resolution;
+use crate::storage::StorageState;
+
+// Names are stored the way the user typed them, spaces, punctuation and
+// Unicode included, after NFC normalization so the same name always has the
+// same bytes. Only what the filesystem or a client can't cope with is
+// refused: control characters, separators, "." and "..", names Windows
+// reserves for devices, and names longer than most filesystems allow.
+const MAX_NAME_BYTES: usize = 255;
+const MAX_PATH_BYTES: usize = 4096;
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Where a new file or folder ended up, for the client to show.
+#[derive(Serialize)]
+pub struct StoredPath {
+    pub path: String,
+    pub conflict: Resolution,
+}
+
+impl StoredPath {
+    pub fn new(path: &Path) -> Json<StoredPath> {
+        StoredPath::resolved(path, Resolution::None)
+    }
+
+    pub fn resolved(path: &Path, conflict: Resolution) -> Json<StoredPath> {
+        Json(StoredPath { path: path.to_string_lossy().to_string(), conflict })
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+}
+
+// A single file or folder name, normalized, or None if it can't be used.
+pub fn check_name(name: &str) -> Option<String> {
+    let name: String = name.nfc().collect();
+    let valid = !name.is_empty()
+        && name != "." && name != ".."
+        && name.len() <= MAX_NAME_BYTES
+        && !name.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+        && !is_reserved(&name);
+    valid.then_some(name)
+}
+
+// A relative path of names, as sent for folder uploads. Both slashes
+// separate folders and empty or "." segments are skipped.
+pub fn check_relative_path(path: &str) -> Option<PathBuf> {
+    let mut checked = PathBuf::new();
+    for segment in path.split(['/', '\\']).filter(|segment| !segment.is_empty() && *segment != ".") {
+        checked.push(check_name(segment)?);
+    }
+    let valid = !checked.as_os_str().is_empty() && checked.as_os_str().len() <= MAX_PATH_BYTES;
+    valid.then_some(checked)
+}
+
+// Header values can only carry ASCII, so names may come percent-encoded.
+pub fn decode_header(value: &str) -> Option<String> {
+    RawStr::new(value).percent_decode().ok().map(|value| value.to_string())
+}
+
+// Names that would be the same file on a case-insensitive or
+// normalization-insensitive filesystem, as on Windows and macOS clients.
+fn same_name(a: &str, b: &str) -> bool {
+    a.nfc().flat_map(char::to_lowercase).eq(b.nfc().flat_map(char::to_lowercase))
+}
+
+enum Lookup {
+    Exact,
+    Collision(String),
+    Missing,
+}
+
+fn lookup(names: &[String], name: &str) -> Lookup {
+    if names.iter().any(|existing| existing == name) {
+        return Lookup::Exact;
+    }
+    match names.iter().find(|existing| same_name(existing, name)) {
+        Some(existing) => Lookup::Collision(existing.clone()),
+        None => Lookup::Missing,
+    }
+}
+
+pub fn local_entry_names(directory: &Path) -> std::io::Result<Vec<String>> {
+    std::fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+        .collect()
+}
+
+// The existing path that `path` would only differ from in case or
+// normalization, checked folder by folder, for backends through `Storage`.
+pub async fn find_collision(storage: &StorageState, username: &str, path: &Path) -> Option<PathBuf> {
+    let mut current = PathBuf::new();
+    for component in path.iter() {
+        let names = storage.entry_names(username, &current).await.ok()?;
+        match lookup(&names, &component.to_string_lossy()) {
+            Lookup::Exact => current.push(component),
+            Lookup::Collision(existing) => return Some(current.join(existing)),
+            Lookup::Missing => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_names_as_typed() {
+        assert_eq!(check_name("Report (final) #2.pdf").as_deref(), Some("Report (final) #2.pdf"));
+        assert_eq!(check_name("日本語 ファイル.txt").as_deref(), Some("日本語 ファイル.txt"));
+        assert_eq!(check_name(".hidden").as_deref(), Some(".hidden"));
+        assert_eq!(check_name("console.log").as_deref(), Some("console.log"));
+    }
+
+    #[test]
+    fn normalizes_names_to_nfc() {
+        assert_eq!(check_name("cafe\u{301}.txt").as_deref(), Some("caf\u{e9}.txt"));
+    }
+
+    #[test]
+    fn refuses_unusable_names() {
+        for name in ["", ".", "..", "a/b", "a\\b", "tab\there", "nul\0", "CON", "con.txt", "LPT1 .log"] {
+            assert_eq!(check_name(name), None, "{:?}", name);
+        }
+        assert!(check_name(&"a".repeat(MAX_NAME_BYTES)).is_some());
+        assert_eq!(check_name(&"a".repeat(MAX_NAME_BYTES + 1)), None);
+        // The limit is in bytes, not characters.
+        assert_eq!(check_name(&"é".repeat(MAX_NAME_BYTES / 2 + 1)), None);
+    }
+
+    #[test]
+    fn checks_relative_paths() {
+        assert_eq!(check_relative_path("photos/2024/beach.jpg"), Some(PathBuf::from("photos/2024/beach.jpg")));
+        assert_eq!(check_relative_path("/photos//./beach.jpg"), Some(PathBuf::from("photos/beach.jpg")));
+        assert_eq!(check_relative_path("photos\\beach.jpg"), Some(PathBuf::from("photos/beach.jpg")));
+        assert_eq!(check_relative_path("photos/../../etc/passwd"), None);
+        assert_eq!(check_relative_path("photos/AUX/beach.jpg"), None);
+        assert_eq!(check_relative_path(""), None);
+        assert_eq!(check_relative_path("/./"), None);
+
+        let long = vec!["a".repeat(200); MAX_PATH_BYTES / 200 + 1].join("/");
+        assert_eq!(check_relative_path(&long), None);
+    }
+
+    #[test]
+    fn finds_names_that_only_differ_in_case_or_normalization() {
+        let names = vec!["Notes.txt".to_string(), "caf\u{e9}".to_string()];
+        assert!(matches!(lookup(&names, "Notes.txt"), Lookup::Exact));
+        assert!(matches!(lookup(&names, "notes.TXT"), Lookup::Collision(existing) if existing == "Notes.txt"));
+        assert!(matches!(lookup(&names, "CAFE\u{301}"), Lookup::Collision(existing) if existing == "caf\u{e9}"));
+        assert!(matches!(lookup(&names, "other.txt"), Lookup::Missing));
+    }
+}
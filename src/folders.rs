@@ -0,0 +1,122 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{delete, patch, post, put, State};
+use std::path::{Path, PathBuf};
+
+use crate::audit::Audit;
+use crate::filenames::{self, StoredPath};
+use crate::quota::UserQuota;
+use crate::storage::{self, StorageState};
+use crate::AuthenticatedSession;
+
+async fn is_directory(storage: &StorageState, username: &str, path: &Path) -> bool {
+    matches!(storage.metadata(username, path).await, Ok(entry) if entry.is_dir)
+}
+
+// The user's root can't be renamed, moved or deleted, and the trash is only
+// managed through the /trash endpoints.
+fn folder_path(path: &Path) -> Option<PathBuf> {
+    storage::relative_path(path).filter(|path| !storage::is_trash(path))
+}
+
+#[post("/folder/<folder_path..>")]
+pub async fn create_folder(session: AuthenticatedSession, audit: Audit, folder_path: PathBuf,
+    storage: &State<StorageState>) -> Result<(Status, Json<StoredPath>), Status> {
+    audit.record(&session.username, "create_folder", &folder_path.to_string_lossy());
+
+    let relative = filenames::check_relative_path(&folder_path.to_string_lossy()).ok_or(Status::BadRequest)?;
+    let path = self::folder_path(&relative).ok_or(Status::Forbidden)?;
+
+    if storage.metadata(&session.username, &path).await.is_ok() || filenames::find_collision(storage, &session.username, &path).await.is_some() {
+        return Err(Status::Conflict);
+    }
+
+    match storage.create_folder(&session.username, &path).await {
+        Ok(_) => Ok((Status::Created, StoredPath::new(&path))),
+        Err(_) => Err(Status::ExpectationFailed),
+    }
+}
+
+#[delete("/folder/<folder_path..>")]
+pub async fn delete_folder(session: AuthenticatedSession, audit: Audit, folder_path: PathBuf, storage: &State<StorageState>,
+    quota: UserQuota) -> Status {
+    audit.record(&session.username, "delete", &folder_path.to_string_lossy());
+
+    let path = match self::folder_path(&folder_path) {
+        Some(path) => path,
+        None => return Status::Forbidden,
+    };
+
+    if !is_directory(storage, &session.username, &path).await {
+        return Status::NoContent;
+    }
+
+    // The trash keeps counting the bytes.
+    let usage = storage.usage(&session.username, &path).await.unwrap_or_default();
+    if storage.remove(&session.username, &path).await.is_err() {
+        return Status::ExpectationFailed;
+    }
+    quota.record(0, -(usage.files as i64));
+
+    Status::Ok
+}
+
+#[patch("/folder/<folder_path..>?<new_folder_name>")]
+pub async fn rename_folder(session: AuthenticatedSession, audit: Audit, folder_path: PathBuf, new_folder_name: String,
+    storage: &State<StorageState>) -> Result<Json<StoredPath>, Status> {
+    audit.record_with_target(&session.username, "rename", &folder_path.to_string_lossy(), &new_folder_name);
+
+    let old_path = self::folder_path(&folder_path).ok_or(Status::Forbidden)?;
+
+    // The new name has to be a single path segment.
+    let new_name = filenames::check_name(&new_folder_name).ok_or(Status::BadRequest)?;
+    let new_path = match self::folder_path(&old_path.with_file_name(new_name)) {
+        Some(path) if path.parent() == old_path.parent() => path,
+        _ => return Err(Status::BadRequest),
+    };
+
+    if !is_directory(storage, &session.username, &old_path).await {
+        return Err(Status::NoContent);
+    }
+
+    // Only differing in case from itself is fine.
+    let collision = filenames::find_collision(storage, &session.username, &new_path).await;
+    if storage.metadata(&session.username, &new_path).await.is_ok() || matches!(collision, Some(existing) if existing != old_path) {
+        return Err(Status::Conflict);
+    }
+
+    if storage.rename(&session.username, &old_path, &new_path).await.is_err() {
+        return Err(Status::ExpectationFailed);
+    }
+
+    Ok(StoredPath::new(&new_path))
+}
+
+#[put("/folder/move/<folder_path..>?<new_folder_path>")]
+pub async fn move_folder(session: AuthenticatedSession, audit: Audit, folder_path: PathBuf, new_folder_path: String,
+    storage: &State<StorageState>) -> Result<Json<StoredPath>, Status> {
+    audit.record_with_target(&session.username, "move", &folder_path.to_string_lossy(), &new_folder_path);
+
+    let old_path = self::folder_path(&folder_path).ok_or(Status::Forbidden)?;
+    let relative = filenames::check_relative_path(&new_folder_path).ok_or(Status::BadRequest)?;
+    let new_path = self::folder_path(&relative).ok_or(Status::Forbidden)?;
+
+    // A folder can't be moved inside itself.
+    if new_path.starts_with(&old_path) {
+        return Err(Status::BadRequest);
+    }
+
+    if !is_directory(storage, &session.username, &old_path).await {
+        return Err(Status::NoContent);
+    }
+
+    if storage.metadata(&session.username, &new_path).await.is_ok() || filenames::find_collision(storage, &session.username, &new_path).await.is_some() {
+        return Err(Status::Conflict);
+    }
+
+    if storage.rename(&session.username, &old_path, &new_path).await.is_err() {
+        return Err(Status::ExpectationFailed);
+    }
+
+    Ok(StoredPath::new(&new_path))
+}
@@ -0,0 +1,111 @@
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+use crate::storage::{self, StorageState};
+use crate::trash::TRASH_FOLDER;
+use crate::AuthenticatedSession;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Directory,
+    File,
+}
+
+#[derive(Serialize)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: u64,
+    pub mime: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DirectoryListing {
+    pub path: String,
+    pub entries: Vec<DirectoryEntry>,
+    pub offset: usize,
+    pub limit: usize,
+    pub total: usize,
+}
+
+pub async fn read_entries(storage: &StorageState, username: &str, path: &Path) -> std::io::Result<Vec<DirectoryEntry>> {
+    let entries = storage.entries(username, path).await?;
+    Ok(entries.into_iter().map(|(name, entry)| match entry.is_dir {
+        true => DirectoryEntry { name, kind: EntryKind::Directory, size: 0, modified: entry.modified, mime: None },
+        false => {
+            let mime = Path::new(&name).extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(ContentType::from_extension)
+                .map(|content_type| content_type.to_string());
+            DirectoryEntry { name, kind: EntryKind::File, size: entry.size, modified: entry.modified, mime }
+        },
+    }).collect())
+}
+
+// Directories always come before files; `sort` only orders within each group.
+fn sort_entries(entries: &mut [DirectoryEntry], sort: &str, descending: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort {
+            "size" => a.size.cmp(&b.size),
+            "modified" => a.modified.cmp(&b.modified),
+            "type" => a.mime.cmp(&b.mime),
+            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+        };
+        let ordering = if descending { ordering.reverse() } else { ordering };
+        match a.kind.cmp(&b.kind) {
+            Ordering::Equal => ordering.then_with(|| a.name.cmp(&b.name)),
+            kind_ordering => kind_ordering,
+        }
+    });
+}
+
+#[get("/dir/<dir_path..>?<offset>&<limit>&<sort>&<order>")]
+pub async fn get_directory(session: AuthenticatedSession, dir_path: PathBuf, offset: Option<usize>, limit: Option<usize>,
+    sort: Option<String>, order: Option<String>, storage: &State<StorageState>) -> Result<Json<DirectoryListing>, Status> {
+    let is_root = dir_path.as_os_str().is_empty();
+    let path = match is_root {
+        true => PathBuf::new(),
+        false => storage::relative_path(&dir_path).ok_or(Status::Forbidden)?,
+    };
+
+    // The trash is listed separately through /trash.
+    if storage::is_trash(&path) {
+        return Err(Status::Forbidden);
+    }
+
+    let mut entries = match read_entries(storage, &session.username, &path).await {
+        Ok(entries) => entries,
+        // A user who has never uploaded anything has no directory yet.
+        Err(_) if is_root => Vec::new(),
+        Err(_) => return Err(Status::NotFound),
+    };
+
+    if is_root {
+        entries.retain(|entry| entry.name != TRASH_FOLDER);
+    }
+
+    let descending = order.as_deref() == Some("desc");
+    sort_entries(&mut entries, sort.as_deref().unwrap_or("name"), descending);
+
+    let total = entries.len();
+    let offset = offset.unwrap_or(0).min(total);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let entries = entries.into_iter().skip(offset).take(limit).collect();
+
+    Ok(Json(DirectoryListing {
+        path: dir_path.to_string_lossy().to_string(),
+        entries,
+        offset,
+        limit,
+        total,
+    }))
+}
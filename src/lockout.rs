@@ -0,0 +1,172 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{delete, get, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::net::IpAddr;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::unix_timestamp;
+use crate::users::AdminSession;
+
+// Failed logins are counted per username and per client IP in the database,
+// so they survive restarts. Past a threshold the username or IP is locked
+// out, for twice as long with every further failure. Counting by username
+// slows down guessing against one account even when the IPs keep changing.
+// Counts are forgotten a day after the last failure.
+const USER_THRESHOLD: u64 = 5;
+const IP_THRESHOLD: u64 = 20;
+const BASE_LOCKOUT_SECONDS: u64 = 60;
+const MAX_LOCKOUT_SECONDS: u64 = 60 * 60;
+const FAILURE_MEMORY_SECONDS: u64 = 24 * 60 * 60;
+const AUDIT_RETENTION_SECONDS: u64 = 30 * 24 * 60 * 60;
+const AUDIT_PAGE_SIZE: i64 = 200;
+
+#[derive(Serialize)]
+pub struct LoginFailure {
+    pub username: String,
+    pub ip: Option<String>,
+    pub reason: String,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Subject {
+    User,
+    Ip,
+}
+
+impl Subject {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Subject::User => "user",
+            Subject::Ip => "ip",
+        }
+    }
+
+    fn threshold(&self) -> u64 {
+        match self {
+            Subject::User => USER_THRESHOLD,
+            Subject::Ip => IP_THRESHOLD,
+        }
+    }
+}
+
+fn lockout_seconds(failures: u64, threshold: u64) -> u64 {
+    let doublings = (failures - threshold).min(16) as u32;
+    (BASE_LOCKOUT_SECONDS << doublings).min(MAX_LOCKOUT_SECONDS)
+}
+
+fn locked_until(connection: &Connection, subject: Subject, value: &str) -> rusqlite::Result<u64> {
+    connection.query_row(
+        "SELECT locked_until FROM login_throttle WHERE kind = ?1 AND subject = ?2",
+        params![subject.as_str(), value],
+        |row| row.get::<_, i64>(0))
+        .optional()
+        .map(|locked_until| locked_until.unwrap_or(0) as u64)
+}
+
+fn count_failure(connection: &Connection, subject: Subject, value: &str, now: u64) -> rusqlite::Result<()> {
+    let previous: Option<(i64, i64)> = connection.query_row(
+        "SELECT failures, last_failure_at FROM login_throttle WHERE kind = ?1 AND subject = ?2",
+        params![subject.as_str(), value],
+        |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+
+    let failures = match previous {
+        Some((failures, last_failure_at)) if last_failure_at as u64 + FAILURE_MEMORY_SECONDS > now => failures as u64 + 1,
+        _ => 1,
+    };
+    let locked_until = match failures >= subject.threshold() {
+        true => now + lockout_seconds(failures, subject.threshold()),
+        false => 0,
+    };
+
+    connection.execute(
+        "INSERT INTO login_throttle (kind, subject, failures, last_failure_at, locked_until) VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (kind, subject) DO UPDATE SET failures = excluded.failures,
+            last_failure_at = excluded.last_failure_at, locked_until = excluded.locked_until",
+        params![subject.as_str(), value, failures as i64, now as i64, locked_until as i64])?;
+    Ok(())
+}
+
+// Seconds until the username or the IP may try again, if either is locked out.
+pub fn check(connection: &Connection, username: &str, ip: Option<IpAddr>) -> Option<u64> {
+    let now = unix_timestamp();
+    let mut locked_until = locked_until(connection, Subject::User, username).unwrap_or(0);
+    if let Some(ip) = ip {
+        locked_until = locked_until.max(self::locked_until(connection, Subject::Ip, &ip.to_string()).unwrap_or(0));
+    }
+    (locked_until > now).then(|| locked_until - now)
+}
+
+// Attempts made while locked out are audited but don't extend the lockout,
+// otherwise nobody could ever get the account back by waiting.
+pub fn record_failure(connection: &Connection, username: &str, ip: Option<IpAddr>, reason: &str) {
+    let now = unix_timestamp();
+    let ip = ip.map(|ip| ip.to_string());
+    tracing::warn!(username, ip = ip.as_deref().unwrap_or("unknown"), reason, "Failed login");
+
+    let _ = connection.execute(
+        "INSERT INTO login_failures (username, ip, reason, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![username, ip, reason, now as i64]);
+    if reason == "locked" {
+        return;
+    }
+
+    let _ = count_failure(connection, Subject::User, username, now);
+    if let Some(ip) = ip {
+        let _ = count_failure(connection, Subject::Ip, &ip, now);
+    }
+}
+
+// A successful login clears the username's count. The IP's count is left to
+// fade, so one valid account can't be used to keep resetting it.
+pub fn record_success(connection: &Connection, username: &str) {
+    let _ = connection.execute(
+        "DELETE FROM login_throttle WHERE kind = ?1 AND subject = ?2",
+        params![Subject::User.as_str(), username]);
+}
+
+pub fn remove_expired(connection: &Connection) -> rusqlite::Result<()> {
+    let now = unix_timestamp() as i64;
+    connection.execute(
+        "DELETE FROM login_throttle WHERE last_failure_at <= ?1 AND locked_until <= ?2",
+        params![now - FAILURE_MEMORY_SECONDS as i64, now])?;
+    connection.execute("DELETE FROM login_failures WHERE created_at <= ?1", params![now - AUDIT_RETENTION_SECONDS as i64])?;
+    Ok(())
+}
+
+#[get("/admin/login-failures?<username>")]
+pub async fn get_login_failures(_admin: AdminSession, username: Option<String>,
+    database: &State<DatabaseState>) -> Result<Json<Vec<LoginFailure>>, Status> {
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    let mut statement = connection.prepare(
+        "SELECT username, ip, reason, created_at FROM login_failures
+        WHERE ?1 IS NULL OR username = ?1 ORDER BY created_at DESC, id DESC LIMIT ?2")
+        .map_err(|_| Status::InternalServerError)?;
+    let failures = statement.query_map(params![username, AUDIT_PAGE_SIZE], |row| Ok(LoginFailure {
+            username: row.get(0)?,
+            ip: row.get(1)?,
+            reason: row.get(2)?,
+            created_at: row.get::<_, i64>(3)? as u64,
+        }))
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<LoginFailure>>>())
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(failures))
+}
+
+// Lets a locked out user try again right away.
+#[delete("/admin/users/<username>/lockout")]
+pub async fn clear_lockout(admin: AdminSession, audit: Audit, username: String, database: &State<DatabaseState>) -> Status {
+    audit.record_with_target(&admin.username, "clear_lockout", "", &username);
+
+    match database.lock() {
+        Ok(connection) => {
+            record_success(&connection, &username);
+            Status::Ok
+        },
+        Err(_) => Status::InternalServerError,
+    }
+}
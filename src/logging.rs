@@ -0,0 +1,276 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{self, FromRequest};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Data, Request, Response};
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+use rand::prelude::*;
+
+// Leveled logging through `tracing`, as logfmt or JSON lines on stdout or
+// appended to `file`. Rocket's own messages are routed through it as well.
+// `level` takes `RUST_LOG` style directives, e.g. "info,hello_rocket::webdav=debug",
+// and `RUST_LOG` overrides it when set.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Logfmt,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoggingConfig {
+    #[serde(default = "default_level")]
+    pub level: String,
+    #[serde(default = "default_format")]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+// Rocket logs every request with its full URI at info, so it is kept to
+// warnings; the request lines below replace it. Its per-request details
+// (target "_") are only let through for errors.
+fn default_level() -> String {
+    "info,rocket=warn,_=error".to_string()
+}
+
+fn default_format() -> LogFormat {
+    LogFormat::Logfmt
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: default_level(),
+            format: default_format(),
+            file: None,
+        }
+    }
+}
+
+pub fn init(config: &LoggingConfig) -> std::io::Result<()> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .unwrap_or_else(|_| EnvFilter::new(default_level()));
+
+    let output = match &config.file {
+        Some(file) => Output::File(Mutex::new(OpenOptions::new().create(true).append(true).open(file)?)),
+        None => Output::Stdout,
+    };
+    let writer = RedactingWriter(output);
+
+    // Rocket colors its messages unless its own logger is the one installed.
+    rocket::yansi::Paint::disable();
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer).with_ansi(false);
+    let installed = match config.format {
+        LogFormat::Json => builder.json().flatten_event(true).with_current_span(false).try_init(),
+        LogFormat::Logfmt => builder.event_format(Logfmt).try_init(),
+    };
+    installed.map_err(|error| std::io::Error::other(error.to_string()))
+}
+
+// Blanks out what must never reach a log file: share tokens, which are the
+// whole credential of a share link, `password=` query parameters, and API
+// tokens. Every line goes through here, Rocket's included.
+pub fn redact(line: &str) -> String {
+    let line = redact_after(line, "/s/", &['/', '?', '#', ' ', '"', '\n']);
+    let line = redact_after(&line, "password=", &['&', ' ', '"', '\n']);
+    let line = redact_after(&line, "Bearer ", &[' ', '"', '\n']);
+    redact_after(&line, "mydrive_", &[' ', '"', '\n', '&'])
+}
+
+fn redact_after(line: &str, marker: &str, stops: &[char]) -> String {
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(index) = rest.find(marker) {
+        let (before, after) = rest.split_at(index + marker.len());
+        redacted.push_str(before);
+        let end = after.find(stops).unwrap_or(after.len());
+        if end > 0 {
+            redacted.push_str(REDACTED);
+        }
+        rest = &after[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+enum Output {
+    Stdout,
+    File(Mutex<std::fs::File>),
+}
+
+struct RedactingWriter(Output);
+
+struct LineWriter<'a>(&'a Output);
+
+impl<'a> MakeWriter<'a> for RedactingWriter {
+    type Writer = LineWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LineWriter(&self.0)
+    }
+}
+
+// tracing hands over each formatted event in a single write.
+impl Write for LineWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let line = redact(&String::from_utf8_lossy(buf));
+        match self.0 {
+            Output::Stdout => std::io::stdout().lock().write_all(line.as_bytes())?,
+            Output::File(file) => match file.lock() {
+                Ok(mut file) => file.write_all(line.as_bytes())?,
+                Err(_) => return Err(std::io::Error::other("log file lock poisoned")),
+            },
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0 {
+            Output::Stdout => std::io::stdout().flush(),
+            Output::File(_) => Ok(()),
+        }
+    }
+}
+
+// `time=... level=info target=... msg="..." key=value`, quoting values that need it.
+struct Logfmt;
+
+// Messages from the `log` crate, Rocket's included, come with their origin
+// as `log.*` fields; `log.target` stands in for the target and the rest is dropped.
+#[derive(Default)]
+struct LogfmtFields {
+    target: Option<String>,
+    fields: String,
+}
+
+fn push_value(line: &mut String, value: &str) {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        line.push_str(value);
+    } else {
+        let _ = write!(line, "{:?}", value);
+    }
+}
+
+impl Visit for LogfmtFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let key = match field.name() {
+            "message" => "msg",
+            "log.target" => {
+                self.target = Some(value.to_string());
+                return;
+            },
+            name if name.starts_with("log.") => return,
+            name => name,
+        };
+        let _ = write!(self.fields, " {}=", key);
+        push_value(&mut self.fields, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Logfmt
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        let mut time = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut time))?;
+
+        let mut fields = LogfmtFields::default();
+        event.record(&mut fields);
+
+        let metadata = event.metadata();
+        let mut line = format!("time={} level={} target=", time, metadata.level().as_str().to_lowercase());
+        push_value(&mut line, fields.target.as_deref().unwrap_or(metadata.target()));
+        writeln!(writer, "{}{}", line, fields.fields)
+    }
+}
+
+// Set by `RequestLogger` for every request, echoed back in `X-Request-Id` and
+// written with the request line and its audit record. A sane id sent by a
+// proxy in front is kept.
+pub struct RequestId(pub String);
+
+struct RequestStart(Instant);
+
+pub fn generate_request_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 8]>())
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn request_id(request: &Request<'_>) -> String {
+    request.local_cache(|| RequestId(generate_request_id())).0.clone()
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RequestId(request_id(request)))
+    }
+}
+
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logging",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let id = match request.headers().get_one(REQUEST_ID_HEADER) {
+            Some(id) if is_valid_request_id(id) => id.to_string(),
+            _ => generate_request_id(),
+        };
+        request.local_cache(|| RequestId(id));
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = request_id(request);
+        let latency = request.local_cache(|| RequestStart(Instant::now())).0.elapsed();
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.clone()));
+
+        // The query string is left out, it can carry share passwords.
+        let status = response.status().code;
+        let path = request.uri().path().to_string();
+        let ip = request.client_ip().map(|ip| ip.to_string()).unwrap_or_default();
+        let latency_ms = latency.as_micros() as f64 / 1000.0;
+        if status >= 500 {
+            tracing::event!(Level::ERROR, request_id = %request_id, method = %request.method(), path = %path, status, latency_ms, ip = %ip, "request");
+        } else {
+            tracing::event!(Level::INFO, request_id = %request_id, method = %request.method(), path = %path, status, latency_ms, ip = %ip, "request");
+        }
+    }
+}
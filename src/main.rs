@@ -1,18 +1,42 @@
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use async_recursion::async_recursion;
+pub mod archive;
+pub mod audit;
+mod conflicts;
+pub mod copy;
+mod database;
+mod dedup;
+pub mod encryption;
+mod filenames;
+pub mod folders;
+pub mod listing;
+pub mod lockout;
+mod logging;
+mod range;
+mod session;
+pub mod quota;
+pub mod ratelimit;
+mod s3;
+pub mod shares;
+mod storage;
+pub mod tokens;
+pub mod totp;
+pub mod trash;
+pub mod upload;
+pub mod users;
+pub mod versions;
+mod webdav;
+
 use rocket::request::FromRequest;
 use rocket::time::OffsetDateTime;
 use rocket::{get, post, delete, patch, put, catch, routes, State, Request, uri, catchers};
 use rocket::http::{Status, Cookie, CookieJar};
 use tokio::fs::File;
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader};
 use std::num::ParseIntError;
-use std::path::{PathBuf, Path};
-use std::{process::Command, net::IpAddr};
+use std::path::{Component, PathBuf, Path};
+use std::{process::Command, net::{IpAddr, SocketAddr}};
 use rocket::http::{Header};
 use rocket::response::{Responder, Redirect};
-use rocket::response::status::NoContent;
+use rocket::response::status::{Custom, NoContent};
 use rocket::fs::{NamedFile};
 use rocket::serde::{json::Json, Serialize, Deserialize};
 use std::*;
@@ -20,140 +44,152 @@ use std::{result::Result};
 use rocket::data::{ByteUnit, Data};
 use rocket::request;
 use std::time::Duration;
-use std::time::Instant;
 use std::sync::{Arc, Mutex, RwLock};
 use rand::prelude::*;
 use rocket::fs::FileServer;
+use database::{open_database, DatabaseState};
+use quota::{QuotaState, QuotaTracker, UserQuota};
+use encryption::{KeyringState, UnlockedKeys, UserKeys};
+use conflicts::{ConflictPolicy, Resolution};
+use copy::CopyJobsState;
+use filenames::StoredPath;
+use storage::{DownloadRequest, FileDownload, LocalStorage, StorageState};
+use audit::{Audit, AuditFairing, AuditLog, AuditState};
+use logging::RequestLogger;
+use ratelimit::{RateLimitFairing, RateLimitState, RateLimits};
+use session::{MemorySessionBackend, SessionBackend, SessionStore, SessionStoreState, SqliteSessionBackend};
 
-type RateLimiterState = Arc<Mutex<RateLimiter>>;
-
-pub struct RateLimiter {
-    pub limit: u32,
-    pub interval: Duration,
-    pub request_count: HashMap<String, (u32, Instant)>,
+#[derive(Responder)]
+struct CustomHeaderResponder<'a, T> {
+    inner: T,
+    header: Header<'a>,
 }
 
-impl RateLimiter {
-    pub fn should_allow(&mut self, ip: &str) -> bool {
-        let now: std::time::Instant = std::time::Instant::now();
-        let entry = self.request_count.entry(ip.to_owned()).or_insert((0, now));
-
-        if now.duration_since(entry.1) > self.interval {
-            *entry = (1, now);
-            true
-        } else if entry.0 < self.limit {
-            entry.0 += 1;
-            true
-        } else {
-            false
-        }
-    }   
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MyAppConfig {
+    pub directory: String,
+    #[serde(default = "default_database")]
+    pub database: String,
+    #[serde(default = "default_session_backend")]
+    pub session_backend: String,
+    #[serde(default = "default_session_ttl_minutes")]
+    pub session_ttl_minutes: u64,
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub quota_files: Option<u64>,
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u64,
+    #[serde(default)]
+    pub webdav_port: Option<u16>,
+    #[serde(default = "default_max_versions")]
+    pub max_versions: usize,
+    #[serde(default = "default_version_retention_days")]
+    pub version_retention_days: u64,
+    #[serde(default = "default_storage_mode")]
+    pub storage_mode: String,
+    #[serde(default = "default_storage_backend")]
+    pub storage_backend: String,
+    #[serde(default)]
+    pub s3: Option<s3::S3Config>,
+    #[serde(default)]
+    pub rate_limit: ratelimit::RateLimitConfig,
+    #[serde(default = "default_audit_log")]
+    pub audit_log: String,
+    #[serde(default = "default_audit_log_max_bytes")]
+    pub audit_log_max_bytes: u64,
+    #[serde(default = "default_audit_log_files")]
+    pub audit_log_files: usize,
+    #[serde(default)]
+    pub logging: logging::LoggingConfig,
 }
 
-impl Clone for RateLimiter {
-    fn clone(&self) -> Self {
-        Self { limit: self.limit.clone(), 
-            interval: self.interval.clone(), 
-            request_count: self.request_count.clone() }
-    }
+fn default_database() -> String {
+    "mydb.db".to_string()
 }
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for RateLimiter {
-    type Error = ();
-
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let client_ip = request.client_ip().map(|ip| ip.to_string()).unwrap_or_default();
+fn default_session_backend() -> String {
+    "sqlite".to_string()
+}
 
-        let rate_limiter_state = request.rocket().state::<RateLimiterState>().unwrap();
+fn default_session_ttl_minutes() -> u64 {
+    45
+}
 
-        match rate_limiter_state.lock() {
-            Ok(mut rate_limiter_mutex) => {
-                if rate_limiter_mutex.should_allow(&client_ip) {
-                    request::Outcome::Success(rate_limiter_mutex.clone())
-                } else {
-                    request::Outcome::Failure((Status::TooManyRequests, ()))
-                }
-            },
-            Err(_) => request::Outcome::Failure((Status::InternalServerError, ())),
-        }
-    }
+fn default_trash_retention_days() -> u64 {
+    30
 }
-        
-#[derive(Responder)]
-struct CustomHeaderResponder<'a, T> {
-    inner: T,
-    header: Header<'a>,
+
+fn default_max_versions() -> usize {
+    10
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct MyAppConfig {
-    directory: String,
+fn default_version_retention_days() -> u64 {
+    30
 }
 
-#[catch(401)]
-fn aunthorized_access(_req: &Request) -> Redirect {
-    Redirect::to(uri!("/login"))
+fn default_storage_mode() -> String {
+    "plain".to_string()
 }
 
-#[get("/login")]
-async fn get_login(_rate_limiter: RateLimiter) -> Option<NamedFile> {
-    NamedFile::open(Path::new("pages/login.html")).await.ok()
+fn default_storage_backend() -> String {
+    "local".to_string()
 }
 
-#[derive(Deserialize)]
-pub struct User {
-    pub username: String, 
-    pub password: String,
+fn default_audit_log() -> String {
+    "audit.log".to_string()
 }
 
-async fn load_users_from_csv() -> Vec<User> {
-    let file = std::fs::File::open("users.csv").expect("Failed to find");
-    let reader = BufReader::new(file);
-    let mut users = Vec::new();
+fn default_audit_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
 
-    for line in reader.lines() {
-        let line = line.unwrap();
-        let parts : Vec<&str> = line.split("|").collect();
+fn default_audit_log_files() -> usize {
+    5
+}
 
-        if parts.len() == 2 {
-            users.push(User {
-                username: parts[0].to_string(),
-                password: parts[1].to_string(),
-            })
+// Joins a client supplied relative path onto the user's directory, refusing
+// anything that could climb back out of it.
+pub fn resolve_user_path(user_directory: &Path, relative: &Path) -> Option<PathBuf> {
+    let mut path = user_directory.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            _ => return None,
         }
     }
-
-    users
+    Some(path)
 }
 
-pub struct SessionStore {
-    sessions: HashMap<u64, String>,
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
 
-type SessionStoreState = Arc<RwLock<SessionStore>>;
-
-impl SessionStore {
-    pub fn new() -> Self {
-        SessionStore {
-            sessions: HashMap::new(),
-        }
-    }
-
-    pub fn insert(&mut self, session_id: u64, username: String) {
-        self.sessions.insert(session_id, username);
+// Scripts using API tokens get the status instead of the login page.
+#[catch(401)]
+fn aunthorized_access(req: &Request) -> Result<Redirect, Custom<()>> {
+    match req.headers().get_one("Authorization") {
+        Some(_) => Err(Custom(Status::Unauthorized, ())),
+        None => Ok(Redirect::to(uri!("/login"))),
     }
+}
 
-    pub fn remove(&mut self, session_id: u64) {
-        self.sessions.remove(&session_id);
-    }
+#[get("/login")]
+async fn get_login() -> Option<NamedFile> {
+    NamedFile::open(Path::new("pages/login.html")).await.ok()
+}
 
-    pub fn get(&self, session_id: u64) -> Option<String> {
-        self.sessions.get(&session_id).cloned()
-    }
+#[derive(Deserialize)]
+pub struct User {
+    pub username: String, 
+    pub password: String,
 }
 
-fn generate_session_id(session_store_state : &State<SessionStoreState>) -> u64 {
+fn generate_session_id(session_store_state : &SessionStoreState) -> u64 {
     match session_store_state.read() {
         Ok(session_store) => {
             let mut session_id = rand::thread_rng().gen();
@@ -169,6 +205,29 @@ fn generate_session_id(session_store_state : &State<SessionStoreState>) -> u64 {
 pub struct AuthenticatedSession {
     pub session_id: u64,
     pub username: String,
+    // Set when the request came with an API token instead of a session cookie.
+    pub token_scope: Option<tokens::TokenScope>,
+}
+
+fn build_session_cookie(session_id: u64, ttl: Duration) -> Cookie<'static> {
+    let mut cookie = Cookie::new("session_id", session_id.to_string());
+    cookie.set_path("/");
+    cookie.set_secure(true);
+    cookie.set_http_only(false);
+    cookie.set_expires(OffsetDateTime::checked_add(OffsetDateTime::now_utc(), rocket::time::Duration::seconds(ttl.as_secs() as i64)));
+    cookie
+}
+
+pub fn start_session(cookies: &CookieJar<'_>, session_store_state: &SessionStoreState, keyring: &KeyringState,
+    username: &str, keys: Option<UnlockedKeys>) {
+    let session_id = generate_session_id(session_store_state);
+    if let (Some(keys), Ok(mut keyring)) = (keys, keyring.lock()) {
+        keyring.insert(session_id, username, keys);
+    }
+
+    let mut session_store = session_store_state.write().unwrap();
+    session_store.insert(session_id, username.to_string());
+    cookies.add_private(build_session_cookie(session_id, session_store.ttl()));
 }
 
 fn get_session_id_from_cookie_value(cookie_value: &str) -> Result<u64, ParseIntError> {
@@ -187,14 +246,20 @@ impl<'r> FromRequest<'r> for AuthenticatedSession {
             Some(cookie) => {
                 let session_store_state = request.rocket().state::<SessionStoreState>().unwrap();
                 match session_store_state.write() {
-                        Ok(session_store) => {
+                        Ok(mut session_store) => {
                             match get_session_id_from_cookie_value(cookie.value()) {
                                 Ok(session_id) => {
                                     match session_store.get(session_id) {
-                                        Some(username) => request::Outcome::Success(AuthenticatedSession {
-                                            session_id: session_id,
-                                            username: username.to_string(),
-                                        }),
+                                        Some(username) => {
+                                            if session_store.renew(session_id) {
+                                                request.cookies().add_private(build_session_cookie(session_id, session_store.ttl()));
+                                            }
+                                            request::Outcome::Success(AuthenticatedSession {
+                                                session_id,
+                                                username: username.to_string(),
+                                                token_scope: None,
+                                            })
+                                        },
                                         None => request::Outcome::Failure((Status::Unauthorized, ()))
                                     }
                                 },
@@ -205,51 +270,77 @@ impl<'r> FromRequest<'r> for AuthenticatedSession {
                         Err(_) => request::Outcome::Failure((Status::InternalServerError, ())),
                 }
             },
-            None => request::Outcome::Failure((Status::Unauthorized, ()))
+            None => match request.headers().get_one("Authorization") {
+                Some(authorization) => tokens::authenticate(request, authorization),
+                None => request::Outcome::Failure((Status::Unauthorized, ()))
+            }
         }
     }
 }
 
-async fn is_valid_credentials(username: String, password: String) -> bool {
-    let users = load_users_from_csv().await;
-    println!("num users: {}", users.len());
-    for user in users {
-        println!("username for a user: {}", user.username);
-         if user.username.eq(&username) {
-            let password_hash = PasswordHash::new(&user.password).unwrap();
-            match Argon2::default().verify_password(password.as_bytes(), &password_hash) {
-                Ok(_) => return true,
-                Err(_) => return false,
-            }
-         }
+async fn is_valid_credentials(database: &DatabaseState, username: &str, password: &str) -> bool {
+    let password_hash = match database.lock() {
+        Ok(connection) => users::find_password_hash(&connection, username),
+        Err(_) => None,
+    };
+
+    match password_hash {
+        Some(password_hash) => users::verify_password(password, &password_hash),
+        None => false,
     }
-    false
 }
 
 #[post("/login", data = "<form>")]
-async fn post_login(session: Option<AuthenticatedSession>, form: rocket::serde::json::Json<User>, 
-    _rate_limiter: RateLimiter, cookies: &CookieJar<'_>, session_store_state: &State<SessionStoreState>) -> Status {
+#[allow(clippy::too_many_arguments)]
+async fn post_login(session: Option<AuthenticatedSession>, form: rocket::serde::json::Json<User>, audit: Audit,
+    client_ip: Option<IpAddr>, cookies: &CookieJar<'_>, session_store_state: &State<SessionStoreState>,
+    database: &State<DatabaseState>, keyring: &State<KeyringState>, challenges: &State<totp::ChallengeState>) -> Status {
     match session {
         Some(_as) => Status::Ok,
         None => {
             let username = form.username.to_string();
             let password = form.password.to_string();
 
-            if is_valid_credentials(username.clone(), password).await {
-                let session_id = generate_session_id(session_store_state);
-                
-                cookies.add_private(Cookie::build("session_id", session_id.to_string())
-                .path("/")
-                .secure(true)
-                .http_only(false)
-                .expires(OffsetDateTime::checked_add(OffsetDateTime::now_utc(), rocket::time::Duration::minutes(45)))
-                .finish());
+            // Locked out logins aren't even checked, so guessing gets nowhere while it lasts.
+            match database.lock() {
+                Ok(connection) => if lockout::check(&connection, &username, client_ip).is_some() {
+                    lockout::record_failure(&connection, &username, client_ip, "locked");
+                    audit.record(&username, "login_failed", "");
+                    return Status::TooManyRequests;
+                },
+                Err(_) => return Status::InternalServerError,
+            }
 
+            if is_valid_credentials(database, &username, &password).await {
+                let (wrapped, two_factor) = match database.lock() {
+                    Ok(connection) => {
+                        // With two-step login the count is only cleared once the code checks out.
+                        let two_factor = totp::is_enabled(&connection, &username);
+                        if !two_factor {
+                            lockout::record_success(&connection, &username);
+                        }
+                        (encryption::load_wrapped(&connection, &username), two_factor)
+                    },
+                    Err(_) => return Status::InternalServerError,
+                };
+                // Encryption keys can only be unwrapped while the password is at hand.
+                let keys = wrapped.and_then(|wrapped| encryption::unlock(&wrapped, &username, &password));
+
+                // 202 asks the login page for a code, see totp::verify_login,
+                // which records the login once the code checks out.
+                if two_factor {
+                    totp::start_challenge(challenges, cookies, &username, keys);
+                    return Status::Accepted;
+                }
 
-                let mut session_store = session_store_state.write().unwrap();
-                session_store.insert(session_id.clone(), username.clone());
+                start_session(cookies, session_store_state, keyring, &username, keys);
+                audit.record(&username, "login", "");
                 Status::Ok
             } else {
+                if let Ok(connection) = database.lock() {
+                    lockout::record_failure(&connection, &username, client_ip, "password");
+                }
+                audit.record(&username, "login_failed", "");
                 Status::Forbidden
             }
         }
@@ -257,7 +348,12 @@ async fn post_login(session: Option<AuthenticatedSession>, form: rocket::serde::
 }
 
 #[get("/logout")]
-fn logout(session: AuthenticatedSession, session_store_state: &State<SessionStoreState>) -> Redirect {
+fn logout(session: AuthenticatedSession, audit: Audit, session_store_state: &State<SessionStoreState>, keyring: &State<KeyringState>) -> Redirect {
+    audit.record(&session.username, "logout", "");
+
+    if let Ok(mut keyring) = keyring.lock() {
+        keyring.remove(session.session_id);
+    }
     match session_store_state.write() {
         Ok(mut session_state) => {
             session_state.remove(session.session_id);
@@ -273,13 +369,13 @@ async fn get_username(session: AuthenticatedSession) -> Result<Json<String>, Red
 }
 
 #[get("/certificate")]
-async fn get_certificate<'r>(_rate_limiter: RateLimiter) -> Result<CustomHeaderResponder<'r, NamedFile>, NoContent> {
+async fn get_certificate<'r>() -> Result<CustomHeaderResponder<'r, NamedFile>, NoContent> {
     let result = NamedFile::open("ssl/certificate.cer").await;    
 
     match result {
         Ok(file) => {
             let header = Header::new("Content-Disposition", "attachment; filename=certificate.cer");
-            let custom_header_responder_name_file = CustomHeaderResponder { inner: file, header: header };
+            let custom_header_responder_name_file = CustomHeaderResponder { inner: file, header };
             Ok(custom_header_responder_name_file)
         }
         Err(_) => Err(NoContent),
@@ -316,233 +412,187 @@ async fn get_sys_info(_session: AuthenticatedSession, app_config: &State<MyAppCo
 }
 
 #[get("/file/<file_path..>")]
-async fn get_file<'r>(session: AuthenticatedSession, file_path : PathBuf, app_config: &State<MyAppConfig>,) -> Result<CustomHeaderResponder<'r, NamedFile>, NoContent> {
-    let directory = &app_config.directory;
-    let user_directory = PathBuf::from(format!("{}/{}", directory, session.username));
-    let mut path = user_directory.clone();
-    path.push(file_path);
+async fn get_file(session: AuthenticatedSession, audit: Audit, file_path : PathBuf, download: DownloadRequest, keys: UserKeys,
+    storage: &State<StorageState>) -> Result<FileDownload, Status> {
+    // The trash is only reachable through delete and restore.
+    let path = match storage::relative_path(&file_path) {
+        Some(path) if !storage::is_trash(&path) => path,
+        Some(_) => return Err(Status::Forbidden),
+        None => return Err(Status::NoContent),
+    };
 
-    if !path.starts_with(&user_directory) {
-        println!("User tried to access unauthorized content");
-        return Err(NoContent);
+    // Files uploaded before encryption was turned on may still be plain.
+    if !matches!(keys, UserKeys::Disabled) {
+        let header = storage.read_prefix(&session.username, &path, encryption::HEADER_LEN).await.map_err(|_| Status::NoContent)?;
+        if let Some(key_id) = encryption::header_key_id(&header) {
+            // A key that isn't there any more was discarded by an admin.
+            let keys = match keys {
+                UserKeys::Unlocked(keys) if keys.contains(key_id) => keys,
+                UserKeys::Unlocked(_) => return Err(Status::Forbidden),
+                _ => return Err(Status::Locked),
+            };
+            let size = storage.metadata(&session.username, &path).await.map_err(|_| Status::NoContent)?.size;
+            let reader = storage.reader(&session.username, &path).await.map_err(|_| Status::NoContent)?;
+            audit.record(&session.username, "download", &path.to_string_lossy());
+            return Ok(FileDownload::Decrypted(encryption::DecryptedFile::new(keys, reader, size, &storage::file_name(&path))));
+        }
     }
 
-    let requested_file = NamedFile::open(path.clone()).await;
-
-    match requested_file {
+    // Only downloads that happen are recorded.
+    match storage.download(&session.username, &path, download).await {
         Ok(file) => {
-            let file_name = path.file_name().unwrap().to_str().unwrap();
-            let header = Header::new("Content-Disposition", "attachment; filename=".to_string() + file_name);
-            let custom_header_responder_name_file = CustomHeaderResponder { inner: file, header: header };
-            Ok(custom_header_responder_name_file)
-        }
-        Err(_) => Err(NoContent),
+            audit.record(&session.username, "download", &path.to_string_lossy());
+            Ok(file)
+        },
+        Err(_) => Err(Status::NoContent),
     }
 }
 
 #[delete("/file/<file_path..>")]
-async fn delete_file(session: AuthenticatedSession, file_path: PathBuf, app_config: &State<MyAppConfig>) -> Status {
-    let directory = &app_config.directory;
-    let user_directory = PathBuf::from(format!("{}/{}", directory, session.username));
-    let mut path = user_directory.clone();
-    path.push(file_path.clone());
-
-    if !path.starts_with(&user_directory) {
-        println!("User tried to access unauthorized content");
+async fn delete_file(session: AuthenticatedSession, audit: Audit, file_path: PathBuf, storage: &State<StorageState>, quota: UserQuota) -> Status {
+    audit.record(&session.username, "delete", &file_path.to_string_lossy());
+
+    let path = match storage::relative_path(&file_path) {
+        Some(path) => path,
+        None => return Status::Forbidden,
+    };
+
+    // Items already in the trash are handled through the /trash endpoints.
+    if storage::is_trash(&path) {
         return Status::Forbidden;
     }
 
-    if !path.exists() {
-        return Status::NoContent;
+    match storage.metadata(&session.username, &path).await {
+        Ok(entry) if !entry.is_dir => (),
+        _ => return Status::NoContent,
     }
 
-    if path.starts_with(format!("{}/trash", user_directory.to_str().unwrap())) {
-        match tokio::fs::remove_file(path.clone()).await {
-            Ok(_) => Status::Ok,
-           Err(_) =>  Status::InternalServerError,
-        }
-    } else {
-        let trash_folder = user_directory.join("trash");
-        if !trash_folder.exists() {
-            if let Err(_)= fs::create_dir(&trash_folder) {
-                return Status::ExpectationFailed;
-            }
-        }
-
-        let trash_file_path = trash_folder.join(file_path.file_name().unwrap());
-        if let Err(_) = tokio::fs::rename(path.clone(), trash_file_path).await {
-            return Status::NoContent;
-        }
-
-        match path.parent() {
-            Some(parent_dir) => match remove_directory_if_empty(&parent_dir, &user_directory).await {
-                Ok(_) => return Status::Ok,
-                Err(_) => return Status::Ok,
-            },
-            None => return Status::Ok,
-        }
+    // The trash keeps counting the bytes.
+    if storage.remove(&session.username, &path).await.is_err() {
+        return Status::ExpectationFailed;
     }
+    quota.record(0, -1);
+
+    Status::Ok
 }
 
-#[patch("/file/<old_file_path..>?<new_file_name>")]
-async fn rename_file(session: AuthenticatedSession, old_file_path: PathBuf, new_file_name: String, app_config: &State<MyAppConfig>) -> Status {
-    let directory = &app_config.directory;
-    let user_directory = PathBuf::from(format!("{}/{}", directory, session.username));
-    let mut old_path = user_directory.clone();
-    old_path.push(old_file_path.clone());
-    let mut new_path = old_path.clone();
-    new_path.pop();
-    new_path.push(new_file_name);
+#[patch("/file/<old_file_path..>?<new_file_name>&<conflict>")]
+#[allow(clippy::too_many_arguments)]
+async fn rename_file(session: AuthenticatedSession, audit: Audit, old_file_path: PathBuf, new_file_name: String, conflict: Option<&str>,
+    storage: &State<StorageState>, quota: UserQuota) -> Result<Json<StoredPath>, Status> {
+    audit.record_with_target(&session.username, "rename", &old_file_path.to_string_lossy(), &new_file_name);
 
-    // Ensure requested path is still within the user's directory.
-    if !old_path.starts_with(&user_directory) {
-        return Status::Forbidden;
-    }
+    // Ensure requested path is still within the user's directory and not in the trash.
+    let old_path = match storage::relative_path(&old_file_path) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
+
+    // The new name has to stay a single segment in the same folder.
+    let new_path = match filenames::check_name(&new_file_name).and_then(|name| storage::relative_path(&old_path.with_file_name(name))) {
+        Some(path) if path.parent() == old_path.parent() && !storage::is_trash(&path) => path,
+        _ => return Err(Status::BadRequest),
+    };
 
     // Check if the file exists
-    let metadata = tokio::fs::metadata(&old_path).await;
-    if metadata.is_err() || !metadata.unwrap().is_file() {
-        return Status::NoContent
-    }
+    let modified = match storage.metadata(&session.username, &old_path).await {
+        Ok(entry) if !entry.is_dir => entry.modified,
+        _ => return Err(Status::NoContent),
+    };
 
-    // Check something not already named that
-    let metadata = tokio::fs::metadata(&new_path).await;
-    if !metadata.is_err() {
-        return Status::Conflict;
+    // Renaming onto another file fails unless asked otherwise.
+    let policy = ConflictPolicy::parse(conflict, ConflictPolicy::Fail)?;
+    let (new_path, resolution) = conflicts::resolve(storage, &session.username, &new_path, policy, Some(&old_path), Some(modified)).await?;
+    if resolution == Resolution::Skipped {
+        return Ok(StoredPath::resolved(&new_path, resolution));
     }
+    let replaced_size = match resolution {
+        Resolution::Overwritten => storage.metadata(&session.username, &new_path).await.ok().map(|entry| entry.size),
+        _ => None,
+    };
    
     // Do the renaming
-    if let Err(_) = tokio::fs::rename(old_path, new_path).await {
-        return Status::NoContent
+    if storage.rename(&session.username, &old_path, &new_path).await.is_err() {
+        return Err(Status::NoContent);
+    }
+    if let Some(replaced_size) = replaced_size {
+        quota.record(-(replaced_size as i64), -1);
     }
 
-    Status::Ok
+    Ok(StoredPath::resolved(&new_path, resolution))
 }
 
-#[put("/file/move/<old_file_path..>?<new_file_path..>")]
-async fn move_file(session: AuthenticatedSession, old_file_path: PathBuf, new_file_path: String, app_config: &State<MyAppConfig>) -> Status {
-    let directory = &app_config.directory;
-    let user_directory = PathBuf::from(format!("{}/{}", directory, session.username));
-    let mut old_path = user_directory.clone();
-    old_path.push(old_file_path.clone());
+#[put("/file/move/<old_file_path..>?<conflict>&<new_file_path..>")]
+#[allow(clippy::too_many_arguments)]
+async fn move_file(session: AuthenticatedSession, audit: Audit, old_file_path: PathBuf, conflict: Option<&str>, new_file_path: String,
+    storage: &State<StorageState>, quota: UserQuota) -> Result<Json<StoredPath>, Status> {
+    audit.record_with_target(&session.username, "move", &old_file_path.to_string_lossy(), &new_file_path);
 
-    println!("{:?}", old_path.to_str());
-
-    // Ensure requested path is still within the user's directory.
-    if !old_path.starts_with(&user_directory) {
-        return Status::Forbidden;
-    }
+    // Ensure requested path is still within the user's directory and not in the trash.
+    let old_path = match storage::relative_path(&old_file_path) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
 
     // Check if the file exists
-    let metadata = tokio::fs::metadata(&old_path).await;
-    if metadata.is_err() || !metadata.unwrap().is_file() {
-        return Status::NoContent;
-    }
-
-    let mut new_path = user_directory.clone();
-    new_path.push(PathBuf::from(new_file_path));
+    let modified = match storage.metadata(&session.username, &old_path).await {
+        Ok(entry) if !entry.is_dir => entry.modified,
+        _ => return Err(Status::NoContent),
+    };
 
-    println!("{:?}", new_path.to_str());
+    // Ensure the destination path is still within the user's directory and
+    // outside the trash, which is only reachable through delete and restore.
+    let new_path = match filenames::check_relative_path(&new_file_path).and_then(|path| storage::relative_path(&path)) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
 
-    // Ensure the destination path is still within the user's directory.
-    if !new_path.starts_with(&user_directory) {
-        return Status::Forbidden;
+    let policy = ConflictPolicy::parse(conflict, ConflictPolicy::Overwrite)?;
+    let (new_path, resolution) = conflicts::resolve(storage, &session.username, &new_path, policy, Some(&old_path), Some(modified)).await?;
+    if resolution == Resolution::Skipped {
+        return Ok(StoredPath::resolved(&new_path, resolution));
     }
 
-    // Create the destination directory if it doesn't exist
-    if let Some(parent) = new_path.parent() {
-        if !parent.exists() {
-            if let Err(_) = fs::create_dir_all(parent) {
-                return Status::ExpectationFailed;
-            }
-        }
-    }
+    // A file being replaced at the destination stops counting.
+    let replaced_size = match resolution {
+        Resolution::Overwritten => storage.metadata(&session.username, &new_path).await.ok().map(|entry| entry.size),
+        _ => None,
+    };
 
-    if let Err(_) = tokio::fs::rename(old_path.clone(), new_path).await {
-        return Status::ExpectationFailed;
+    if storage.rename(&session.username, &old_path, &new_path).await.is_err() {
+        return Err(Status::ExpectationFailed);
     }
-
-    match old_path.parent() {
-        Some(parent_dir) => match remove_directory_if_empty(&parent_dir, &user_directory).await {
-            Ok(_) => return Status::Ok,
-            Err(_) => return Status::Ok,
-        },
-        None => return Status::Ok,
+    if let Some(replaced_size) = replaced_size {
+        quota.record(-(replaced_size as i64), -1);
     }
-}
 
-async fn remove_directory_if_empty(dir_path: &Path, root_path: &Path) -> Result<(), &'static str> {
-    let mut current_dir = dir_path.to_owned();
-
-    while current_dir != root_path {
-        let entries = tokio::fs::read_dir(&current_dir).await;
-
-        if entries.is_err() { return Err("errrr"); }
-
-        if entries.unwrap().next_entry().await.map_err(|_| "errrr")?.is_none() {
-            match tokio::fs::remove_dir(&current_dir).await {
-                Ok(_) => current_dir.pop(),
-                Err(_) => return Err("errrrr"),
-            };
-        } else {
-            break;
-        }
-    }
-    Ok(())
+    Ok(StoredPath::resolved(&new_path, resolution))
 }
 
 #[get("/file")]
-async fn get_files(session: AuthenticatedSession, app_config: &State<MyAppConfig>) -> Result<Json<Vec<String>>, NoContent> {
-    let directory = &app_config.directory;
-    let user_directory = format!("{}/{}", directory, session.username);
-
-    let all_paths = traverse_directory(&PathBuf::from(user_directory.clone()), 
-        &PathBuf::from(user_directory.clone())).await;
-    
-    match all_paths {
-        Ok(paths) => {
-            let paths_str = paths
-                .into_iter()
-                .map(|path| path.to_string_lossy().to_string())
-                .collect::<Vec<String>>();
-            Ok(Json(paths_str))
-        }
+async fn get_files(session: AuthenticatedSession, storage: &State<StorageState>) -> Result<Json<Vec<String>>, NoContent> {
+    match storage.list_files(&session.username).await {
+        Ok(paths) => Ok(Json(paths)),
         Err(_) => Err(NoContent),
     }
 }
 
-#[async_recursion]
-async fn traverse_directory(path: &Path, base_path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
-    let mut file_paths: Vec<PathBuf> = Vec::new();
-
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let metadata = entry.metadata()?;
-        let file_path = entry.path();
-
-        if metadata.is_dir() {
-            let nested_files = traverse_directory(&file_path, base_path).await?;
-            file_paths.extend(nested_files);
-        } else if metadata.is_file() {
-            let relative_path = file_path.strip_prefix(base_path).unwrap_or(&file_path);
-            file_paths.push(relative_path.to_path_buf());
-        }
-    }
-
-    Ok(file_paths)
-}
-
-const ILLEGAL_CHARS : [char; 14] = ['<', '>', '|', ':', '(', ')', '&', ';', '#', '?', '*','/', '\\', ' '];
-fn sanitize_path(path: String) -> String {
-    let mut result = path.clone();
-    for char in ILLEGAL_CHARS {
-        result = result.replace(char, "_");
+// The folder an upload goes to, the user's root by default. It is confined
+// to the user's space but not sanitized, since it names an existing folder.
+pub fn upload_directory(dir: Option<&str>) -> Option<PathBuf> {
+    match dir {
+        Some(dir) => resolve_user_path(Path::new(""), Path::new(dir.trim_start_matches('/')))
+            .filter(|path| !storage::is_trash(path)),
+        None => Some(PathBuf::new()),
     }
-    return result;
 }
 
+// `X-File-Name`, percent-decoded. It can be a relative path to upload into
+// subfolders. `X-File-Modified` optionally carries the modification time of
+// the file on the client, in seconds, which the stored file keeps.
 pub struct FileName {
-    pub name: String,
+    pub name: PathBuf,
+    pub modified: Option<u64>,
 }
 
 #[rocket::async_trait]
@@ -550,39 +600,120 @@ impl<'r> FromRequest<'r> for FileName {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        match request.headers().get_one("X-File-Name") {
-            Some(name) => request::Outcome::Success(FileName{name: sanitize_path(name.to_string())}),
-            None => request::Outcome::Failure((Status::from_code(401).unwrap(), ())),
+        let name = match request.headers().get_one("X-File-Name") {
+            Some(name) => filenames::decode_header(name).as_deref().and_then(filenames::check_relative_path),
+            None => return request::Outcome::Failure((Status::from_code(401).unwrap(), ())),
+        };
+        let modified = request.headers().get_one("X-File-Modified").and_then(|modified| modified.parse().ok());
+        match name {
+            Some(name) => request::Outcome::Success(FileName{name, modified}),
+            None => request::Outcome::Failure((Status::BadRequest, ())),
         }
     }
 }
 
-#[post("/file", data = "<file>")]
-async fn post_file_from_form<'r>(session: AuthenticatedSession, 
+#[post("/file?<dir>&<conflict>", data = "<file>")]
+#[allow(clippy::too_many_arguments)]
+async fn post_file_from_form(session: AuthenticatedSession, 
+        audit: Audit,
         file_name: FileName,
+        dir: Option<String>,
+        conflict: Option<&str>,
         file : Data<'_>, 
         app_config: &State<MyAppConfig>, 
-    ) -> Status {
-    let directory : &str = &format!("{}/{}", app_config.directory, session.username);
-    match tokio::fs::try_exists(directory).await {
-        Ok(val) => if !val {
-            match tokio::fs::create_dir(directory).await {
-                Ok(_) => (),
-                Err(_) => return Status::ExpectationFailed,
-            }
-        },
-        Err(_) => return Status::ExpectationFailed,
+        storage: &State<StorageState>,
+        quota: UserQuota,
+        keys: UserKeys,
+    ) -> Result<Json<StoredPath>, Status> {
+    let directory = match upload_directory(dir.as_deref()) {
+        Some(directory) => directory,
+        None => return Err(Status::Forbidden),
+    };
+    let path = directory.join(&file_name.name);
+    audit.record(&session.username, "upload", &path.to_string_lossy());
+
+    if matches!(keys, UserKeys::Locked) {
+        return Err(Status::Locked);
+    }
+
+    // The trash is only reachable through delete and restore.
+    let path = match storage::relative_path(&path) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::BadRequest),
+    };
+
+    let policy = ConflictPolicy::parse(conflict, ConflictPolicy::Overwrite)?.for_scope(session.token_scope);
+    let (path, resolution) = conflicts::resolve(storage, &session.username, &path, policy, None, file_name.modified).await?;
+    if resolution == Resolution::Skipped {
+        return Ok(StoredPath::resolved(&path, resolution));
     }
 
-    let file_path : String = format!("{}/{}", directory, file_name.name);
-    let created_file : File = match File::create(&file_path).await {
-        Ok(f) => f,
-        Err(_) => return Status::BadRequest,
+    let replaced_size : Option<u64> = match storage.metadata(&session.username, &path).await {
+        Ok(entry) if entry.is_dir => return Err(Status::Conflict),
+        Ok(entry) => Some(entry.size),
+        _ => None,
     };
 
-    match file.open(ByteUnit::Terabyte(1)).stream_to(created_file).await {
-        Ok(_) => Status::Ok,
-        Err(_) => Status::BadRequest,
+    if replaced_size.is_none() && !quota.allows(0, 1) {
+        return Err(Status::InsufficientStorage);
+    }
+
+    // Overwriting a file frees its old size, so it can be reused by the upload.
+    let quota_limit : Option<u64> = quota.available_bytes().map(|available| available + quota.freed_by_replacing(replaced_size.unwrap_or(0)));
+    let limit = match quota_limit {
+        Some(available) if available < upload::MAX_UPLOAD_SIZE.as_u64() => ByteUnit::Byte(available),
+        _ => upload::MAX_UPLOAD_SIZE,
+    };
+
+    // Stream into a staging file first so a dropped upload never leaves a
+    // partial file behind under its final name.
+    let (staging_path, staging_file) : (PathBuf, File) = match upload::create_staging_file(&app_config.directory, &session.username).await {
+        Ok(staged) => staged,
+        Err(_) => return Err(Status::ExpectationFailed),
+    };
+
+    // The quota counts what ends up on disk, so encrypted files include their overhead.
+    let data = file.open(limit);
+    let streamed = match &keys {
+        UserKeys::Unlocked(keys) => encryption::stream_encrypted(data, keys, staging_file).await,
+        _ => data.stream_to(staging_file).await.map(|n| (n.complete, n.written)),
+    };
+    let written = match streamed {
+        Ok((true, written)) => written,
+        Ok(_) => {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            return match quota_limit {
+                Some(available) if available < upload::MAX_UPLOAD_SIZE.as_u64() => Err(Status::InsufficientStorage),
+                _ => Err(Status::PayloadTooLarge),
+            };
+        },
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            return Err(Status::BadRequest);
+        }
+    };
+
+    if let Some(modified) = file_name.modified {
+        let _ = upload::set_modified(&staging_path, modified);
+    }
+
+    match storage.store(&session.username, &path, &staging_path).await {
+        Ok(_) => {
+            match replaced_size {
+                Some(replaced_size) => quota.record(written as i64 - replaced_size as i64, 0),
+                None => quota.record(written as i64, 1),
+            }
+            Ok(StoredPath::resolved(&path, resolution))
+        },
+        // The replaced file didn't fit as a version.
+        Err(error) if error.kind() == std::io::ErrorKind::StorageFull => {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            Err(Status::InsufficientStorage)
+        },
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            Err(Status::ExpectationFailed)
+        }
     }
 }
 
@@ -591,13 +722,14 @@ async fn index(_usr: AuthenticatedSession) -> Option<NamedFile> {
     NamedFile::open(Path::new("pages/index.html")).await.ok()
 }
 
-fn run_setup() {
+fn run_setup(database: &DatabaseState) {
     let path = Path::new("users.csv");
 
-    if !path.exists() {
-        match std::fs::File::create(path) {
-            Ok(_) => (),
-            Err(_) => print!("Failed to create csv"),
+    if path.exists() {
+        let connection = database.lock().unwrap();
+        match users::import_users_csv(&connection, path) {
+            Ok(count) => tracing::info!(count, "Imported users from users.csv"),
+            Err(error) => tracing::error!(%error, "Failed to import users.csv"),
         }
     }
 }
@@ -613,24 +745,151 @@ async fn main() -> Result<(), ()> {
     let local_ip_string : String = String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().trim().to_string();
     let local_ip = local_ip_string.parse::<IpAddr>();
     let mut figment = rocket::Config::figment().clone();
-    figment = figment.merge(("my_app_config", MyAppConfig { directory: "directory".to_string()}));
-    if local_ip.is_err() {
-        println!("Error {}", local_ip.unwrap_err());
-    } else {
-        figment = figment.merge(("address", local_ip.unwrap()));
-    }
-    
+    figment = figment.merge(("my_app_config", MyAppConfig {
+        directory: "directory".to_string(),
+        database: default_database(),
+        session_backend: default_session_backend(),
+        session_ttl_minutes: default_session_ttl_minutes(),
+        quota_bytes: None,
+        quota_files: None,
+        trash_retention_days: default_trash_retention_days(),
+        webdav_port: None,
+        max_versions: default_max_versions(),
+        version_retention_days: default_version_retention_days(),
+        storage_mode: default_storage_mode(),
+        storage_backend: default_storage_backend(),
+        s3: None,
+        rate_limit: ratelimit::RateLimitConfig::default(),
+        audit_log: default_audit_log(),
+        audit_log_max_bytes: default_audit_log_max_bytes(),
+        audit_log_files: default_audit_log_files(),
+        logging: logging::LoggingConfig::default(),
+    }));
+
     let app_config : MyAppConfig = figment.extract().expect("MyAppConfig");
+    logging::init(&app_config.logging).expect("Failed to set up logging");
+
+    match local_ip {
+        Ok(local_ip) => figment = figment.merge(("address", local_ip)),
+        Err(error) => tracing::warn!("No local address found: {}", error),
+    }
+
+    if std::env::args().any(|arg| arg == "--migrate-dedup") {
+        if !dedup::is_enabled(&app_config) {
+            tracing::warn!("storage_mode is not \"dedup\", new files will not be deduplicated");
+        }
+        match dedup::migrate(&app_config.directory) {
+            Ok(report) => tracing::info!(files = report.files, deduplicated = report.deduplicated,
+                bytes_saved = report.bytes_saved, "Migrated to deduplicated storage"),
+            Err(error) => tracing::error!(%error, "Migration failed"),
+        }
+        return Ok(());
+    }
+
+    let database: DatabaseState = Arc::new(Mutex::new(open_database(&app_config.database).expect("Failed to open database")));
+
+    run_setup(&database);
+
+    let session_backend: Box<dyn SessionBackend> = match app_config.session_backend.as_str() {
+        "memory" => Box::new(MemorySessionBackend::default()),
+        _ => Box::new(SqliteSessionBackend::new(database.clone())),
+    };
+    let session_store_state: SessionStoreState = Arc::new(RwLock::new(SessionStore::new(
+        session_backend, Duration::from_secs(app_config.session_ttl_minutes * 60))));
 
-    run_setup();
+    let quota_state: QuotaState = Arc::new(Mutex::new(QuotaTracker::default()));
+    let storage: StorageState = match app_config.storage_backend.as_str() {
+        "s3" => Arc::new(s3::S3Storage::new(app_config.s3.clone().expect("storage_backend = \"s3\" needs an [default.s3] section"), database.clone())),
+        _ => Arc::new(LocalStorage::new(app_config.clone(), database.clone(), quota_state.clone())),
+    };
+
+    // A retention of 0 keeps deleted items and old versions until they are removed by hand.
+    let purge_database = database.clone();
+    let purge_storage = storage.clone();
+    let purge_quota = quota_state.clone();
+    let purge_directory = app_config.directory.clone();
+    let trash_retention_days = app_config.trash_retention_days;
+    let version_retention_days = app_config.version_retention_days;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if trash_retention_days > 0 {
+                trash::purge_expired(&purge_database, &purge_storage, &purge_directory, &purge_quota, trash_retention_days).await;
+            }
+            if version_retention_days > 0 {
+                versions::purge_expired(&purge_database, &purge_directory, &purge_quota, version_retention_days).await;
+            }
+            // Blobs only lose their last reference when something is purged or replaced.
+            let gc_directory = purge_directory.clone();
+            let _ = tokio::task::spawn_blocking(move || dedup::collect_garbage(&gc_directory)).await;
+        }
+    });
+
+    let rate_limits: RateLimitState = Arc::new(RateLimits::new(app_config.rate_limit.clone()));
+    let sweeper_rate_limits = rate_limits.clone();
+    let sweeper_state = session_store_state.clone();
+    let sweeper_database = database.clone();
+    let keyring: KeyringState = Arc::new(Mutex::new(encryption::Keyring::default()));
+    let sweeper_keyring = keyring.clone();
+    let challenges: totp::ChallengeState = Arc::new(Mutex::new(HashMap::new()));
+    let sweeper_challenges = challenges.clone();
+    let copy_jobs: CopyJobsState = Arc::new(Mutex::new(HashMap::new()));
+    let sweeper_copy_jobs = copy_jobs.clone();
+    let sweeper_directory = app_config.directory.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
+        loop {
+            interval.tick().await;
+            if let Ok(mut session_store) = sweeper_state.write() {
+                session_store.purge_expired();
+                if let Ok(mut keyring) = sweeper_keyring.lock() {
+                    keyring.retain_sessions(|session_id| session_store.get(session_id).is_some());
+                }
+            }
+            if let Ok(connection) = sweeper_database.lock() {
+                let _ = shares::remove_expired(&connection);
+                let _ = tokens::remove_expired(&connection);
+                let _ = lockout::remove_expired(&connection);
+            }
+            totp::purge_expired(&sweeper_challenges);
+            copy::purge_finished(&sweeper_copy_jobs);
+            upload::remove_expired_uploads(&sweeper_directory).await;
+            sweeper_rate_limits.evict_stale();
+        }
+    });
+
+    let audit_log: AuditState = Arc::new(AuditLog::new(&app_config));
+
+    // WebDAV runs next to Rocket on its own port, reusing the same address and TLS certificate.
+    if app_config.webdav_port.is_some() && !storage::is_local(&app_config) {
+        tracing::warn!("WebDAV needs storage_backend = \"local\", not starting it");
+    }
+    if let Some(webdav_port) = app_config.webdav_port.filter(|_| storage::is_local(&app_config)) {
+        let rocket_config = rocket::Config::try_from(&figment).expect("Rocket config");
+        let tls = webdav::load_tls(&rocket_config).expect("Failed to load TLS certificate for WebDAV");
+        let dav_server = Arc::new(webdav::DavServer::new(app_config.clone(), database.clone(), storage.clone(), quota_state.clone(),
+            rate_limits.clone(), audit_log.clone()));
+        let address = SocketAddr::new(rocket_config.address, webdav_port);
+        tokio::spawn(async move {
+            if let Err(error) = webdav::serve(dav_server, address, tls).await {
+                tracing::error!(%error, "WebDAV server stopped");
+            }
+        });
+    }
 
     let _ = rocket::custom(figment)
-        .manage(Arc::new(Mutex::new(RateLimiter {
-            limit: 10,
-            interval: Duration::from_secs(60),
-            request_count: HashMap::new(),
-        })))
-        .manage(Arc::new(RwLock::new(SessionStore::new())))
+        .attach(RequestLogger)
+        .attach(RateLimitFairing(rate_limits))
+        .attach(AuditFairing(audit_log.clone()))
+        .manage(audit_log)
+        .manage(session_store_state)
+        .manage(database)
+        .manage(quota_state)
+        .manage(storage)
+        .manage(keyring)
+        .manage(challenges)
+        .manage(copy_jobs)
         .mount("/", FileServer::from("static"))
         .register("/", catchers![
             aunthorized_access,
@@ -639,18 +898,73 @@ async fn main() -> Result<(), ()> {
             "/",
             routes![
                 index,
+                ratelimit::rate_limited,
                 get_login,
                 post_login,
+                totp::verify_login,
                 logout,
                 get_certificate, 
                 get_file, 
                 post_file_from_form, 
                 get_files,
+                archive::get_archive,
                 delete_file,
                 rename_file,
                 move_file,
+                copy::copy_file,
                 get_sys_info,
                 get_username,
+                upload::options_upload,
+                upload::create_upload,
+                upload::upload_offset,
+                upload::upload_chunk,
+                upload::terminate_upload,
+                upload::upload_batch,
+                users::get_users,
+                users::post_user,
+                users::reset_password,
+                users::change_password,
+                users::disable_user,
+                users::enable_user,
+                users::remove_user,
+                quota::get_quota,
+                quota::put_user_quota,
+                listing::get_directory,
+                folders::create_folder,
+                folders::delete_folder,
+                folders::rename_folder,
+                folders::move_folder,
+                copy::copy_folder,
+                copy::get_copy_jobs,
+                copy::get_copy_job,
+                copy::cancel_copy_job,
+                trash::get_trash,
+                trash::restore_trash_item,
+                trash::delete_trash_item,
+                trash::empty_trash,
+                shares::create_share,
+                shares::get_shares,
+                shares::revoke_share,
+                shares::get_shared,
+                versions::get_versions,
+                versions::get_version,
+                versions::restore_version,
+                encryption::get_encryption,
+                encryption::enable_encryption,
+                encryption::rotate_keys,
+                totp::get_totp,
+                totp::enroll_totp,
+                totp::confirm_totp,
+                totp::regenerate_recovery_codes,
+                totp::disable_totp,
+                totp::reset_totp,
+                audit::get_audit,
+                audit::get_admin_audit,
+                lockout::get_login_failures,
+                lockout::clear_lockout,
+                tokens::get_tokens,
+                tokens::create_token,
+                tokens::revoke_token,
             ],
         )
         .manage(app_config)
@@ -0,0 +1,235 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, put, Request, State};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::storage::{self, Storage, StorageState};
+use crate::trash::TRASH_FOLDER;
+use crate::users::AdminSession;
+use crate::{AuthenticatedSession, MyAppConfig};
+
+pub type QuotaState = Arc<Mutex<QuotaTracker>>;
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Limits {
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct QuotaReport {
+    pub used: Usage,
+    pub limit: Limits,
+}
+
+// Usage is worked out by going through the user's storage the first time it
+// is needed and kept up to date by the handlers after that. The bytes of the
+// trash and of old versions count until they are purged, their files don't.
+#[derive(Default)]
+pub struct QuotaTracker {
+    usage: HashMap<String, Usage>,
+}
+
+impl QuotaTracker {
+    fn usage(&self, username: &str) -> Option<Usage> {
+        self.usage.get(username).copied()
+    }
+
+    // Changes made while the usage was being measured are already in it.
+    fn seed(&mut self, username: &str, usage: Usage) {
+        self.usage.entry(username.to_string()).or_insert(usage);
+    }
+
+    pub fn adjust(&mut self, username: &str, bytes: i64, files: i64) {
+        if let Some(usage) = self.usage.get_mut(username) {
+            usage.bytes = usage.bytes.saturating_add_signed(bytes);
+            usage.files = usage.files.saturating_add_signed(files);
+        }
+    }
+}
+
+async fn measure_user(database: &DatabaseState, storage: &dyn Storage, username: &str) -> Option<Usage> {
+    let total = storage.usage(username, Path::new("")).await.unwrap_or_default();
+    let trash = storage.usage(username, Path::new(TRASH_FOLDER)).await.unwrap_or_default();
+    let versions: i64 = database.lock().ok()?.query_row(
+        "SELECT COALESCE(SUM(size), 0) FROM versions WHERE username = ?1", params![username], |row| row.get(0)).ok()?;
+    Some(Usage {
+        bytes: total.bytes + versions as u64,
+        files: total.files.saturating_sub(trash.files),
+    })
+}
+
+// Gives back the bytes of trash items or old versions deleted for good.
+pub fn release(tracker: &QuotaState, username: &str, bytes: u64) {
+    if let Ok(mut tracker) = tracker.lock() {
+        tracker.adjust(username, -(bytes as i64), 0);
+    }
+}
+
+fn scan_directory(path: &Path, usage: &mut Usage) {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let entry_path = entry.path();
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => scan_directory(&entry_path, usage),
+            Ok(metadata) if metadata.is_file() => {
+                usage.bytes += metadata.len();
+                usage.files += 1;
+            },
+            _ => (),
+        }
+    }
+}
+
+// Usage of a single file or a whole folder on the local disk.
+pub fn measure(path: &Path) -> Usage {
+    let mut usage = Usage::default();
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => {
+            usage.bytes = metadata.len();
+            usage.files = 1;
+        },
+        _ => scan_directory(path, &mut usage),
+    }
+    usage
+}
+
+pub fn limits_for(connection: &Connection, app_config: &MyAppConfig, username: &str) -> Limits {
+    let overrides = connection.query_row(
+        "SELECT quota_bytes, quota_files FROM users WHERE username = ?1",
+        params![username],
+        |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?)));
+
+    let (bytes, files) = overrides.unwrap_or((None, None));
+    Limits {
+        bytes: bytes.map(|bytes| bytes as u64).or(app_config.quota_bytes),
+        files: files.map(|files| files as u64).or(app_config.quota_files),
+    }
+}
+
+pub struct UserQuota {
+    pub username: String,
+    pub limits: Limits,
+    keeps_versions: bool,
+    tracker: QuotaState,
+}
+
+impl UserQuota {
+    pub async fn load(database: &DatabaseState, app_config: &MyAppConfig, tracker: QuotaState, storage: &dyn Storage,
+        username: &str) -> Option<UserQuota> {
+        let known = tracker.lock().ok()?.usage(username).is_some();
+        if !known {
+            let usage = measure_user(database, storage, username).await?;
+            tracker.lock().ok()?.seed(username, usage);
+        }
+
+        let connection = database.lock().ok()?;
+        Some(UserQuota::tracked(&connection, app_config, tracker, username))
+    }
+
+    // For work done on behalf of a request that already loaded the quota,
+    // so the usage is known.
+    pub fn tracked(connection: &Connection, app_config: &MyAppConfig, tracker: QuotaState, username: &str) -> UserQuota {
+        UserQuota {
+            username: username.to_string(),
+            limits: limits_for(connection, app_config, username),
+            keeps_versions: app_config.max_versions > 0 && storage::is_local(app_config),
+            tracker,
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        match self.tracker.lock() {
+            Ok(tracker) => tracker.usage(&self.username).unwrap_or_default(),
+            Err(_) => Usage::default(),
+        }
+    }
+
+    // Bytes that may still be written, or None when there is no byte limit.
+    pub fn available_bytes(&self) -> Option<u64> {
+        self.limits.bytes.map(|limit| limit.saturating_sub(self.usage().bytes))
+    }
+
+    // What overwriting a file of `size` bytes gives back: nothing when the
+    // old contents are kept as a version.
+    pub fn freed_by_replacing(&self, size: u64) -> u64 {
+        if self.keeps_versions { 0 } else { size }
+    }
+
+    pub fn allows(&self, bytes: u64, files: u64) -> bool {
+        let usage = self.usage();
+        self.limits.bytes.is_none_or(|limit| usage.bytes + bytes <= limit)
+            && self.limits.files.is_none_or(|limit| usage.files + files <= limit)
+    }
+
+    pub fn record(&self, bytes: i64, files: i64) {
+        if let Ok(mut tracker) = self.tracker.lock() {
+            tracker.adjust(&self.username, bytes, files);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserQuota {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let session = match request.guard::<AuthenticatedSession>().await {
+            request::Outcome::Success(session) => session,
+            request::Outcome::Failure(failure) => return request::Outcome::Failure(failure),
+            request::Outcome::Forward(forward) => return request::Outcome::Forward(forward),
+        };
+
+        let app_config = request.rocket().state::<MyAppConfig>().unwrap();
+        let tracker = request.rocket().state::<QuotaState>().unwrap().clone();
+        let database = request.rocket().state::<DatabaseState>().unwrap();
+        let storage = request.rocket().state::<StorageState>().unwrap();
+        match UserQuota::load(database, app_config, tracker, storage.as_ref(), &session.username).await {
+            Some(quota) => request::Outcome::Success(quota),
+            None => request::Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+#[get("/quota")]
+pub async fn get_quota(quota: UserQuota) -> Json<QuotaReport> {
+    Json(QuotaReport { used: quota.usage(), limit: quota.limits })
+}
+
+// A null limit falls back to the server-wide default from Rocket.toml.
+#[put("/admin/users/<username>/quota", data = "<limits>")]
+pub async fn put_user_quota(admin: AdminSession, audit: Audit, username: String, limits: Json<Limits>, database: &State<DatabaseState>) -> Status {
+    audit.record_with_target(&admin.username, "set_quota", "", &username);
+
+    let connection = match database.lock() {
+        Ok(connection) => connection,
+        Err(_) => return Status::InternalServerError,
+    };
+
+    let updated = connection.execute(
+        "UPDATE users SET quota_bytes = ?2, quota_files = ?3 WHERE username = ?1",
+        params![username, limits.bytes.map(|bytes| bytes as i64), limits.files.map(|files| files as i64)]);
+
+    match updated {
+        Ok(1) => Status::Ok,
+        Ok(_) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
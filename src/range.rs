@@ -0,0 +1,377 @@
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+use rocket::Request;
+use std::io::{Cursor, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use rand::prelude::*;
+
+use crate::webdav::percent_encode;
+
+// Past this many ranges in one request we just send the whole file.
+const MAX_RANGES: usize = 16;
+
+pub type BoxedReader = Pin<Box<dyn AsyncRead + Send>>;
+
+// Names can have spaces and any language since they are kept as typed. Old
+// clients get `filename` with anything else replaced, the rest the exact name
+// in `filename*` (RFC 6266).
+pub fn content_disposition(file_name: &str) -> Header<'static> {
+    let fallback: String = file_name.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    Header::new("Content-Disposition", format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, percent_encode(file_name)))
+}
+
+pub struct RangedFile {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+    file_name: Option<String>,
+}
+
+impl RangedFile {
+    pub async fn open(path: &Path) -> std::io::Result<RangedFile> {
+        let metadata = tokio::fs::metadata(path).await?;
+        if !metadata.is_file() {
+            return Err(std::io::Error::other("not a file"));
+        }
+
+        Ok(RangedFile {
+            path: path.to_path_buf(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            file_name: None,
+        })
+    }
+
+    pub fn attachment(mut self, file_name: &str) -> Self {
+        self.file_name = Some(file_name.to_string());
+        self
+    }
+
+    pub fn etag(&self) -> String {
+        entity_tag(self.len, self.modified)
+    }
+
+    pub fn last_modified(&self) -> String {
+        format_http_date(self.modified)
+    }
+
+    fn content_type(&self) -> ContentType {
+        self.path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Binary)
+    }
+
+    fn not_modified(&self, conditions: &Conditions<'_>) -> bool {
+        if let Some(if_none_match) = conditions.if_none_match {
+            let etag = self.etag();
+            return if_none_match.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag);
+        }
+
+        match conditions.if_modified_since.and_then(parse_http_date) {
+            Some(since) => unix_secs(self.modified) <= unix_secs(since),
+            None => false,
+        }
+    }
+
+    // A Range header only applies when If-Range (if present) still matches the file.
+    fn range_applies(&self, conditions: &Conditions<'_>) -> bool {
+        match conditions.if_range {
+            Some(validator) if validator.starts_with('"') => validator == self.etag(),
+            Some(validator) => match parse_http_date(validator) {
+                Some(date) => unix_secs(self.modified) == unix_secs(date),
+                None => false,
+            },
+            None => true,
+        }
+    }
+
+    fn open_segment(&self, start: u64, len: u64) -> std::io::Result<BoxedReader> {
+        let mut file = std::fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Box::pin(tokio::fs::File::from_std(file).take(len)))
+    }
+}
+
+// The headers of a request that decide what part of a file it gets.
+pub struct Conditions<'a> {
+    pub if_none_match: Option<&'a str>,
+    pub if_modified_since: Option<&'a str>,
+    pub if_range: Option<&'a str>,
+    pub range: Option<&'a str>,
+}
+
+impl<'a> Conditions<'a> {
+    fn from_request(req: &'a Request<'_>) -> Conditions<'a> {
+        let headers = req.headers();
+        Conditions {
+            if_none_match: headers.get_one("If-None-Match"),
+            if_modified_since: headers.get_one("If-Modified-Since"),
+            if_range: headers.get_one("If-Range"),
+            range: headers.get_one("Range"),
+        }
+    }
+}
+
+// A response to a file request without the server it goes out through, so
+// the WebDAV listener can answer ranges the same way.
+pub struct RangedResponse {
+    pub status: Status,
+    pub headers: Vec<Header<'static>>,
+    pub body: Option<BoxedReader>,
+}
+
+impl RangedFile {
+    pub fn respond(self, conditions: &Conditions<'_>) -> Result<RangedResponse, Status> {
+        let mut headers = vec![
+            Header::new("Accept-Ranges", "bytes"),
+            Header::new("ETag", self.etag()),
+            Header::new("Last-Modified", self.last_modified()),
+        ];
+
+        if let Some(file_name) = &self.file_name {
+            headers.push(content_disposition(file_name));
+        }
+
+        if self.not_modified(conditions) {
+            return Ok(RangedResponse { status: Status::NotModified, headers, body: None });
+        }
+
+        let ranges = match conditions.range {
+            Some(range) if self.range_applies(conditions) => parse_range_header(range, self.len),
+            _ => RangeRequest::Full,
+        };
+
+        let content_type = self.content_type();
+        match ranges {
+            RangeRequest::Full => {
+                let body = self.open_segment(0, self.len).map_err(|_| Status::NotFound)?;
+                headers.push(content_type.into());
+                headers.push(Header::new("Content-Length", self.len.to_string()));
+                Ok(RangedResponse { status: Status::Ok, headers, body: Some(body) })
+            },
+            RangeRequest::Unsatisfiable => {
+                headers.push(Header::new("Content-Range", format!("bytes */{}", self.len)));
+                Ok(RangedResponse { status: Status::RangeNotSatisfiable, headers, body: None })
+            },
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                let body = self.open_segment(start, end - start + 1).map_err(|_| Status::NotFound)?;
+                headers.push(content_type.into());
+                headers.push(Header::new("Content-Range", format!("bytes {}-{}/{}", start, end, self.len)));
+                headers.push(Header::new("Content-Length", (end - start + 1).to_string()));
+                Ok(RangedResponse { status: Status::PartialContent, headers, body: Some(body) })
+            },
+            RangeRequest::Partial(ranges) => {
+                let boundary = hex::encode(rand::thread_rng().gen::<[u8; 12]>());
+                let mut body: BoxedReader = Box::pin(tokio::io::empty());
+                let mut body_len: u64 = 0;
+
+                for (start, end) in ranges {
+                    let part_header = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, content_type, start, end, self.len);
+                    let segment = self.open_segment(start, end - start + 1).map_err(|_| Status::NotFound)?;
+                    body_len += part_header.len() as u64 + end - start + 1;
+                    body = Box::pin(body.chain(Cursor::new(part_header.into_bytes())).chain(segment));
+                }
+
+                let closing = format!("\r\n--{}--\r\n", boundary);
+                body_len += closing.len() as u64;
+                body = Box::pin(body.chain(Cursor::new(closing.into_bytes())));
+
+                headers.push(Header::new("Content-Type", format!("multipart/byteranges; boundary={}", boundary)));
+                headers.push(Header::new("Content-Length", body_len.to_string()));
+                Ok(RangedResponse { status: Status::PartialContent, headers, body: Some(body) })
+            },
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RangedFile {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let ranged = self.respond(&Conditions::from_request(req))?;
+        let mut response = Response::build();
+        response.status(ranged.status);
+        for header in ranged.headers {
+            response.header(header);
+        }
+        if let Some(body) = ranged.body {
+            response.streamed_body(body);
+        }
+        response.ok()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    Full,
+    Unsatisfiable,
+    Partial(Vec<(u64, u64)>),
+}
+
+// Parses `bytes=a-b, c-, -n` into inclusive (start, end) pairs. Malformed
+// headers are ignored as RFC 9110 asks, which means serving the full file.
+fn parse_range_header(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Full,
+        };
+
+        let range = if start.is_empty() {
+            match end.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) => Some((len.saturating_sub(suffix), len.saturating_sub(1))),
+                Err(_) => return RangeRequest::Full,
+            }
+        } else {
+            let start = match start.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return RangeRequest::Full,
+            };
+            let end = if end.is_empty() {
+                len.saturating_sub(1)
+            } else {
+                match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start < len { Some((start, end)) } else { None }
+        };
+
+        if let Some(range) = range {
+            if len > 0 {
+                ranges.push(range);
+            }
+        }
+    }
+
+    if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else if ranges.len() > MAX_RANGES {
+        RangeRequest::Full
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+pub fn entity_tag(len: u64, modified: SystemTime) -> String {
+    format!("\"{:x}-{:x}\"", len, unix_nanos(modified))
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: SystemTime) -> String {
+    let date = OffsetDateTime::from_unix_timestamp(unix_secs(time) as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[date.weekday().number_days_from_monday() as usize],
+        date.day(),
+        MONTHS[date.month() as usize - 1],
+        date.year(),
+        date.hour(),
+        date.minute(),
+        date.second())
+}
+
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+
+    let day: u8 = parts[1].parse().ok()?;
+    let month_index = MONTHS.iter().position(|month| *month == parts[2])?;
+    let month = Month::try_from(month_index as u8 + 1).ok()?;
+    let year: i32 = parts[3].parse().ok()?;
+    let clock: Vec<u8> = parts[4].split(':').map(|part| part.parse().ok()).collect::<Option<Vec<u8>>>()?;
+    if clock.len() != 3 {
+        return None;
+    }
+
+    let date = Date::from_calendar_date(year, month, day).ok()?;
+    let time = Time::from_hms(clock[0], clock[1], clock[2]).ok()?;
+    let timestamp = PrimitiveDateTime::new(date, time).assume_utc().unix_timestamp();
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(u64::try_from(timestamp).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range_header("bytes=0-9", 100), RangeRequest::Partial(vec![(0, 9)]));
+        assert_eq!(parse_range_header("bytes=90-", 100), RangeRequest::Partial(vec![(90, 99)]));
+        assert_eq!(parse_range_header("bytes=-10", 100), RangeRequest::Partial(vec![(90, 99)]));
+        // An end past the file is cut to its last byte.
+        assert_eq!(parse_range_header("bytes=50-500", 100), RangeRequest::Partial(vec![(50, 99)]));
+        assert_eq!(parse_range_header("bytes=-500", 100), RangeRequest::Partial(vec![(0, 99)]));
+    }
+
+    #[test]
+    fn parses_multiple_ranges() {
+        assert_eq!(parse_range_header("bytes=0-0, 10-19 ,-1", 100), RangeRequest::Partial(vec![(0, 0), (10, 19), (99, 99)]));
+        // Ranges that start past the end are dropped as long as one is left.
+        assert_eq!(parse_range_header("bytes=0-9,200-300", 100), RangeRequest::Partial(vec![(0, 9)]));
+
+        let many = (0..=MAX_RANGES).map(|index| format!("{}-{}", index, index)).collect::<Vec<_>>().join(",");
+        assert_eq!(parse_range_header(&format!("bytes={}", many), 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        assert_eq!(parse_range_header("bytes=100-", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=-0", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_malformed_headers() {
+        assert_eq!(parse_range_header("items=0-9", 100), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=9-0", 100), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=a-b", 100), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=5", 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date(&format_http_date(UNIX_EPOCH)), Some(UNIX_EPOCH));
+    }
+
+    #[test]
+    fn refuses_malformed_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 31 Feb 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+}
@@ -0,0 +1,489 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, uri, Data, Request, Response};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::database::DatabaseState;
+use crate::session::SessionStoreState;
+use crate::tokens;
+
+// Token buckets in front of every route. Each request goes through the first
+// policy whose paths match it and takes a token from the bucket of each of the
+// policy's keys, the client address and/or the user. Buckets refill at
+// `requests_per_minute` and hold up to `burst` tokens. Policies with
+// `bytes_per_minute` also meter the body sizes of uploads and downloads, which
+// may overdraw the bucket once so a single large file still gets through.
+// Requests over the limit never reach their route, see `on_request`.
+pub type RateLimitState = Arc<RateLimits>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitKey {
+    Ip,
+    User,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimitPolicy {
+    pub name: String,
+    // Exact paths, "/prefix/*" for everything below a prefix, or "*".
+    pub paths: Vec<String>,
+    // Every method when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default = "default_keys")]
+    pub keys: Vec<LimitKey>,
+    pub requests_per_minute: u32,
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub bytes_per_minute: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_policies")]
+    pub policies: Vec<RateLimitPolicy>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_keys() -> Vec<LimitKey> {
+    vec![LimitKey::Ip]
+}
+
+fn policy(name: &str, paths: &[&str], keys: Vec<LimitKey>, requests_per_minute: u32) -> RateLimitPolicy {
+    RateLimitPolicy {
+        name: name.to_string(),
+        paths: paths.iter().map(|path| path.to_string()).collect(),
+        methods: Vec::new(),
+        keys,
+        requests_per_minute,
+        burst: None,
+        bytes_per_minute: None,
+    }
+}
+
+// Login and anonymous share downloads keep the old 10 requests a minute per address.
+fn default_policies() -> Vec<RateLimitPolicy> {
+    vec![
+        policy("login", &["/login", "/login/verify", "/certificate", "/s/*"], vec![LimitKey::Ip], 10),
+        policy("transfer", &["/file", "/file/*", "/files", "/upload", "/upload/*", "/version/*", "/archive"], vec![LimitKey::User], 120),
+        policy("default", &["*"], vec![LimitKey::User], 300),
+    ]
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: default_enabled(),
+            policies: default_policies(),
+        }
+    }
+}
+
+impl RateLimitPolicy {
+    fn matches(&self, method: Method, path: &str) -> bool {
+        let method_matches = self.methods.is_empty()
+            || self.methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method.as_str()));
+        method_matches && self.paths.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some("") => true,
+            Some(prefix) => path.starts_with(prefix),
+            None => path == pattern,
+        })
+    }
+
+    fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.requests_per_minute).max(1)
+    }
+}
+
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, per_minute: f64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            per_second: per_minute / 60.0,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    // Seconds until `tokens` are available.
+    fn wait(&self, tokens: f64) -> u64 {
+        match self.per_second > 0.0 {
+            true => ((tokens - self.tokens).max(0.0) / self.per_second).ceil() as u64,
+            false => u64::MAX,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+struct Buckets {
+    requests: TokenBucket,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(policy: &RateLimitPolicy) -> Buckets {
+        Buckets {
+            requests: TokenBucket::new(policy.burst() as f64, policy.requests_per_minute as f64),
+            bytes: policy.bytes_per_minute.map(|bytes| TokenBucket::new(bytes as f64, bytes as f64)),
+        }
+    }
+
+    // Seconds until this bucket lets another request through, 0 if it does now.
+    fn retry_after(&self) -> u64 {
+        let requests = self.requests.wait(1.0);
+        // The byte bucket only has to be out of debt.
+        let bytes = self.bytes.as_ref().map(|bytes| if bytes.tokens > 0.0 { 0 } else { bytes.wait(1.0) }).unwrap_or(0);
+        requests.max(bytes)
+    }
+}
+
+// What `on_request` decided, for `on_response` to report and to meter the response body.
+struct Decision {
+    policy: usize,
+    keys: Vec<String>,
+    limit: u32,
+    remaining: u32,
+    reset: u64,
+    retry_after: Option<u64>,
+}
+
+pub struct RateLimits {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(usize, String), Buckets>>,
+}
+
+impl RateLimits {
+    pub fn new(config: RateLimitConfig) -> RateLimits {
+        RateLimits {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn find_policy(&self, method: Method, path: &str) -> Option<usize> {
+        self.config.policies.iter().position(|policy| policy.matches(method, path))
+    }
+
+    // Takes a request, and `bytes` if the policy meters them, from every key's
+    // bucket, or from none of them if any is empty.
+    fn take(&self, policy_index: usize, keys: Vec<String>, bytes: u64) -> Decision {
+        let policy = &self.config.policies[policy_index];
+        let now = Instant::now();
+        let mut decision = Decision {
+            policy: policy_index,
+            keys,
+            limit: policy.burst(),
+            remaining: policy.burst(),
+            reset: 0,
+            retry_after: None,
+        };
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(_) => return decision,
+        };
+
+        let mut retry_after = 0;
+        for key in &decision.keys {
+            let bucket = buckets.entry((policy_index, key.clone())).or_insert_with(|| Buckets::new(policy));
+            bucket.requests.refill(now);
+            if let Some(bytes) = bucket.bytes.as_mut() {
+                bytes.refill(now);
+            }
+            retry_after = retry_after.max(bucket.retry_after());
+        }
+        if retry_after > 0 {
+            decision.remaining = 0;
+            decision.reset = retry_after;
+            decision.retry_after = Some(retry_after);
+            return decision;
+        }
+
+        for key in &decision.keys {
+            if let Some(bucket) = buckets.get_mut(&(policy_index, key.clone())) {
+                bucket.requests.tokens -= 1.0;
+                if let Some(bytes_bucket) = bucket.bytes.as_mut() {
+                    bytes_bucket.tokens -= bytes as f64;
+                }
+                decision.remaining = decision.remaining.min(bucket.requests.tokens.max(0.0) as u32);
+                decision.reset = decision.reset.max(bucket.requests.wait(bucket.requests.capacity));
+            }
+        }
+        decision
+    }
+
+    fn charge_bytes(&self, decision: &Decision, bytes: u64) {
+        if let Ok(mut buckets) = self.buckets.lock() {
+            for key in &decision.keys {
+                if let Some(bytes_bucket) = buckets.get_mut(&(decision.policy, key.clone())).and_then(|bucket| bucket.bytes.as_mut()) {
+                    bytes_bucket.tokens -= bytes as f64;
+                }
+            }
+        }
+    }
+
+    // WebDAV isn't served by Rocket, so its password checks are counted by
+    // hand against the policy of POST /login, by address.
+    pub fn allow_login(&self, client_ip: IpAddr) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        match self.find_policy(Method::Post, "/login") {
+            Some(policy) => self.take(policy, vec![format!("ip:{}", client_ip)], 0).retry_after.is_none(),
+            None => true,
+        }
+    }
+
+    // Full buckets are no different from new ones, so they can go.
+    pub fn evict_stale(&self) {
+        let now = Instant::now();
+        if let Ok(mut buckets) = self.buckets.lock() {
+            buckets.retain(|_, bucket| {
+                bucket.requests.refill(now);
+                if let Some(bytes) = bucket.bytes.as_mut() {
+                    bytes.refill(now);
+                }
+                !bucket.requests.is_full() || bucket.bytes.as_ref().is_some_and(|bytes| !bytes.is_full())
+            });
+        }
+    }
+}
+
+// The user a request comes from, without renewing the session like
+// `AuthenticatedSession` would. Tokens are looked up too, a made up one
+// would otherwise get a fresh bucket with every request.
+fn user_key(request: &Request<'_>) -> Option<String> {
+    if let Some(cookie) = request.cookies().get_private("session_id") {
+        let session_id = cookie.value().parse::<u64>().ok()?;
+        let session_store = request.rocket().state::<SessionStoreState>()?.read().ok()?;
+        return session_store.get(session_id).map(|username| format!("user:{}", username));
+    }
+    let token = request.headers().get_one("Authorization")?.strip_prefix("Bearer ")?;
+    let connection = request.rocket().state::<DatabaseState>()?.lock().ok()?;
+    tokens::token_owner(&connection, token.trim()).map(|username| format!("user:{}", username))
+}
+
+fn request_keys(request: &Request<'_>, keys: &[LimitKey]) -> Vec<String> {
+    let ip_key = || format!("ip:{}", request.client_ip().map(|ip| ip.to_string()).unwrap_or_default());
+    let mut request_keys: Vec<String> = keys.iter().map(|key| match key {
+        LimitKey::Ip => ip_key(),
+        // Anonymous requests fall back to their address.
+        LimitKey::User => user_key(request).unwrap_or_else(ip_key),
+    }).collect();
+    request_keys.dedup();
+    request_keys
+}
+
+// Rejected requests are routed here, since a fairing can't answer a request itself.
+#[get("/rate-limited")]
+pub fn rate_limited() -> Status {
+    Status::TooManyRequests
+}
+
+pub struct RateLimitFairing(pub RateLimitState);
+
+#[rocket::async_trait]
+impl Fairing for RateLimitFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiting",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let rate_limits = &self.0;
+        if !rate_limits.config.enabled {
+            return;
+        }
+        let policy = match rate_limits.find_policy(request.method(), request.uri().path().as_str()) {
+            Some(policy) => policy,
+            None => return,
+        };
+
+        let keys = request_keys(request, &rate_limits.config.policies[policy].keys);
+        let uploaded = request.headers().get_one("Content-Length").and_then(|length| length.parse().ok()).unwrap_or(0);
+        let decision = rate_limits.take(policy, keys, uploaded);
+        let limited = decision.retry_after.is_some();
+        request.local_cache(|| Some(decision));
+
+        if limited {
+            request.set_method(Method::Get);
+            request.set_uri(uri!(rate_limited));
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let decision = match request.local_cache(|| None::<Decision>) {
+            Some(decision) => decision,
+            None => return,
+        };
+
+        if decision.retry_after.is_none() && response.status().class().is_success()
+            && self.0.config.policies[decision.policy].bytes_per_minute.is_some() {
+            let downloaded = response.headers().get_one("Content-Length").and_then(|length| length.parse().ok())
+                .or_else(|| response.body().preset_size().map(|size| size as u64));
+            if let Some(downloaded) = downloaded {
+                self.0.charge_bytes(decision, downloaded);
+            }
+        }
+
+        response.set_header(Header::new("RateLimit-Limit", decision.limit.to_string()));
+        response.set_header(Header::new("RateLimit-Remaining", decision.remaining.to_string()));
+        response.set_header(Header::new("RateLimit-Reset", decision.reset.to_string()));
+        if let Some(retry_after) = decision.retry_after {
+            response.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(policy: RateLimitPolicy) -> RateLimits {
+        RateLimits::new(RateLimitConfig { enabled: true, policies: vec![policy] })
+    }
+
+    #[test]
+    fn refills_up_to_capacity() {
+        let mut bucket = TokenBucket::new(10.0, 60.0);
+        let start = bucket.updated;
+        bucket.tokens = 0.0;
+
+        bucket.refill(start + Duration::from_secs(4));
+        assert_eq!(bucket.tokens, 4.0);
+        assert!(!bucket.is_full());
+        assert_eq!(bucket.wait(1.0), 0);
+        assert_eq!(bucket.wait(10.0), 6);
+
+        bucket.refill(start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 10.0);
+        assert!(bucket.is_full());
+    }
+
+    #[test]
+    fn waits_out_debt() {
+        let mut bucket = TokenBucket::new(60.0, 60.0);
+        let start = bucket.updated;
+        bucket.tokens = -30.0;
+        assert_eq!(bucket.wait(1.0), 31);
+
+        bucket.refill(start + Duration::from_secs(30));
+        assert_eq!(bucket.tokens, 0.0);
+        assert_eq!(bucket.wait(1.0), 1);
+
+        // A bucket that never refills never lets anything through again.
+        assert_eq!(TokenBucket::new(1.0, 0.0).wait(2.0), u64::MAX);
+    }
+
+    #[test]
+    fn refuses_requests_past_the_burst() {
+        let mut policy = policy("test", &["*"], vec![LimitKey::Ip], 60);
+        policy.burst = Some(2);
+        let limits = limits(policy);
+        let keys = || vec!["ip:127.0.0.1".to_string()];
+
+        let first = limits.take(0, keys(), 0);
+        assert_eq!((first.limit, first.remaining, first.retry_after), (2, 1, None));
+        assert_eq!(limits.take(0, keys(), 0).remaining, 0);
+
+        let refused = limits.take(0, keys(), 0);
+        assert_eq!(refused.retry_after, Some(1));
+        assert_eq!(refused.remaining, 0);
+
+        // Other keys have buckets of their own.
+        assert_eq!(limits.take(0, vec!["ip:127.0.0.2".to_string()], 0).retry_after, None);
+    }
+
+    #[test]
+    fn refuses_every_key_when_one_is_empty() {
+        let mut policy = policy("test", &["*"], vec![LimitKey::Ip, LimitKey::User], 60);
+        policy.burst = Some(1);
+        let limits = limits(policy);
+
+        assert_eq!(limits.take(0, vec!["user:bob".to_string()], 0).retry_after, None);
+        let refused = limits.take(0, vec!["ip:127.0.0.1".to_string(), "user:bob".to_string()], 0);
+        assert!(refused.retry_after.is_some());
+        // The address wasn't charged for the refused request.
+        assert_eq!(limits.take(0, vec!["ip:127.0.0.1".to_string()], 0).retry_after, None);
+    }
+
+    #[test]
+    fn lets_one_large_transfer_overdraw_the_byte_bucket() {
+        let mut policy = policy("test", &["*"], vec![LimitKey::User], 60);
+        policy.bytes_per_minute = Some(600);
+        let limits = limits(policy);
+        let keys = || vec!["user:bob".to_string()];
+
+        let decision = limits.take(0, keys(), 0);
+        assert_eq!(decision.retry_after, None);
+        limits.charge_bytes(&decision, 1200);
+
+        // 600 bytes in debt at 10 a second.
+        let refused = limits.take(0, keys(), 0);
+        assert!(refused.retry_after.is_some_and(|seconds| (60..=61).contains(&seconds)));
+
+        let buckets = limits.buckets.lock().unwrap();
+        let bucket = &buckets[&(0, "user:bob".to_string())];
+        assert!(bucket.bytes.as_ref().unwrap().tokens < 0.0);
+        // Only the first request took a token.
+        assert!(bucket.requests.tokens > 58.0 && bucket.requests.tokens < 60.0);
+    }
+
+    #[test]
+    fn matches_paths_and_methods() {
+        let mut upload = policy("upload", &["/upload", "/upload/*"], vec![LimitKey::User], 60);
+        upload.methods = vec!["post".to_string(), "PATCH".to_string()];
+        assert!(upload.matches(Method::Post, "/upload"));
+        assert!(upload.matches(Method::Patch, "/upload/abc"));
+        assert!(!upload.matches(Method::Get, "/upload/abc"));
+        assert!(!upload.matches(Method::Post, "/uploads"));
+
+        let everything = policy("default", &["*"], vec![LimitKey::User], 60);
+        assert!(everything.matches(Method::Delete, "/anything"));
+
+        let limits = RateLimits::new(RateLimitConfig::default());
+        assert_eq!(limits.find_policy(Method::Post, "/login"), Some(0));
+        assert_eq!(limits.find_policy(Method::Get, "/s/abc"), Some(0));
+        assert_eq!(limits.find_policy(Method::Patch, "/upload/abc"), Some(1));
+        assert_eq!(limits.find_policy(Method::Get, "/dir"), Some(2));
+    }
+
+    #[test]
+    fn counts_webdav_logins_against_the_login_policy() {
+        let limits = RateLimits::new(RateLimitConfig::default());
+        let client_ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!((0..10).all(|_| limits.allow_login(client_ip)));
+        assert!(!limits.allow_login(client_ip));
+        assert!(limits.allow_login("192.0.2.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,168 @@
+use rusqlite::{params, OptionalExtension};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::database::DatabaseState;
+use crate::unix_timestamp;
+
+pub type SessionStoreState = Arc<RwLock<SessionStore>>;
+
+pub trait SessionBackend: Send + Sync {
+    fn insert(&mut self, session_id: u64, username: &str, expires_at: u64);
+    fn get(&self, session_id: u64) -> Option<(String, u64)>;
+    fn set_expiry(&mut self, session_id: u64, expires_at: u64);
+    fn remove(&mut self, session_id: u64);
+    fn remove_user(&mut self, username: &str);
+    fn remove_expired(&mut self, now: u64) -> usize;
+}
+
+#[derive(Default)]
+pub struct MemorySessionBackend {
+    sessions: HashMap<u64, (String, u64)>,
+}
+
+impl SessionBackend for MemorySessionBackend {
+    fn insert(&mut self, session_id: u64, username: &str, expires_at: u64) {
+        self.sessions.insert(session_id, (username.to_string(), expires_at));
+    }
+
+    fn get(&self, session_id: u64) -> Option<(String, u64)> {
+        self.sessions.get(&session_id).cloned()
+    }
+
+    fn set_expiry(&mut self, session_id: u64, expires_at: u64) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.1 = expires_at;
+        }
+    }
+
+    fn remove(&mut self, session_id: u64) {
+        self.sessions.remove(&session_id);
+    }
+
+    fn remove_user(&mut self, username: &str) {
+        self.sessions.retain(|_, (session_username, _)| session_username != username);
+    }
+
+    fn remove_expired(&mut self, now: u64) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        before - self.sessions.len()
+    }
+}
+
+// Session ids are random u64s; SQLite integers are signed so they are stored
+// bit-for-bit as i64.
+pub struct SqliteSessionBackend {
+    database: DatabaseState,
+}
+
+impl SqliteSessionBackend {
+    pub fn new(database: DatabaseState) -> Self {
+        SqliteSessionBackend { database }
+    }
+}
+
+impl SessionBackend for SqliteSessionBackend {
+    fn insert(&mut self, session_id: u64, username: &str, expires_at: u64) {
+        if let Ok(connection) = self.database.lock() {
+            let _ = connection.execute(
+                "INSERT OR REPLACE INTO sessions (session_id, username, expires_at) VALUES (?1, ?2, ?3)",
+                params![session_id as i64, username, expires_at as i64]);
+        }
+    }
+
+    fn get(&self, session_id: u64) -> Option<(String, u64)> {
+        let connection = self.database.lock().ok()?;
+        connection.query_row(
+            "SELECT username, expires_at FROM sessions WHERE session_id = ?1",
+            params![session_id as i64],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64)))
+            .optional()
+            .ok()?
+    }
+
+    fn set_expiry(&mut self, session_id: u64, expires_at: u64) {
+        if let Ok(connection) = self.database.lock() {
+            let _ = connection.execute(
+                "UPDATE sessions SET expires_at = ?2 WHERE session_id = ?1",
+                params![session_id as i64, expires_at as i64]);
+        }
+    }
+
+    fn remove(&mut self, session_id: u64) {
+        if let Ok(connection) = self.database.lock() {
+            let _ = connection.execute("DELETE FROM sessions WHERE session_id = ?1", params![session_id as i64]);
+        }
+    }
+
+    fn remove_user(&mut self, username: &str) {
+        if let Ok(connection) = self.database.lock() {
+            let _ = connection.execute("DELETE FROM sessions WHERE username = ?1", params![username]);
+        }
+    }
+
+    fn remove_expired(&mut self, now: u64) -> usize {
+        match self.database.lock() {
+            Ok(connection) => connection
+                .execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now as i64])
+                .unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+}
+
+pub struct SessionStore {
+    backend: Box<dyn SessionBackend>,
+    ttl: Duration,
+}
+
+impl SessionStore {
+    pub fn new(backend: Box<dyn SessionBackend>, ttl: Duration) -> Self {
+        SessionStore { backend, ttl }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn insert(&mut self, session_id: u64, username: String) {
+        let expires_at = unix_timestamp() + self.ttl.as_secs();
+        self.backend.insert(session_id, &username, expires_at);
+    }
+
+    pub fn remove(&mut self, session_id: u64) {
+        self.backend.remove(session_id);
+    }
+
+    pub fn remove_user(&mut self, username: &str) {
+        self.backend.remove_user(username);
+    }
+
+    pub fn get(&self, session_id: u64) -> Option<String> {
+        match self.backend.get(session_id) {
+            Some((username, expires_at)) if expires_at > unix_timestamp() => Some(username),
+            _ => None,
+        }
+    }
+
+    // Sliding expiry: pushes the deadline out again on activity. Writes are
+    // skipped until at least a minute of the window has been used up.
+    pub fn renew(&mut self, session_id: u64) -> bool {
+        let now = unix_timestamp();
+        let expires_at = now + self.ttl.as_secs();
+
+        match self.backend.get(session_id) {
+            Some((_, current)) if current > now && current + 60 <= expires_at => {
+                self.backend.set_expiry(session_id, expires_at);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn purge_expired(&mut self) -> usize {
+        self.backend.remove_expired(unix_timestamp())
+    }
+}
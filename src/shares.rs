@@ -0,0 +1,238 @@
+use rocket::http::Status;
+use rocket::response::status::Created;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, Responder, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::archive::Archive;
+use crate::database::DatabaseState;
+use crate::encryption;
+use crate::storage::{self, DownloadRequest, FileDownload, StorageState};
+use crate::users;
+use crate::{unix_timestamp, AuthenticatedSession};
+
+const DEFAULT_SHARE_LIFETIME: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct NewShare {
+    pub path: String,
+    pub expires_at: Option<u64>,
+    pub password: Option<String>,
+    pub max_downloads: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ShareRecord {
+    pub token: String,
+    pub url: String,
+    pub path: String,
+    pub is_dir: bool,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub has_password: bool,
+    pub max_downloads: Option<u64>,
+    pub downloads: u64,
+}
+
+struct Share {
+    username: String,
+    path: String,
+    expires_at: u64,
+    password: Option<String>,
+    max_downloads: Option<u64>,
+    downloads: u64,
+}
+
+#[derive(Responder)]
+pub enum ShareDownload {
+    File(FileDownload),
+    Folder(Archive),
+}
+
+fn generate_share_token() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+fn read_record(row: &rusqlite::Row) -> rusqlite::Result<ShareRecord> {
+    let token: String = row.get(0)?;
+    Ok(ShareRecord {
+        url: format!("/s/{}", token),
+        token,
+        path: row.get(1)?,
+        is_dir: row.get(2)?,
+        created_at: row.get::<_, i64>(3)? as u64,
+        expires_at: row.get::<_, i64>(4)? as u64,
+        has_password: row.get::<_, Option<String>>(5)?.is_some(),
+        max_downloads: row.get::<_, Option<i64>>(6)?.map(|max| max as u64),
+        downloads: row.get::<_, i64>(7)? as u64,
+    })
+}
+
+pub fn list_shares(connection: &Connection, username: &str) -> rusqlite::Result<Vec<ShareRecord>> {
+    let mut statement = connection.prepare(
+        "SELECT token, path, is_dir, created_at, expires_at, password, max_downloads, downloads
+        FROM shares WHERE username = ?1 ORDER BY created_at DESC")?;
+    let shares = statement.query_map(params![username], read_record)?;
+    shares.collect()
+}
+
+// Shares of disabled or deleted accounts are never served.
+fn find_share(connection: &Connection, token: &str) -> rusqlite::Result<Option<Share>> {
+    connection.query_row(
+        "SELECT shares.username, path, expires_at, shares.password, max_downloads, downloads
+        FROM shares JOIN users ON users.username = shares.username
+        WHERE token = ?1 AND users.disabled = 0",
+        params![token],
+        |row| Ok(Share {
+            username: row.get(0)?,
+            path: row.get(1)?,
+            expires_at: row.get::<_, i64>(2)? as u64,
+            password: row.get(3)?,
+            max_downloads: row.get::<_, Option<i64>>(4)?.map(|max| max as u64),
+            downloads: row.get::<_, i64>(5)? as u64,
+        }))
+        .optional()
+}
+
+// Counts a download, failing if another request used up the last one first.
+fn record_download(connection: &Connection, token: &str) -> rusqlite::Result<bool> {
+    let updated = connection.execute(
+        "UPDATE shares SET downloads = downloads + 1
+        WHERE token = ?1 AND (max_downloads IS NULL OR downloads < max_downloads)",
+        params![token])?;
+    Ok(updated == 1)
+}
+
+pub fn remove_expired(connection: &Connection) -> rusqlite::Result<usize> {
+    connection.execute("DELETE FROM shares WHERE expires_at <= ?1", params![unix_timestamp() as i64])
+}
+
+#[post("/share", data = "<new_share>")]
+pub async fn create_share(session: AuthenticatedSession, audit: Audit, new_share: Json<NewShare>, storage: &State<StorageState>,
+    database: &State<DatabaseState>) -> Result<Created<Json<ShareRecord>>, Status> {
+    audit.record(&session.username, "share", &new_share.path);
+
+    let path = match storage::relative_path(Path::new(new_share.path.trim_start_matches('/'))) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Err(Status::Forbidden),
+    };
+
+    let is_dir = match storage.metadata(&session.username, &path).await {
+        Ok(entry) => entry.is_dir,
+        Err(_) => return Err(Status::NotFound),
+    };
+
+    let now = unix_timestamp();
+    let expires_at = new_share.expires_at.unwrap_or(now + DEFAULT_SHARE_LIFETIME);
+    if expires_at <= now || new_share.max_downloads == Some(0) {
+        return Err(Status::BadRequest);
+    }
+
+    let password_hash = match new_share.password.as_deref() {
+        Some("") => return Err(Status::BadRequest),
+        Some(password) => Some(users::hash_password(password).ok_or(Status::InternalServerError)?),
+        None => None,
+    };
+
+    let relative_path = path.to_string_lossy().to_string();
+    let token = generate_share_token();
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    connection.execute(
+        "INSERT INTO shares (token, username, path, is_dir, created_at, expires_at, password, max_downloads, downloads)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 0)",
+        params![token, session.username, relative_path, is_dir, now as i64, expires_at as i64,
+            password_hash, new_share.max_downloads.map(|max| max as i64)])
+        .map_err(|_| Status::InternalServerError)?;
+
+    let record = ShareRecord {
+        url: format!("/s/{}", token),
+        token,
+        path: relative_path,
+        is_dir,
+        created_at: now,
+        expires_at,
+        has_password: password_hash.is_some(),
+        max_downloads: new_share.max_downloads,
+        downloads: 0,
+    };
+    Ok(Created::new(record.url.clone()).body(Json(record)))
+}
+
+#[get("/share")]
+pub async fn get_shares(session: AuthenticatedSession, database: &State<DatabaseState>) -> Result<Json<Vec<ShareRecord>>, Status> {
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    list_shares(&connection, &session.username).map(Json).map_err(|_| Status::InternalServerError)
+}
+
+#[delete("/share/<token>")]
+pub async fn revoke_share(session: AuthenticatedSession, audit: Audit, token: String, database: &State<DatabaseState>) -> Status {
+    audit.record(&session.username, "revoke_share", &token);
+
+    let deleted = match database.lock() {
+        Ok(connection) => connection.execute(
+            "DELETE FROM shares WHERE token = ?1 AND username = ?2", params![token, session.username]),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match deleted {
+        Ok(1) => Status::Ok,
+        Ok(_) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+// Anonymous download. Every request counts towards the download limit,
+// including resumed range requests.
+#[get("/s/<token>?<password>")]
+pub async fn get_shared(token: String, password: Option<String>, audit: Audit, download: DownloadRequest,
+    storage: &State<StorageState>, database: &State<DatabaseState>) -> Result<ShareDownload, Status> {
+    let share = match database.lock() {
+        Ok(connection) => match find_share(&connection, &token) {
+            Ok(Some(share)) => share,
+            Ok(None) => return Err(Status::NotFound),
+            Err(_) => return Err(Status::InternalServerError),
+        },
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    audit.record(&share.username, "shared_download", &share.path);
+
+    if share.expires_at <= unix_timestamp() || share.max_downloads.is_some_and(|max| share.downloads >= max) {
+        return Err(Status::Gone);
+    }
+
+    // 401 would be caught and redirected to the login page, which means
+    // nothing to someone without an account.
+    if let Some(password_hash) = &share.password {
+        match password {
+            Some(password) if users::verify_password(&password, password_hash) => (),
+            _ => return Err(Status::Forbidden),
+        }
+    }
+
+    let path = storage::relative_path(Path::new(&share.path)).ok_or(Status::NotFound)?;
+    let entry = storage.metadata(&share.username, &path).await.map_err(|_| Status::NotFound)?;
+
+    // Nobody holding the link has the owner's keys.
+    let download = if entry.is_dir {
+        ShareDownload::Folder(Archive::shared(storage, &share.username, &path).await?)
+    } else {
+        let header = storage.read_prefix(&share.username, &path, encryption::HEADER_LEN).await.map_err(|_| Status::NotFound)?;
+        if encryption::header_key_id(&header).is_some() {
+            return Err(Status::Forbidden);
+        }
+        ShareDownload::File(storage.download(&share.username, &path, download).await.map_err(|_| Status::NotFound)?)
+    };
+
+    match database.lock() {
+        Ok(connection) => match record_download(&connection, &token) {
+            Ok(true) => Ok(download),
+            Ok(false) => Err(Status::Gone),
+            Err(_) => Err(Status::InternalServerError),
+        },
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
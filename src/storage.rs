@@ -0,0 +1,301 @@
+use async_recursion::async_recursion;
+use rocket::request::{self, FromRequest};
+use rocket::{Request, Responder};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+
+use crate::copy;
+use crate::database::DatabaseState;
+use crate::dedup;
+use crate::filenames;
+use crate::encryption::DecryptedFile;
+use crate::quota::{self, QuotaState, Usage};
+use crate::range::{unix_secs, BoxedReader, RangedFile};
+use crate::s3::ObjectDownload;
+use crate::{resolve_user_path, trash, upload, versions, MyAppConfig};
+
+// Where the handlers keep user files, trash included. Paths given to a
+// backend are relative to the user's space and already checked with
+// `relative_path`. `storage_backend = "local"` keeps the plain directory
+// layout, "s3" stores files as objects in an S3-compatible bucket. Uploads
+// are always staged on the local disk first, whatever the backend, and
+// handed over with `store`.
+pub type StorageState = Arc<dyn Storage>;
+
+pub struct StoredEntry {
+    pub is_dir: bool,
+    pub size: u64,
+    // Seconds since the epoch, 0 when the backend doesn't know.
+    pub modified: u64,
+}
+
+// Conditional and range headers of a download, for backends that answer
+// them remotely instead of through `RangedFile`.
+#[derive(Clone, Default)]
+pub struct DownloadRequest {
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+}
+
+#[derive(Responder)]
+pub enum FileDownload {
+    Local(RangedFile),
+    Remote(ObjectDownload),
+    Decrypted(DecryptedFile),
+}
+
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    async fn metadata(&self, username: &str, path: &Path) -> std::io::Result<StoredEntry>;
+
+    // Every file of the user, relative to their space, trash excluded.
+    async fn list_files(&self, username: &str) -> std::io::Result<Vec<String>>;
+
+    // The files and folders directly inside `directory`, with their metadata.
+    async fn entries(&self, username: &str, directory: &Path) -> std::io::Result<Vec<(String, StoredEntry)>>;
+
+    // The names of the files and folders directly inside `directory`.
+    async fn entry_names(&self, username: &str, directory: &Path) -> std::io::Result<Vec<String>> {
+        Ok(self.entries(username, directory).await?.into_iter().map(|(name, _)| name).collect())
+    }
+
+    // What a file, or everything below a folder, takes up.
+    async fn usage(&self, username: &str, path: &Path) -> std::io::Result<Usage>;
+
+    async fn download(&self, username: &str, path: &Path, request: DownloadRequest) -> std::io::Result<FileDownload>;
+
+    // Up to the first `len` bytes of a file, fewer if it is shorter.
+    async fn read_prefix(&self, username: &str, path: &Path, len: usize) -> std::io::Result<Vec<u8>>;
+
+    async fn reader(&self, username: &str, path: &Path) -> std::io::Result<BoxedReader>;
+
+    // Moves a finished staging file into place, replacing any existing file.
+    async fn store(&self, username: &str, path: &Path, staging_path: &Path) -> std::io::Result<()>;
+
+    // Like `store`, for rewriting the same contents, so no version is kept.
+    async fn replace(&self, username: &str, path: &Path, staging_path: &Path) -> std::io::Result<()>;
+
+    // Moves a file or folder to the trash.
+    async fn remove(&self, username: &str, path: &Path) -> std::io::Result<()>;
+
+    // Deletes a file or folder for good.
+    async fn purge(&self, username: &str, path: &Path) -> std::io::Result<()>;
+
+    // Renames or moves a file or folder.
+    async fn rename(&self, username: &str, old_path: &Path, new_path: &Path) -> std::io::Result<()>;
+
+    // Copies a file, replacing any existing file like `store`.
+    async fn copy(&self, username: &str, source: &Path, target: &Path) -> std::io::Result<()>;
+
+    // Makes an empty folder, for backends that have folders of their own.
+    async fn create_folder(&self, username: &str, path: &Path) -> std::io::Result<()>;
+}
+
+// A client supplied path that stays inside the user's space and isn't the root.
+pub fn relative_path(path: &Path) -> Option<PathBuf> {
+    resolve_user_path(Path::new(""), path).filter(|path| !path.as_os_str().is_empty())
+}
+
+// WebDAV and file versions only exist on the local backend.
+pub fn is_local(app_config: &MyAppConfig) -> bool {
+    app_config.storage_backend != "s3"
+}
+
+pub fn is_trash(path: &Path) -> bool {
+    path.starts_with(trash::TRASH_FOLDER)
+}
+
+pub fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DownloadRequest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let header = |name: &str| request.headers().get_one(name).map(|value| value.to_string());
+        request::Outcome::Success(DownloadRequest {
+            range: header("Range"),
+            if_range: header("If-Range"),
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+        })
+    }
+}
+
+// Files live under `<directory>/<username>`. Deleting goes through the
+// trash and replacing a file keeps the old one as a version.
+pub struct LocalStorage {
+    app_config: MyAppConfig,
+    database: DatabaseState,
+    quota: QuotaState,
+}
+
+impl LocalStorage {
+    pub fn new(app_config: MyAppConfig, database: DatabaseState, quota: QuotaState) -> LocalStorage {
+        LocalStorage { app_config, database, quota }
+    }
+
+    fn user_directory(&self, username: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{}", self.app_config.directory, username))
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for LocalStorage {
+    async fn metadata(&self, username: &str, path: &Path) -> std::io::Result<StoredEntry> {
+        let metadata = tokio::fs::metadata(self.user_directory(username).join(path)).await?;
+        Ok(StoredEntry {
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().map(unix_secs).unwrap_or(0),
+        })
+    }
+
+    async fn list_files(&self, username: &str) -> std::io::Result<Vec<String>> {
+        let user_directory = self.user_directory(username);
+        let paths = traverse_directory(&user_directory, &user_directory).await?;
+        Ok(paths.into_iter().map(|path| path.to_string_lossy().to_string()).collect())
+    }
+
+    async fn entries(&self, username: &str, directory: &Path) -> std::io::Result<Vec<(String, StoredEntry)>> {
+        let mut entries = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(self.user_directory(username).join(directory)).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let metadata = match entry.metadata().await {
+                Ok(metadata) if metadata.is_dir() || metadata.is_file() => metadata,
+                _ => continue,
+            };
+            entries.push((entry.file_name().to_string_lossy().to_string(), StoredEntry {
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata.modified().map(unix_secs).unwrap_or(0),
+            }));
+        }
+        Ok(entries)
+    }
+
+    async fn entry_names(&self, username: &str, directory: &Path) -> std::io::Result<Vec<String>> {
+        filenames::local_entry_names(&self.user_directory(username).join(directory))
+    }
+
+    async fn usage(&self, username: &str, path: &Path) -> std::io::Result<Usage> {
+        let path = self.user_directory(username).join(path);
+        tokio::fs::metadata(&path).await?;
+        Ok(quota::measure(&path))
+    }
+
+    async fn download(&self, username: &str, path: &Path, _request: DownloadRequest) -> std::io::Result<FileDownload> {
+        let file = RangedFile::open(&self.user_directory(username).join(path)).await?;
+        Ok(FileDownload::Local(file.attachment(&file_name(path))))
+    }
+
+    async fn read_prefix(&self, username: &str, path: &Path, len: usize) -> std::io::Result<Vec<u8>> {
+        let file = tokio::fs::File::open(self.user_directory(username).join(path)).await?;
+        let mut prefix = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut prefix).await?;
+        Ok(prefix)
+    }
+
+    async fn reader(&self, username: &str, path: &Path) -> std::io::Result<BoxedReader> {
+        let file = tokio::fs::File::open(self.user_directory(username).join(path)).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn store(&self, username: &str, path: &Path, staging_path: &Path) -> std::io::Result<()> {
+        let target = self.user_directory(username).join(path);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Keep what is being overwritten so it can be restored later.
+        versions::keep_version(&self.database, &self.app_config, &self.quota, username, &target).await?;
+        tokio::fs::rename(staging_path, &target).await?;
+        dedup::store(&self.app_config, &target).await;
+        Ok(())
+    }
+
+    async fn replace(&self, username: &str, path: &Path, staging_path: &Path) -> std::io::Result<()> {
+        let target = self.user_directory(username).join(path);
+        tokio::fs::rename(staging_path, &target).await?;
+        dedup::store(&self.app_config, &target).await;
+        Ok(())
+    }
+
+    async fn remove(&self, username: &str, path: &Path) -> std::io::Result<()> {
+        trash::move_to_trash(&self.database, self, username, path).await.map(|_| ())
+    }
+
+    async fn purge(&self, username: &str, path: &Path) -> std::io::Result<()> {
+        let path = self.user_directory(username).join(path);
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&path).await,
+            Ok(_) => tokio::fs::remove_file(&path).await,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn rename(&self, username: &str, old_path: &Path, new_path: &Path) -> std::io::Result<()> {
+        let user_directory = self.user_directory(username);
+        let old_path = user_directory.join(old_path);
+        let new_path = user_directory.join(new_path);
+
+        // A file being replaced at the destination is kept as a version.
+        versions::keep_version(&self.database, &self.app_config, &self.quota, username, &new_path).await?;
+        if let Some(parent) = new_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::rename(&old_path, &new_path).await?;
+        versions::track_rename(&self.database, &user_directory, username, &old_path, &new_path);
+        Ok(())
+    }
+
+    // Staged first so the copy replaces the target in one rename.
+    async fn copy(&self, username: &str, source: &Path, target: &Path) -> std::io::Result<()> {
+        let (staging_path, _) = upload::create_staging_file(&self.app_config.directory, username).await?;
+        let copied = match copy::clone_file(&self.user_directory(username).join(source), &staging_path).await {
+            Ok(_) => self.store(username, target, &staging_path).await,
+            Err(error) => Err(error),
+        };
+        if copied.is_err() {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+        }
+        copied
+    }
+
+    async fn create_folder(&self, username: &str, path: &Path) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(self.user_directory(username).join(path)).await
+    }
+}
+
+#[async_recursion]
+async fn traverse_directory(path: &Path, base_path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut file_paths: Vec<PathBuf> = Vec::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let file_path = entry.path();
+
+        // The trash is listed separately through /trash.
+        if file_path == base_path.join(trash::TRASH_FOLDER) {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            let nested_files = traverse_directory(&file_path, base_path).await?;
+            file_paths.extend(nested_files);
+        } else if metadata.is_file() {
+            let relative_path = file_path.strip_prefix(base_path).unwrap_or(&file_path);
+            file_paths.push(relative_path.to_path_buf());
+        }
+    }
+
+    Ok(file_paths)
+}
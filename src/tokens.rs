@@ -0,0 +1,243 @@
+use rocket::http::{Method, Status};
+use rocket::request;
+use rocket::response::status::Created;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, Request, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::{unix_timestamp, AuthenticatedSession};
+
+// Personal access tokens for scripts and backups, sent as
+// `Authorization: Bearer <token>` instead of the session cookie. Only a hash
+// of each token is stored; the token itself is shown once when it is created.
+const TOKEN_PREFIX: &str = "mydrive_";
+
+// How often the last use of a token is written back.
+const LAST_USED_RESOLUTION: u64 = 60;
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Upload,
+    Full,
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    pub name: String,
+    pub scope: TokenScope,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct TokenRecord {
+    pub id: String,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub record: TokenRecord,
+}
+
+impl TokenScope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Upload => "upload",
+            TokenScope::Full => "full",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<TokenScope> {
+        match scope {
+            "read" => Some(TokenScope::Read),
+            "upload" => Some(TokenScope::Upload),
+            "full" => Some(TokenScope::Full),
+            _ => None,
+        }
+    }
+
+    // Read tokens can only look, and not at the admin pages. Upload tokens
+    // can also add files and folders, but not rename, move, copy, delete or
+    // overwrite anything, see `ConflictPolicy::for_scope`.
+    fn allows(&self, method: Method, path: &str) -> bool {
+        match (self, method) {
+            (TokenScope::Full, _) => true,
+            _ if path == "/admin" || path.starts_with("/admin/") => false,
+            (_, Method::Get | Method::Head | Method::Options) => true,
+            (TokenScope::Upload, Method::Post) => path == "/file" || path == "/files" || path == "/upload" || (path.starts_with("/folder/") && !path.starts_with("/folder/copy/")),
+            (TokenScope::Upload, Method::Patch | Method::Delete) => path.starts_with("/upload/"),
+            _ => false,
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn read_record(row: &rusqlite::Row) -> rusqlite::Result<TokenRecord> {
+    Ok(TokenRecord {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scope: TokenScope::parse(&row.get::<_, String>(2)?).unwrap_or(TokenScope::Read),
+        created_at: row.get::<_, i64>(3)? as u64,
+        expires_at: row.get::<_, Option<i64>>(4)?.map(|expires_at| expires_at as u64),
+        last_used_at: row.get::<_, Option<i64>>(5)?.map(|last_used_at| last_used_at as u64),
+    })
+}
+
+fn list_tokens(connection: &Connection, username: &str) -> rusqlite::Result<Vec<TokenRecord>> {
+    let mut statement = connection.prepare(
+        "SELECT id, name, scope, created_at, expires_at, last_used_at FROM api_tokens WHERE username = ?1 ORDER BY created_at DESC")?;
+    let tokens = statement.query_map(params![username], read_record)?;
+    tokens.collect()
+}
+
+// The owner and scope of a live token. Tokens of disabled or removed users stop working.
+fn find_token(connection: &Connection, token: &str) -> rusqlite::Result<Option<(String, TokenScope)>> {
+    let now = unix_timestamp();
+    let found = connection.query_row(
+        "SELECT api_tokens.id, api_tokens.username, api_tokens.scope, api_tokens.last_used_at FROM api_tokens
+        JOIN users ON users.username = api_tokens.username
+        WHERE api_tokens.token_hash = ?1 AND users.disabled = 0
+        AND (api_tokens.expires_at IS NULL OR api_tokens.expires_at > ?2)",
+        params![hash_token(token), now as i64],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, Option<i64>>(3)?)))
+        .optional()?;
+
+    let (id, username, scope, last_used_at) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    if last_used_at.map(|last_used_at| last_used_at as u64 + LAST_USED_RESOLUTION <= now).unwrap_or(true) {
+        connection.execute("UPDATE api_tokens SET last_used_at = ?2 WHERE id = ?1", params![id, now as i64])?;
+    }
+    Ok(TokenScope::parse(&scope).map(|scope| (username, scope)))
+}
+
+// Like `find_token`, without counting as a use.
+pub fn token_owner(connection: &Connection, token: &str) -> Option<String> {
+    connection.query_row(
+        "SELECT api_tokens.username FROM api_tokens
+        JOIN users ON users.username = api_tokens.username
+        WHERE api_tokens.token_hash = ?1 AND users.disabled = 0
+        AND (api_tokens.expires_at IS NULL OR api_tokens.expires_at > ?2)",
+        params![hash_token(token), unix_timestamp() as i64],
+        |row| row.get(0))
+        .optional()
+        .ok()
+        .flatten()
+}
+
+pub fn remove_expired(connection: &Connection) -> rusqlite::Result<usize> {
+    connection.execute("DELETE FROM api_tokens WHERE expires_at <= ?1", params![unix_timestamp() as i64])
+}
+
+// The bearer token half of the `AuthenticatedSession` guard. Token requests
+// have no session of their own, so they get session id 0, which never has
+// encryption keys unlocked.
+pub fn authenticate(request: &Request<'_>, authorization: &str) -> request::Outcome<AuthenticatedSession, ()> {
+    let token = match authorization.strip_prefix("Bearer ") {
+        Some(token) => token.trim(),
+        None => return request::Outcome::Failure((Status::Unauthorized, ())),
+    };
+
+    let found = match request.rocket().state::<DatabaseState>().unwrap().lock() {
+        Ok(connection) => find_token(&connection, token),
+        Err(_) => return request::Outcome::Failure((Status::InternalServerError, ())),
+    };
+
+    match found {
+        Ok(Some((username, scope))) if scope.allows(request.method(), request.uri().path().as_str()) => {
+            request::Outcome::Success(AuthenticatedSession {
+                session_id: 0,
+                username,
+                token_scope: Some(scope),
+            })
+        },
+        Ok(Some(_)) => request::Outcome::Failure((Status::Forbidden, ())),
+        Ok(None) => request::Outcome::Failure((Status::Unauthorized, ())),
+        Err(_) => request::Outcome::Failure((Status::InternalServerError, ())),
+    }
+}
+
+// Tokens are managed from a logged in browser only, so a leaked token can't mint more.
+#[get("/tokens")]
+pub async fn get_tokens(session: AuthenticatedSession, database: &State<DatabaseState>) -> Result<Json<Vec<TokenRecord>>, Status> {
+    if session.token_scope.is_some() {
+        return Err(Status::Forbidden);
+    }
+
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    list_tokens(&connection, &session.username).map(Json).map_err(|_| Status::InternalServerError)
+}
+
+#[post("/tokens", data = "<new_token>")]
+pub async fn create_token(session: AuthenticatedSession, audit: Audit, new_token: Json<NewToken>,
+    database: &State<DatabaseState>) -> Result<Created<Json<CreatedToken>>, Status> {
+    audit.record(&session.username, "create_token", &new_token.name);
+
+    if session.token_scope.is_some() {
+        return Err(Status::Forbidden);
+    }
+
+    let now = unix_timestamp();
+    let name = new_token.name.trim();
+    if name.is_empty() || name.len() > 100 || new_token.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false) {
+        return Err(Status::BadRequest);
+    }
+
+    let id = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+    let token = format!("{}{}", TOKEN_PREFIX, hex::encode(rand::thread_rng().gen::<[u8; 32]>()));
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    connection.execute(
+        "INSERT INTO api_tokens (id, username, name, token_hash, scope, created_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![id, session.username, name, hash_token(&token), new_token.scope.as_str(), now as i64,
+            new_token.expires_at.map(|expires_at| expires_at as i64)])
+        .map_err(|_| Status::InternalServerError)?;
+
+    let record = TokenRecord {
+        id,
+        name: name.to_string(),
+        scope: new_token.scope,
+        created_at: now,
+        expires_at: new_token.expires_at,
+        last_used_at: None,
+    };
+    Ok(Created::new(format!("/tokens/{}", record.id)).body(Json(CreatedToken { token, record })))
+}
+
+#[delete("/tokens/<id>")]
+pub async fn revoke_token(session: AuthenticatedSession, audit: Audit, id: String, database: &State<DatabaseState>) -> Status {
+    audit.record(&session.username, "revoke_token", &id);
+
+    if session.token_scope.is_some() {
+        return Status::Forbidden;
+    }
+
+    let deleted = match database.lock() {
+        Ok(connection) => connection.execute(
+            "DELETE FROM api_tokens WHERE id = ?1 AND username = ?2", params![id, session.username]),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match deleted {
+        Ok(1) => Status::Ok,
+        Ok(_) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
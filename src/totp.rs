@@ -0,0 +1,445 @@
+use hmac::{Hmac, Mac};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::encryption::{KeyringState, PasswordConfirmation, UnlockedKeys};
+use crate::lockout;
+use crate::session::SessionStoreState;
+use crate::users::{self, AdminSession};
+use crate::{start_session, unix_timestamp, AuthenticatedSession};
+
+// Optional second login step with time-based one-time passwords (RFC 6238:
+// HMAC-SHA1, 6 digits, 30 second steps) as shown by authenticator apps. The
+// secret and the hashes of the one-time recovery codes live on the user's row
+// next to their password hash. With it turned on a correct password only earns
+// a short-lived challenge cookie, and the session cookie is issued by
+// /login/verify once a code checks out.
+pub type ChallengeState = Arc<Mutex<HashMap<String, Challenge>>>;
+
+const ISSUER: &str = "MyDrive";
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+const CHALLENGE_COOKIE: &str = "login_challenge";
+const CHALLENGE_TTL_SECONDS: u64 = 5 * 60;
+const MAX_ATTEMPTS: u32 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// A login that got the password right and still owes a code. Encryption
+// keys are unwrapped with the password at that point and wait here.
+pub struct Challenge {
+    username: String,
+    keys: Option<UnlockedKeys>,
+    expires_at: u64,
+    attempts: u32,
+}
+
+#[derive(Deserialize)]
+pub struct Code {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct Enrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: usize,
+}
+
+struct TotpRecord {
+    secret: Option<String>,
+    enabled: bool,
+    last_step: u64,
+    recovery_codes: Vec<String>,
+}
+
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+// One step either side is accepted for clock drift. Returns the step the
+// code belongs to so it can't be used twice.
+fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = now / STEP_SECONDS;
+    [current.saturating_sub(1), current, current + 1].into_iter().find(|step| code_at(secret, *step) == code)
+}
+
+fn decode_secret(secret: &str) -> Option<Vec<u8>> {
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+// Recovery codes are random, so a plain hash is enough to keep them safe
+// and they can be looked up without checking each one with argon2.
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = hex::encode(rand::thread_rng().gen::<[u8; 5]>());
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+fn find_record(connection: &Connection, username: &str) -> rusqlite::Result<Option<TotpRecord>> {
+    connection.query_row(
+        "SELECT totp_secret, totp_enabled, totp_last_step, recovery_codes FROM users WHERE username = ?1",
+        params![username],
+        |row| Ok(TotpRecord {
+            secret: row.get(0)?,
+            enabled: row.get(1)?,
+            last_step: row.get::<_, i64>(2)? as u64,
+            recovery_codes: row.get::<_, String>(3)?.split(',').filter(|hash| !hash.is_empty()).map(|hash| hash.to_string()).collect(),
+        }))
+        .optional()
+}
+
+pub fn is_enabled(connection: &Connection, username: &str) -> bool {
+    matches!(find_record(connection, username), Ok(Some(record)) if record.enabled)
+}
+
+fn set_recovery_codes(connection: &Connection, username: &str, hashes: &[String]) -> rusqlite::Result<usize> {
+    connection.execute("UPDATE users SET recovery_codes = ?2 WHERE username = ?1", params![username, hashes.join(",")])
+}
+
+fn disable(connection: &Connection, username: &str) -> rusqlite::Result<bool> {
+    let updated = connection.execute(
+        "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = 0, recovery_codes = '' WHERE username = ?1",
+        params![username])?;
+    Ok(updated == 1)
+}
+
+// Checks a code from the authenticator or a recovery code, and uses it up.
+fn redeem_code(connection: &Connection, username: &str, code: &str) -> rusqlite::Result<bool> {
+    let record = match find_record(connection, username)? {
+        Some(record) if record.enabled => record,
+        _ => return Ok(false),
+    };
+
+    let secret = record.secret.as_deref().and_then(decode_secret).unwrap_or_default();
+    if let Some(step) = matching_step(&secret, code, unix_timestamp()) {
+        if step <= record.last_step {
+            return Ok(false);
+        }
+        connection.execute("UPDATE users SET totp_last_step = ?2 WHERE username = ?1", params![username, step as i64])?;
+        return Ok(true);
+    }
+
+    let hash = hash_recovery_code(code);
+    if !record.recovery_codes.contains(&hash) {
+        return Ok(false);
+    }
+    let remaining: Vec<String> = record.recovery_codes.into_iter().filter(|recovery_code| *recovery_code != hash).collect();
+    set_recovery_codes(connection, username, &remaining)?;
+    Ok(true)
+}
+
+pub fn start_challenge(challenges: &ChallengeState, cookies: &CookieJar<'_>, username: &str, keys: Option<UnlockedKeys>) {
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    if let Ok(mut challenges) = challenges.lock() {
+        challenges.insert(token.clone(), Challenge {
+            username: username.to_string(),
+            keys,
+            expires_at: unix_timestamp() + CHALLENGE_TTL_SECONDS,
+            attempts: 0,
+        });
+    }
+
+    let mut cookie = Cookie::new(CHALLENGE_COOKIE, token);
+    cookie.set_path("/");
+    cookie.set_secure(true);
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_max_age(rocket::time::Duration::seconds(CHALLENGE_TTL_SECONDS as i64));
+    cookies.add_private(cookie);
+}
+
+pub fn purge_expired(challenges: &ChallengeState) {
+    let now = unix_timestamp();
+    if let Ok(mut challenges) = challenges.lock() {
+        challenges.retain(|_, challenge| challenge.expires_at > now);
+    }
+}
+
+// Second half of the login: trades the challenge cookie and a code for a session.
+#[post("/login/verify", data = "<code>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_login(code: Json<Code>, audit: Audit, client_ip: Option<IpAddr>, cookies: &CookieJar<'_>,
+    challenges: &State<ChallengeState>, database: &State<DatabaseState>, session_store_state: &State<SessionStoreState>,
+    keyring: &State<KeyringState>) -> Status {
+    let token = match cookies.get_private(CHALLENGE_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Status::Forbidden,
+    };
+
+    // Each challenge only gets a few guesses before the password is needed again.
+    let username = match challenges.lock() {
+        Ok(mut challenges) => match challenges.get_mut(&token) {
+            Some(challenge) if challenge.expires_at > unix_timestamp() && challenge.attempts < MAX_ATTEMPTS => {
+                challenge.attempts += 1;
+                challenge.username.clone()
+            },
+            Some(_) => {
+                challenges.remove(&token);
+                cookies.remove_private(Cookie::new(CHALLENGE_COOKIE, ""));
+                return Status::Forbidden;
+            },
+            None => {
+                cookies.remove_private(Cookie::new(CHALLENGE_COOKIE, ""));
+                return Status::Forbidden;
+            },
+        },
+        Err(_) => return Status::InternalServerError,
+    };
+
+    // Wrong codes count towards the same lockout as wrong passwords.
+    match database.lock() {
+        Ok(connection) => {
+            if lockout::check(&connection, &username, client_ip).is_some() {
+                lockout::record_failure(&connection, &username, client_ip, "locked");
+                audit.record(&username, "login_failed", "");
+                return Status::TooManyRequests;
+            }
+            match redeem_code(&connection, &username, &code.code) {
+                Ok(true) => lockout::record_success(&connection, &username),
+                Ok(false) => {
+                    lockout::record_failure(&connection, &username, client_ip, "code");
+                    audit.record(&username, "login_failed", "");
+                    return Status::Forbidden;
+                },
+                Err(_) => return Status::InternalServerError,
+            }
+        },
+        Err(_) => return Status::InternalServerError,
+    }
+
+    let challenge = match challenges.lock() {
+        Ok(mut challenges) => challenges.remove(&token),
+        Err(_) => return Status::InternalServerError,
+    };
+    cookies.remove_private(Cookie::new(CHALLENGE_COOKIE, ""));
+    match challenge {
+        Some(challenge) => {
+            start_session(cookies, session_store_state, keyring, &challenge.username, challenge.keys);
+            audit.record(&challenge.username, "login", "");
+            Status::Ok
+        },
+        None => Status::Forbidden,
+    }
+}
+
+#[get("/totp")]
+pub async fn get_totp(session: AuthenticatedSession, database: &State<DatabaseState>) -> Result<Json<TwoFactorStatus>, Status> {
+    let record = match database.lock() {
+        Ok(connection) => find_record(&connection, &session.username).map_err(|_| Status::InternalServerError)?,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    match record {
+        Some(record) => Ok(Json(TwoFactorStatus {
+            enabled: record.enabled,
+            recovery_codes_left: record.recovery_codes.len(),
+        })),
+        None => Err(Status::NotFound),
+    }
+}
+
+// Starts enrollment with a new secret. It only takes effect once a code
+// from it is confirmed, so a half finished setup can't lock anyone out.
+#[post("/totp")]
+pub async fn enroll_totp(session: AuthenticatedSession, database: &State<DatabaseState>) -> Result<Json<Enrollment>, Status> {
+    let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &rand::thread_rng().gen::<[u8; SECRET_LEN]>());
+
+    match database.lock() {
+        Ok(connection) => {
+            if is_enabled(&connection, &session.username) {
+                return Err(Status::Conflict);
+            }
+            connection.execute("UPDATE users SET totp_secret = ?2 WHERE username = ?1", params![session.username, secret])
+                .map_err(|_| Status::InternalServerError)?;
+        },
+        Err(_) => return Err(Status::InternalServerError),
+    }
+
+    let uri = format!("otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER, username = session.username, secret = secret, digits = DIGITS, period = STEP_SECONDS);
+    Ok(Json(Enrollment { secret, uri }))
+}
+
+#[post("/totp/confirm", data = "<code>")]
+pub async fn confirm_totp(session: AuthenticatedSession, audit: Audit, code: Json<Code>, database: &State<DatabaseState>) -> Result<Json<RecoveryCodes>, Status> {
+    audit.record(&session.username, "enable_totp", "");
+
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    let record = match find_record(&connection, &session.username) {
+        Ok(Some(record)) if record.enabled => return Err(Status::Conflict),
+        Ok(Some(record)) => record,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    let secret = record.secret.as_deref().and_then(decode_secret).ok_or(Status::BadRequest)?;
+    let step = matching_step(&secret, &code.code, unix_timestamp()).ok_or(Status::Forbidden)?;
+
+    let (codes, hashes) = generate_recovery_codes();
+    connection.execute(
+        "UPDATE users SET totp_enabled = 1, totp_last_step = ?2, recovery_codes = ?3 WHERE username = ?1",
+        params![session.username, step as i64, hashes.join(",")])
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(Json(RecoveryCodes { recovery_codes: codes }))
+}
+
+// Replaces all recovery codes, for when they ran low or were exposed.
+#[post("/totp/recovery-codes", data = "<confirmation>")]
+pub async fn regenerate_recovery_codes(session: AuthenticatedSession, audit: Audit, confirmation: Json<PasswordConfirmation>,
+    database: &State<DatabaseState>) -> Result<Json<RecoveryCodes>, Status> {
+    audit.record(&session.username, "recovery_codes", "");
+
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    match users::find_password_hash(&connection, &session.username) {
+        Some(password_hash) if users::verify_password(&confirmation.password, &password_hash) => (),
+        _ => return Err(Status::Forbidden),
+    }
+    if !is_enabled(&connection, &session.username) {
+        return Err(Status::Conflict);
+    }
+
+    let (codes, hashes) = generate_recovery_codes();
+    set_recovery_codes(&connection, &session.username, &hashes).map_err(|_| Status::InternalServerError)?;
+    Ok(Json(RecoveryCodes { recovery_codes: codes }))
+}
+
+#[delete("/totp", data = "<confirmation>")]
+pub async fn disable_totp(session: AuthenticatedSession, audit: Audit, confirmation: Json<PasswordConfirmation>, database: &State<DatabaseState>) -> Status {
+    audit.record(&session.username, "disable_totp", "");
+
+    let connection = match database.lock() {
+        Ok(connection) => connection,
+        Err(_) => return Status::InternalServerError,
+    };
+    match users::find_password_hash(&connection, &session.username) {
+        Some(password_hash) if users::verify_password(&confirmation.password, &password_hash) => (),
+        _ => return Status::Forbidden,
+    }
+
+    match disable(&connection, &session.username) {
+        Ok(_) => Status::Ok,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+// For users who lost both their device and their recovery codes.
+#[delete("/admin/users/<username>/totp")]
+pub async fn reset_totp(admin: AdminSession, audit: Audit, username: String, database: &State<DatabaseState>) -> Status {
+    audit.record_with_target(&admin.username, "reset_totp", "", &username);
+
+    match database.lock() {
+        Ok(connection) => match disable(&connection, &username) {
+            Ok(true) => Status::Ok,
+            Ok(false) => Status::NotFound,
+            Err(_) => Status::InternalServerError,
+        },
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    // The SHA-1 vectors of RFC 6238, cut to the last six digits.
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(code_at(RFC_SECRET, time / STEP_SECONDS), code, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift() {
+        let now = 1111111111;
+        let current = now / STEP_SECONDS;
+        assert_eq!(matching_step(RFC_SECRET, "050471", now), Some(current));
+        assert_eq!(matching_step(RFC_SECRET, " 050471 ", now), Some(current));
+        // 1111111109 falls in the step before.
+        assert_eq!(matching_step(RFC_SECRET, "081804", now), Some(current - 1));
+        assert_eq!(matching_step(RFC_SECRET, "081804", now + STEP_SECONDS), None);
+        assert_eq!(matching_step(RFC_SECRET, &format!("{:06}", code_at(RFC_SECRET, current + 1)), now), Some(current + 1));
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let now = 1111111111;
+        assert_eq!(matching_step(RFC_SECRET, "50471", now), None);
+        assert_eq!(matching_step(RFC_SECRET, "0050471", now), None);
+        assert_eq!(matching_step(RFC_SECRET, "+50471", now), None);
+        assert_eq!(matching_step(RFC_SECRET, "", now), None);
+    }
+
+    #[test]
+    fn decodes_base32_secrets() {
+        assert_eq!(decode_secret("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").as_deref(), Some(RFC_SECRET));
+        assert_eq!(decode_secret("not base32!"), None);
+    }
+
+    #[test]
+    fn normalizes_recovery_codes() {
+        assert_eq!(hash_recovery_code("abcde-12345"), hash_recovery_code(" ABCDE12345 "));
+        assert_ne!(hash_recovery_code("abcde-12345"), hash_recovery_code("abcde-12346"));
+
+        let (codes, hashes) = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for (code, hash) in codes.iter().zip(&hashes) {
+            assert_eq!(code.len(), 11);
+            assert_eq!(&hash_recovery_code(code), hash);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{delete, get, post, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::quota::{self, QuotaState, UserQuota};
+use crate::storage::{self, Storage, StorageState};
+use crate::versions;
+use crate::{unix_timestamp, AuthenticatedSession, MyAppConfig};
+
+// Deleted files and folders are moved to `trash/<id>` in the user's space
+// and the place they came from is kept in the `trash` table, so nothing in
+// the trash ever collides and everything can go back where it was. Old
+// versions follow an item into the trash and are dropped when it is purged.
+// Items keep counting towards the quota's bytes until then, not its files.
+pub const TRASH_FOLDER: &str = "trash";
+
+#[derive(Serialize)]
+pub struct TrashItem {
+    pub id: String,
+    pub name: String,
+    pub original_path: String,
+    pub deleted_at: u64,
+    pub is_dir: bool,
+    pub size: u64,
+    pub files: u64,
+}
+
+fn generate_trash_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 12]>())
+}
+
+fn is_valid_trash_id(trash_id: &str) -> bool {
+    trash_id.len() == 24 && trash_id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn trash_path(trash_id: &str) -> PathBuf {
+    Path::new(TRASH_FOLDER).join(trash_id)
+}
+
+fn read_item(row: &rusqlite::Row) -> rusqlite::Result<TrashItem> {
+    let original_path: String = row.get(1)?;
+    Ok(TrashItem {
+        id: row.get(0)?,
+        name: Path::new(&original_path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+        original_path,
+        deleted_at: row.get::<_, i64>(2)? as u64,
+        is_dir: row.get(3)?,
+        size: row.get::<_, i64>(4)? as u64,
+        files: row.get::<_, i64>(5)? as u64,
+    })
+}
+
+pub fn list_items(connection: &Connection, username: &str) -> rusqlite::Result<Vec<TrashItem>> {
+    let mut statement = connection.prepare(
+        "SELECT id, original_path, deleted_at, is_dir, size, files FROM trash WHERE username = ?1 ORDER BY deleted_at DESC")?;
+    let items = statement.query_map(params![username], read_item)?;
+    items.collect()
+}
+
+fn find_item(connection: &Connection, username: &str, trash_id: &str) -> rusqlite::Result<Option<TrashItem>> {
+    connection.query_row(
+        "SELECT id, original_path, deleted_at, is_dir, size, files FROM trash WHERE username = ?1 AND id = ?2",
+        params![username, trash_id],
+        read_item)
+        .optional()
+}
+
+fn forget_item(connection: &Connection, username: &str, trash_id: &str) -> rusqlite::Result<usize> {
+    connection.execute("DELETE FROM trash WHERE username = ?1 AND id = ?2", params![username, trash_id])
+}
+
+// Moves a file or folder from the user's space into the trash and returns its
+// trash id. The caller takes its files off the user's quota.
+pub async fn move_to_trash(database: &DatabaseState, storage: &dyn Storage, username: &str, path: &Path) -> std::io::Result<String> {
+    let entry = storage.metadata(username, path).await?;
+    let usage = storage.usage(username, path).await?;
+
+    let trash_id = generate_trash_id();
+    let trash_path = trash_path(&trash_id);
+    storage.rename(username, path, &trash_path).await?;
+
+    let original_path = path.to_string_lossy().to_string();
+    let recorded = match database.lock() {
+        Ok(connection) => connection.execute(
+            "INSERT INTO trash (id, username, original_path, deleted_at, is_dir, size, files) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![trash_id, username, original_path, unix_timestamp() as i64, entry.is_dir, usage.bytes as i64, usage.files as i64])
+            .is_ok(),
+        Err(_) => false,
+    };
+
+    if !recorded {
+        let _ = storage.rename(username, &trash_path, path).await;
+        return Err(std::io::Error::other("failed to record trash item"));
+    }
+
+    Ok(trash_id)
+}
+
+pub async fn purge_item(database: &DatabaseState, storage: &StorageState, directory: &str, tracker: &QuotaState, username: &str,
+    trash_id: &str) -> std::io::Result<()> {
+    let trash_path = trash_path(trash_id);
+    let usage = storage.usage(username, &trash_path).await.unwrap_or_default();
+    storage.purge(username, &trash_path).await?;
+    if let Ok(connection) = database.lock() {
+        let _ = forget_item(&connection, username, trash_id);
+    }
+    quota::release(tracker, username, usage.bytes);
+    versions::remove_versions_below(database, directory, tracker, username, &trash_path.to_string_lossy()).await;
+    Ok(())
+}
+
+// Anything sitting in the trash folder without a record (for example from
+// before the trash was tracked) is adopted under its current name.
+pub async fn adopt_untracked_items(database: &DatabaseState, storage: &StorageState, username: &str) {
+    let entries = match storage.entries(username, Path::new(TRASH_FOLDER)).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for (name, entry) in entries {
+        let tracked = is_valid_trash_id(&name) && match database.lock() {
+            Ok(connection) => matches!(find_item(&connection, username, &name), Ok(Some(_))),
+            Err(_) => return,
+        };
+        if tracked {
+            continue;
+        }
+
+        let usage = storage.usage(username, &trash_path(&name)).await.unwrap_or_default();
+        let deleted_at = match entry.modified {
+            0 => unix_timestamp(),
+            modified => modified,
+        };
+
+        let trash_id = generate_trash_id();
+        if storage.rename(username, &trash_path(&name), &trash_path(&trash_id)).await.is_ok() {
+            if let Ok(connection) = database.lock() {
+                let _ = connection.execute(
+                    "INSERT INTO trash (id, username, original_path, deleted_at, is_dir, size, files) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![trash_id, username, name, deleted_at as i64, entry.is_dir, usage.bytes as i64, usage.files as i64]);
+            }
+        }
+    }
+}
+
+pub async fn purge_expired(database: &DatabaseState, storage: &StorageState, directory: &str, tracker: &QuotaState, retention_days: u64) {
+    let cutoff = unix_timestamp().saturating_sub(retention_days * 24 * 60 * 60);
+    let expired: Vec<(String, String)> = match database.lock() {
+        Ok(connection) => {
+            let mut statement = match connection.prepare("SELECT username, id FROM trash WHERE deleted_at < ?1") {
+                Ok(statement) => statement,
+                Err(_) => return,
+            };
+            let rows = statement.query_map(params![cutoff as i64], |row| Ok((row.get(0)?, row.get(1)?)));
+            match rows {
+                Ok(rows) => rows.flatten().collect(),
+                Err(_) => return,
+            }
+        },
+        Err(_) => return,
+    };
+
+    for (username, trash_id) in expired {
+        let _ = purge_item(database, storage, directory, tracker, &username, &trash_id).await;
+    }
+}
+
+#[get("/trash")]
+pub async fn get_trash(session: AuthenticatedSession, database: &State<DatabaseState>, storage: &State<StorageState>) -> Result<Json<Vec<TrashItem>>, Status> {
+    adopt_untracked_items(database, storage, &session.username).await;
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    list_items(&connection, &session.username).map(Json).map_err(|_| Status::InternalServerError)
+}
+
+#[post("/trash/<trash_id>/restore")]
+pub async fn restore_trash_item(session: AuthenticatedSession, audit: Audit, trash_id: String, database: &State<DatabaseState>,
+    storage: &State<StorageState>, quota: UserQuota) -> Status {
+    audit.record(&session.username, "restore", &trash_id);
+
+    if !is_valid_trash_id(&trash_id) {
+        return Status::NotFound;
+    }
+
+    let item = match database.lock() {
+        Ok(connection) => match find_item(&connection, &session.username, &trash_id) {
+            Ok(Some(item)) => item,
+            Ok(None) => return Status::NotFound,
+            Err(_) => return Status::InternalServerError,
+        },
+        Err(_) => return Status::InternalServerError,
+    };
+
+    let destination = match storage::relative_path(Path::new(&item.original_path)) {
+        Some(path) if !storage::is_trash(&path) => path,
+        _ => return Status::Conflict,
+    };
+
+    if storage.metadata(&session.username, &destination).await.is_ok() {
+        return Status::Conflict;
+    }
+
+    if !quota.allows(0, item.files) {
+        return Status::InsufficientStorage;
+    }
+
+    if storage.rename(&session.username, &trash_path(&trash_id), &destination).await.is_err() {
+        return Status::ExpectationFailed;
+    }
+    quota.record(0, item.files as i64);
+
+    if let Ok(connection) = database.lock() {
+        let _ = forget_item(&connection, &session.username, &trash_id);
+    }
+
+    Status::Ok
+}
+
+#[delete("/trash/<trash_id>")]
+pub async fn delete_trash_item(session: AuthenticatedSession, audit: Audit, trash_id: String, app_config: &State<MyAppConfig>,
+    database: &State<DatabaseState>, storage: &State<StorageState>, tracker: &State<QuotaState>) -> Status {
+    audit.record(&session.username, "purge", &trash_id);
+
+    if !is_valid_trash_id(&trash_id) {
+        return Status::NotFound;
+    }
+
+    let exists = match database.lock() {
+        Ok(connection) => matches!(find_item(&connection, &session.username, &trash_id), Ok(Some(_))),
+        Err(_) => return Status::InternalServerError,
+    };
+    if !exists {
+        return Status::NotFound;
+    }
+
+    match purge_item(database, storage, &app_config.directory, tracker, &session.username, &trash_id).await {
+        Ok(_) => Status::Ok,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+#[delete("/trash")]
+pub async fn empty_trash(session: AuthenticatedSession, audit: Audit, app_config: &State<MyAppConfig>, database: &State<DatabaseState>,
+    storage: &State<StorageState>, tracker: &State<QuotaState>) -> Status {
+    audit.record(&session.username, "empty_trash", "");
+
+    adopt_untracked_items(database, storage, &session.username).await;
+    let items = match database.lock() {
+        Ok(connection) => list_items(&connection, &session.username).unwrap_or_default(),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    for item in items {
+        if purge_item(database, storage, &app_config.directory, tracker, &session.username, &item.id).await.is_err() {
+            return Status::InternalServerError;
+        }
+    }
+
+    Status::Ok
+}
@@ -0,0 +1,635 @@
+use base64::Engine;
+use rocket::data::{ByteUnit, Data};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{serde_json, Json};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, head, options, patch, post, Request, State};
+use rocket_multipart_form_data::{multer, MultipartFormData, MultipartFormDataError, MultipartFormDataField, MultipartFormDataOptions, Repetition};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::conflicts::{self, ConflictPolicy, Resolution};
+use crate::encryption::{self, UserKeys};
+use crate::quota::UserQuota;
+use crate::storage::{self, StorageState};
+use crate::filenames;
+use crate::range::format_http_date;
+use crate::{upload_directory, AuthenticatedSession, MyAppConfig};
+
+// Upload sessions follow the tus 1.0.0 core protocol plus the creation,
+// termination and expiration extensions, see
+// https://tus.io/protocols/resumable-upload.
+pub const TUS_VERSION: &str = "1.0.0";
+pub const MAX_UPLOAD_SIZE: ByteUnit = ByteUnit::Terabyte(1);
+
+// Staged files nothing was written to for this long are removed, see
+// `remove_expired_uploads`.
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct UploadInfo {
+    file_name: String,
+    length: u64,
+    #[serde(default)]
+    conflict: Option<ConflictPolicy>,
+    #[serde(default)]
+    modified: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResult {
+    pub path: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<Resolution>,
+}
+
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        TusResponse { status, headers: vec![Header::new("Tus-Resumable", TUS_VERSION)] }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+        for header in self.headers {
+            response.header(header);
+        }
+        response.ok()
+    }
+}
+
+pub struct TusHeaders {
+    upload_length: Option<u64>,
+    upload_offset: Option<u64>,
+    upload_metadata: Option<String>,
+    content_type: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        if headers.get_one("Tus-Resumable") != Some(TUS_VERSION) {
+            return request::Outcome::Failure((Status::PreconditionFailed, ()));
+        }
+
+        request::Outcome::Success(TusHeaders {
+            upload_length: headers.get_one("Upload-Length").and_then(|value| value.parse().ok()),
+            upload_offset: headers.get_one("Upload-Offset").and_then(|value| value.parse().ok()),
+            upload_metadata: headers.get_one("Upload-Metadata").map(|value| value.to_string()),
+            content_type: headers.get_one("Content-Type").map(|value| value.to_string()),
+        })
+    }
+}
+
+// The declared size of a request body, if there is one.
+pub struct ContentLength(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ContentLength {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(ContentLength(request.headers().get_one("Content-Length").and_then(|value| value.parse().ok())))
+    }
+}
+
+pub fn staging_directory(directory: &str, username: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.uploads/{}", directory, username))
+}
+
+pub async fn create_staging_file(directory: &str, username: &str) -> std::io::Result<(PathBuf, tokio::fs::File)> {
+    let staging = staging_directory(directory, username);
+    tokio::fs::create_dir_all(&staging).await?;
+    let path = staging.join(generate_upload_id());
+    let file = tokio::fs::File::create(&path).await?;
+    Ok((path, file))
+}
+
+// Gives a staged upload the modification time the client sent, so
+// `?conflict=if-newer` can compare against it later.
+pub fn set_modified(path: &Path, modified: u64) -> std::io::Result<()> {
+    std::fs::File::options().write(true).open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+}
+
+fn generate_upload_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+fn is_valid_upload_id(upload_id: &str) -> bool {
+    upload_id.len() == 32 && upload_id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Upload-Metadata is a comma separated list of `key base64(value)` pairs.
+fn metadata_value(metadata: &str, keys: &[&str]) -> Option<String> {
+    for pair in metadata.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        let key = parts.next()?;
+        if keys.contains(&key) {
+            let value = base64::engine::general_purpose::STANDARD.decode(parts.next()?.trim()).ok()?;
+            return String::from_utf8(value).ok();
+        }
+    }
+    None
+}
+
+fn file_name_from_metadata(metadata: &str) -> Option<String> {
+    metadata_value(metadata, &["filename", "name"])
+}
+
+async fn read_upload_info(staging: &Path, upload_id: &str) -> Option<UploadInfo> {
+    let contents = tokio::fs::read(staging.join(format!("{}.json", upload_id))).await.ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+async fn current_offset(staging: &Path, upload_id: &str) -> Option<u64> {
+    tokio::fs::metadata(staging.join(upload_id)).await.ok().map(|metadata| metadata.len())
+}
+
+// An upload expires `UPLOAD_EXPIRY` after the last chunk was written to it.
+async fn upload_expires(staging: &Path, upload_id: &str) -> Option<SystemTime> {
+    let modified = tokio::fs::metadata(staging.join(upload_id)).await.ok()?.modified().ok()?;
+    Some(modified + UPLOAD_EXPIRY)
+}
+
+// Removes abandoned tus uploads, along with staging files a batch upload or
+// a copy left behind when it was cut off. The info file of a tus upload
+// expires with its data, which is what chunks are written to.
+pub async fn remove_expired_uploads(directory: &str) {
+    let mut users = match tokio::fs::read_dir(format!("{}/.uploads", directory)).await {
+        Ok(users) => users,
+        Err(_) => return,
+    };
+    while let Ok(Some(user)) = users.next_entry().await {
+        let mut files = match tokio::fs::read_dir(user.path()).await {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        while let Ok(Some(file)) = files.next_entry().await {
+            let path = file.path();
+            let data_path = match path.extension().is_some_and(|extension| extension == "json") {
+                true => path.with_extension(""),
+                false => path.clone(),
+            };
+            let metadata = match tokio::fs::metadata(&data_path).await {
+                Ok(metadata) => metadata,
+                Err(_) => match file.metadata().await {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                },
+            };
+            let expired = metadata.modified().ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|age| age > UPLOAD_EXPIRY);
+            if expired && metadata.is_file() {
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+        }
+    }
+}
+
+// Whether a file of `length` bytes fits in the quota, in place of a file of
+// `replaced_size` bytes if there is one.
+fn fits(quota: &UserQuota, length: u64, replaced_size: Option<u64>) -> bool {
+    match replaced_size {
+        Some(replaced_size) => quota.allows(length.saturating_sub(quota.freed_by_replacing(replaced_size)), 0),
+        None => quota.allows(length, 1),
+    }
+}
+
+// The conflict policy and the quota are checked again, the target may have
+// been created or the space used up since the upload started.
+async fn finish_upload(staging: &Path, upload_id: &str, info: &UploadInfo, keys: &UserKeys, quota: &UserQuota, storage: &StorageState) -> Result<(), Status> {
+    let staged = staging.join(upload_id);
+    let policy = info.conflict.unwrap_or(ConflictPolicy::Overwrite);
+    let finished = match conflicts::resolve(storage, &quota.username, Path::new(&info.file_name), policy, None, info.modified).await {
+        Ok((_, Resolution::Skipped)) => Ok(()),
+        Ok((path, _)) => match store_file(&quota.username, &path, &staged, info.modified, keys, staging, storage, quota).await {
+            status if status == Status::Ok => Ok(()),
+            status => Err(status),
+        },
+        Err(status) => Err(status),
+    };
+
+    // A finished upload can't be resumed, so unless storing it failed on our
+    // side it is gone either way.
+    if finished != Err(Status::ExpectationFailed) {
+        let _ = tokio::fs::remove_file(&staged).await;
+        let _ = tokio::fs::remove_file(staging.join(format!("{}.json", upload_id))).await;
+    }
+    finished
+}
+
+#[options("/upload")]
+pub fn options_upload() -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", "creation,termination,expiration")
+        .header("Tus-Max-Size", MAX_UPLOAD_SIZE.as_u64())
+}
+
+// The conflict policy can be given with `?conflict=` or as a "conflict"
+// metadata value, and a "modified" value in seconds is used for `if-newer`.
+#[post("/upload?<conflict>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_upload(session: AuthenticatedSession, audit: Audit, tus: TusHeaders, conflict: Option<&str>, app_config: &State<MyAppConfig>,
+    storage: &State<StorageState>, quota: UserQuota, keys: UserKeys) -> Result<TusResponse, Status> {
+    if matches!(keys, UserKeys::Locked) {
+        return Err(Status::Locked);
+    }
+
+    let length = match tus.upload_length {
+        Some(length) if length <= MAX_UPLOAD_SIZE.as_u64() => length,
+        Some(_) => return Err(Status::PayloadTooLarge),
+        None => return Err(Status::BadRequest),
+    };
+
+    let metadata = tus.upload_metadata.unwrap_or_default();
+    let file_name = match file_name_from_metadata(&metadata) {
+        Some(name) => name,
+        None => return Err(Status::BadRequest),
+    };
+    audit.record(&session.username, "start_upload", &file_name);
+
+    let conflict = conflict.map(|conflict| conflict.to_string()).or_else(|| metadata_value(&metadata, &["conflict"]));
+    let policy = ConflictPolicy::parse(conflict.as_deref(), ConflictPolicy::Overwrite)?.for_scope(session.token_scope);
+    let modified = metadata_value(&metadata, &["modified"]).and_then(|modified| modified.trim().parse().ok());
+
+    // The name can be a relative path, like for `POST /file`.
+    let file_name = match filenames::check_relative_path(&file_name) {
+        Some(path) if !storage::is_trash(&path) => path.to_string_lossy().to_string(),
+        _ => return Err(Status::BadRequest),
+    };
+
+    // Checked here so a conflict or a full quota is reported before any
+    // data is sent, and again when the upload finishes.
+    let (path, resolution) = conflicts::resolve(storage, &session.username, Path::new(&file_name), policy, None, modified).await?;
+    if resolution != Resolution::Skipped {
+        let replaced_size = match storage.metadata(&session.username, &path).await {
+            Ok(entry) if entry.is_dir => return Err(Status::Conflict),
+            Ok(entry) => Some(entry.size),
+            Err(_) => None,
+        };
+        if !fits(&quota, length, replaced_size) {
+            return Err(Status::InsufficientStorage);
+        }
+    }
+
+    let staging = staging_directory(&app_config.directory, &session.username);
+    if tokio::fs::create_dir_all(&staging).await.is_err() {
+        return Err(Status::ExpectationFailed);
+    }
+
+    let upload_id = generate_upload_id();
+    let info = UploadInfo { file_name, length, conflict: Some(policy), modified };
+    let info_json = serde_json::to_vec(&info).map_err(|_| Status::InternalServerError)?;
+    if tokio::fs::write(staging.join(format!("{}.json", upload_id)), info_json).await.is_err()
+        || tokio::fs::File::create(staging.join(&upload_id)).await.is_err() {
+        return Err(Status::ExpectationFailed);
+    }
+
+    let response = TusResponse::new(Status::Created)
+        .header("Location", format!("/upload/{}", upload_id))
+        .header("Upload-Offset", 0);
+    if length == 0 {
+        finish_upload(&staging, &upload_id, &info, &keys, &quota, storage).await?;
+        audit.record(&session.username, "upload", &info.file_name);
+        return Ok(response);
+    }
+    Ok(response.header("Upload-Expires", format_http_date(SystemTime::now() + UPLOAD_EXPIRY)))
+}
+
+#[head("/upload/<upload_id>")]
+pub async fn upload_offset(session: AuthenticatedSession, upload_id: String, _tus: TusHeaders, app_config: &State<MyAppConfig>) -> Result<TusResponse, Status> {
+    if !is_valid_upload_id(&upload_id) {
+        return Err(Status::NotFound);
+    }
+
+    let staging = staging_directory(&app_config.directory, &session.username);
+    let info = read_upload_info(&staging, &upload_id).await.ok_or(Status::NotFound)?;
+    let offset = current_offset(&staging, &upload_id).await.ok_or(Status::NotFound)?;
+    let expires = upload_expires(&staging, &upload_id).await.ok_or(Status::NotFound)?;
+    if expires <= SystemTime::now() {
+        return Err(Status::Gone);
+    }
+
+    Ok(TusResponse::new(Status::Ok)
+        .header("Upload-Offset", offset)
+        .header("Upload-Length", info.length)
+        .header("Upload-Expires", format_http_date(expires))
+        .header("Cache-Control", "no-store"))
+}
+
+#[patch("/upload/<upload_id>", data = "<chunk>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_chunk(session: AuthenticatedSession, audit: Audit, upload_id: String, tus: TusHeaders, chunk: Data<'_>, app_config: &State<MyAppConfig>,
+    storage: &State<StorageState>, quota: UserQuota, keys: UserKeys) -> Result<TusResponse, Status> {
+    if tus.content_type.as_deref() != Some("application/offset+octet-stream") {
+        return Err(Status::UnsupportedMediaType);
+    }
+
+    // The upload is encrypted when it finishes, so the keys have to be
+    // unlocked for every chunk.
+    if matches!(keys, UserKeys::Locked) {
+        return Err(Status::Locked);
+    }
+
+    if !is_valid_upload_id(&upload_id) {
+        return Err(Status::NotFound);
+    }
+
+    let staging = staging_directory(&app_config.directory, &session.username);
+    let info = read_upload_info(&staging, &upload_id).await.ok_or(Status::NotFound)?;
+    let offset = current_offset(&staging, &upload_id).await.ok_or(Status::NotFound)?;
+
+    // Until the sweeper gets to it, an expired upload is kept but can't be resumed.
+    if upload_expires(&staging, &upload_id).await.is_some_and(|expires| expires <= SystemTime::now()) {
+        return Err(Status::Gone);
+    }
+
+    // The client has to agree with us on where the upload stands.
+    if tus.upload_offset != Some(offset) {
+        return Err(Status::Conflict);
+    }
+
+    let mut file = match OpenOptions::new().append(true).open(staging.join(&upload_id)).await {
+        Ok(file) => file,
+        Err(_) => return Err(Status::NotFound),
+    };
+
+    // Whatever made it to disk before a dropped connection is kept so the
+    // client can resume from the new offset.
+    let remaining = ByteUnit::Byte(info.length - offset);
+    let _ = chunk.open(remaining).stream_to(&mut file).await;
+    let _ = file.flush().await;
+    drop(file);
+
+    let offset = current_offset(&staging, &upload_id).await.ok_or(Status::NotFound)?;
+    // Recorded once the file is stored, finishing can still fail on the
+    // quota, the conflict policy or encryption.
+    if offset == info.length {
+        finish_upload(&staging, &upload_id, &info, &keys, &quota, storage).await?;
+        audit.record(&session.username, "upload", &info.file_name);
+        return Ok(TusResponse::new(Status::NoContent).header("Upload-Offset", offset));
+    }
+
+    Ok(TusResponse::new(Status::NoContent)
+        .header("Upload-Offset", offset)
+        .header("Upload-Expires", format_http_date(SystemTime::now() + UPLOAD_EXPIRY)))
+}
+
+#[delete("/upload/<upload_id>")]
+pub async fn terminate_upload(session: AuthenticatedSession, audit: Audit, upload_id: String, _tus: TusHeaders, app_config: &State<MyAppConfig>) -> Result<TusResponse, Status> {
+    audit.record(&session.username, "cancel_upload", &upload_id);
+
+    if !is_valid_upload_id(&upload_id) {
+        return Err(Status::NotFound);
+    }
+
+    let staging = staging_directory(&app_config.directory, &session.username);
+    if read_upload_info(&staging, &upload_id).await.is_none() {
+        return Err(Status::NotFound);
+    }
+
+    let _ = tokio::fs::remove_file(staging.join(&upload_id)).await;
+    let _ = tokio::fs::remove_file(staging.join(format!("{}.json", upload_id))).await;
+
+    Ok(TusResponse::new(Status::NoContent))
+}
+
+// Moves a staged upload into place, encrypting it first if the user has
+// encryption on. The quota is checked per file, so a batch that runs out of
+// space keeps the files that fit.
+#[allow(clippy::too_many_arguments)]
+async fn store_file(username: &str, path: &Path, source: &Path, modified: Option<u64>, keys: &UserKeys, staging: &Path,
+    storage: &StorageState, quota: &UserQuota) -> Status {
+    let replaced_size = match storage.metadata(username, path).await {
+        Ok(entry) if entry.is_dir => return Status::Conflict,
+        Ok(entry) => Some(entry.size),
+        Err(_) => None,
+    };
+
+    let (staged, written) = match keys {
+        UserKeys::Unlocked(keys) => {
+            let staged = staging.join(generate_upload_id());
+            let encrypted = match tokio::fs::File::create(&staged).await {
+                Ok(file) => encryption::encrypt_file(source, keys, file).await,
+                Err(error) => Err(error),
+            };
+            match encrypted {
+                Ok(written) => (staged, written),
+                Err(_) => {
+                    let _ = tokio::fs::remove_file(&staged).await;
+                    return Status::ExpectationFailed;
+                },
+            }
+        },
+        _ => match tokio::fs::metadata(source).await {
+            Ok(metadata) => (source.to_path_buf(), metadata.len()),
+            Err(_) => return Status::ExpectationFailed,
+        },
+    };
+
+    if let Some(modified) = modified {
+        let _ = set_modified(&staged, modified);
+    }
+
+    // Overwriting a file frees its old size, unless it is kept as a version.
+    let status = match fits(quota, written, replaced_size) {
+        true => match storage.store(username, path, &staged).await {
+            Ok(_) => Status::Ok,
+            Err(error) if error.kind() == std::io::ErrorKind::StorageFull => Status::InsufficientStorage,
+            Err(_) => Status::ExpectationFailed,
+        },
+        false => Status::InsufficientStorage,
+    };
+    if status != Status::Ok {
+        if staged != source {
+            let _ = tokio::fs::remove_file(&staged).await;
+        }
+        return status;
+    }
+
+    match replaced_size {
+        Some(replaced_size) => quota.record(written as i64 - replaced_size as i64, 0),
+        None => quota.record(written as i64, 1),
+    }
+    Status::Ok
+}
+
+// Several files in one multipart/form-data request, each sent as a "file"
+// part. The part's file name may be a relative path, as browsers send for
+// folder uploads, and is created below `dir`. Optional "modified" parts give
+// the files' modification times, in the same order. Answers with the
+// outcome of every file.
+#[post("/files?<dir>&<conflict>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_batch(session: AuthenticatedSession, audit: Audit, dir: Option<String>, conflict: Option<&str>,
+    content_type: &ContentType, content_length: ContentLength, data: Data<'_>,
+    app_config: &State<MyAppConfig>, storage: &State<StorageState>, quota: UserQuota, keys: UserKeys) -> Result<Json<Vec<BatchResult>>, Status> {
+    audit.record(&session.username, "upload_batch", dir.as_deref().unwrap_or(""));
+
+    if matches!(keys, UserKeys::Locked) {
+        return Err(Status::Locked);
+    }
+
+    let directory = upload_directory(dir.as_deref()).ok_or(Status::Forbidden)?;
+
+    // Parts are written to the staging directory, so storing them is a rename.
+    let staging = staging_directory(&app_config.directory, &session.username);
+    if tokio::fs::create_dir_all(&staging).await.is_err() {
+        return Err(Status::ExpectationFailed);
+    }
+
+    let quota_limit = quota.available_bytes().filter(|available| *available < MAX_UPLOAD_SIZE.as_u64());
+    let mut options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::file("file").size_limit(MAX_UPLOAD_SIZE.as_u64()).repetition(Repetition::infinite()),
+        MultipartFormDataField::text("modified").repetition(Repetition::infinite()),
+    ]);
+    options.temporary_dir = staging.clone();
+    options.max_data_bytes = quota_limit.unwrap_or(MAX_UPLOAD_SIZE.as_u64());
+
+    // The temporary files left in `form` are removed when it is dropped.
+    let form = match MultipartFormData::parse(content_type, data, options).await {
+        Ok(form) => form,
+        Err(MultipartFormDataError::NotFormDataError | MultipartFormDataError::BoundaryNotFoundError) => return Err(Status::UnsupportedMediaType),
+        Err(MultipartFormDataError::DataTooLargeError(_)) => return Err(Status::PayloadTooLarge),
+        // Past `max_data_bytes` the body is cut off, which shows as a part
+        // that never ends, the same as a body that really is incomplete.
+        Err(MultipartFormDataError::MulterError(multer::Error::IncompleteStream | multer::Error::IncompleteFieldData { .. }
+            | multer::Error::IncompleteHeaders))
+            if quota_limit.is_some_and(|limit| content_length.0.is_none_or(|length| length > limit)) => return Err(Status::InsufficientStorage),
+        Err(_) => return Err(Status::BadRequest),
+    };
+
+    let policy = ConflictPolicy::parse(conflict, ConflictPolicy::Overwrite)?.for_scope(session.token_scope);
+    let modified_times: Vec<Option<u64>> = form.texts.get("modified")
+        .map(|fields| fields.iter().map(|field| field.text.trim().parse().ok()).collect())
+        .unwrap_or_default();
+
+    let mut results = Vec::new();
+    let files = form.files.get("file").map(|files| files.as_slice()).unwrap_or_default();
+    for (index, file) in files.iter().enumerate() {
+        let file_name = file.file_name.clone().unwrap_or_default();
+        let modified = modified_times.get(index).copied().flatten();
+        let resolved = match filenames::check_relative_path(&file_name).and_then(|name| storage::relative_path(&directory.join(name))) {
+            Some(path) if !storage::is_trash(&path) => conflicts::resolve(storage, &session.username, &path, policy, None, modified).await
+                .map_err(|status| (path.to_string_lossy().to_string(), status)),
+            _ => Err((file_name, Status::BadRequest)),
+        };
+
+        let result = match resolved {
+            Ok((path, Resolution::Skipped)) => BatchResult { path: path.to_string_lossy().to_string(), status: Status::Ok.code, conflict: Some(Resolution::Skipped) },
+            Ok((path, resolution)) => {
+                let status = store_file(&session.username, &path, &file.path, modified, &keys, &staging, storage, &quota).await;
+                BatchResult { path: path.to_string_lossy().to_string(), status: status.code, conflict: Some(resolution) }
+            },
+            Err((path, status)) => BatchResult { path, status: status.code, conflict: None },
+        };
+        results.push(result);
+    }
+
+    if results.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    let stored: Vec<&str> = results.iter()
+        .filter(|result| result.status == Status::Ok.code && result.conflict != Some(Resolution::Skipped))
+        .map(|result| result.path.as_str())
+        .collect();
+    audit.record_with_target(&session.username, "upload_batch", dir.as_deref().unwrap_or(""), &stored.join(", "));
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(value)
+    }
+
+    #[test]
+    fn reads_the_file_name_from_metadata() {
+        let metadata = format!("filetype {},filename {}", encode("text/plain"), encode("notes/réunion 1.txt"));
+        assert_eq!(file_name_from_metadata(&metadata).as_deref(), Some("notes/réunion 1.txt"));
+        assert_eq!(file_name_from_metadata(&format!("name {}", encode("a.txt"))).as_deref(), Some("a.txt"));
+        assert_eq!(file_name_from_metadata(&format!(" filename  {} ", encode("b.txt"))).as_deref(), Some("b.txt"));
+    }
+
+    #[test]
+    fn reads_other_metadata_values() {
+        let metadata = format!("filename {}, conflict {},modified {},is_confidential", encode("a.txt"), encode("keep-both"), encode("1700000000"));
+        assert_eq!(metadata_value(&metadata, &["conflict"]).as_deref(), Some("keep-both"));
+        assert_eq!(metadata_value(&metadata, &["modified"]).as_deref(), Some("1700000000"));
+        assert_eq!(metadata_value(&metadata, &["filetype"]), None);
+    }
+
+    #[test]
+    fn refuses_broken_metadata() {
+        assert_eq!(file_name_from_metadata(""), None);
+        assert_eq!(file_name_from_metadata("filename"), None);
+        assert_eq!(file_name_from_metadata("filename not-base64!"), None);
+        assert_eq!(file_name_from_metadata(&format!("filename {}", base64::engine::general_purpose::STANDARD.encode([0xff, 0xfe]))), None);
+    }
+
+    #[tokio::test]
+    async fn removes_expired_uploads() {
+        let directory = std::env::temp_dir().join(format!("upload-test-{}", std::process::id()));
+        let staging = staging_directory(directory.to_str().unwrap(), "bob");
+        std::fs::create_dir_all(&staging).unwrap();
+        let expired = SystemTime::now() - UPLOAD_EXPIRY - Duration::from_secs(60);
+        let write = |name: &str, modified: SystemTime| {
+            std::fs::write(staging.join(name), b"data").unwrap();
+            std::fs::File::options().write(true).open(staging.join(name)).unwrap().set_modified(modified).unwrap();
+        };
+
+        // An old upload still being written to, an abandoned one, a recent
+        // batch staging file and one left behind long ago.
+        write("active.json", expired);
+        write("active", SystemTime::now());
+        write("abandoned.json", expired);
+        write("abandoned", expired);
+        write("batch", SystemTime::now());
+        write("leftover", expired);
+        write("orphan.json", expired);
+
+        remove_expired_uploads(directory.to_str().unwrap()).await;
+        let mut left: Vec<String> = std::fs::read_dir(&staging).unwrap().flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["active", "active.json", "batch"]);
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn checks_upload_ids() {
+        assert!(is_valid_upload_id(&generate_upload_id()));
+        assert!(!is_valid_upload_id("../../etc/passwd"));
+        assert!(!is_valid_upload_id(&"g".repeat(32)));
+        assert!(!is_valid_upload_id("abcd"));
+    }
+}
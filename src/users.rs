@@ -0,0 +1,404 @@
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, put, Request, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::encryption::{self, KeyringState};
+use crate::session::SessionStoreState;
+use crate::{unix_timestamp, AuthenticatedSession};
+
+#[derive(Serialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub is_admin: bool,
+    pub disabled: bool,
+    pub two_factor: bool,
+    pub created_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangedPassword {
+    pub password: String,
+    pub new_password: String,
+}
+
+// Usernames double as directory names under the storage root, so keep them
+// to a conservative character set and never let them start with a dot.
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= 64
+        && !username.starts_with('.')
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+pub fn hash_password(password: &str) -> Option<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).ok()?;
+    Argon2::default().hash_password(password.as_bytes(), &salt).ok().map(|hash| hash.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Returns the stored hash only for accounts that are allowed to log in.
+pub fn find_password_hash(connection: &Connection, username: &str) -> Option<String> {
+    connection.query_row(
+        "SELECT password FROM users WHERE username = ?1 AND disabled = 0",
+        params![username],
+        |row| row.get(0))
+        .optional()
+        .ok()?
+}
+
+pub fn is_admin(connection: &Connection, username: &str) -> bool {
+    connection.query_row(
+        "SELECT is_admin FROM users WHERE username = ?1 AND disabled = 0",
+        params![username],
+        |row| row.get::<_, bool>(0))
+        .unwrap_or(false)
+}
+
+pub fn list_users(connection: &Connection) -> rusqlite::Result<Vec<UserRecord>> {
+    let mut statement = connection.prepare("SELECT username, is_admin, disabled, totp_enabled, created_at FROM users ORDER BY username")?;
+    let users = statement.query_map([], |row| Ok(UserRecord {
+        username: row.get(0)?,
+        is_admin: row.get(1)?,
+        disabled: row.get(2)?,
+        two_factor: row.get(3)?,
+        created_at: row.get::<_, i64>(4)? as u64,
+    }))?;
+    users.collect()
+}
+
+pub fn create_user(connection: &Connection, username: &str, password_hash: &str, is_admin: bool) -> rusqlite::Result<bool> {
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO users (username, password, is_admin, disabled, created_at) VALUES (?1, ?2, ?3, 0, ?4)",
+        params![username, password_hash, is_admin, unix_timestamp() as i64])?;
+    Ok(inserted == 1)
+}
+
+pub fn set_password(connection: &Connection, username: &str, password_hash: &str) -> rusqlite::Result<bool> {
+    let updated = connection.execute("UPDATE users SET password = ?2 WHERE username = ?1", params![username, password_hash])?;
+    Ok(updated == 1)
+}
+
+pub fn set_disabled(connection: &Connection, username: &str, disabled: bool) -> rusqlite::Result<bool> {
+    let updated = connection.execute("UPDATE users SET disabled = ?2 WHERE username = ?1", params![username, disabled])?;
+    Ok(updated == 1)
+}
+
+// Tokens, shares and encryption keys go with the account, so a new one with
+// the same name doesn't inherit them. Two-factor auth lives in the users row.
+pub fn delete_user(connection: &Connection, username: &str) -> rusqlite::Result<bool> {
+    let transaction = connection.unchecked_transaction()?;
+    transaction.execute("DELETE FROM api_tokens WHERE username = ?1", params![username])?;
+    transaction.execute("DELETE FROM shares WHERE username = ?1", params![username])?;
+    encryption::discard_keys(&transaction, username)?;
+    let deleted = transaction.execute("DELETE FROM users WHERE username = ?1", params![username])?;
+    transaction.commit()?;
+    Ok(deleted == 1)
+}
+
+// One-off import of the old pipe-delimited `username|argon2 hash` file. A
+// third `admin` column makes the user an admin, so a fresh install can start
+// with one. The CSV is renamed afterwards so the database stays the only
+// source of truth.
+pub fn import_users_csv(connection: &Connection, path: &Path) -> std::io::Result<usize> {
+    let file = std::fs::File::open(path)?;
+    let mut imported = 0;
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let parts: Vec<&str> = line.split('|').collect();
+
+        let is_admin = match parts.get(2).map(|column| column.trim()) {
+            None | Some("") => false,
+            Some("admin") => true,
+            Some(_) => continue,
+        };
+        if (parts.len() == 2 || parts.len() == 3) && is_valid_username(parts[0]) && PasswordHash::new(parts[1]).is_ok() {
+            if let Ok(true) = create_user(connection, parts[0], parts[1], is_admin) {
+                imported += 1;
+            }
+        }
+    }
+
+    std::fs::rename(path, path.with_extension("csv.imported"))?;
+    Ok(imported)
+}
+
+pub struct AdminSession {
+    pub username: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminSession {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let session = match request.guard::<AuthenticatedSession>().await {
+            request::Outcome::Success(session) => session,
+            request::Outcome::Failure(failure) => return request::Outcome::Failure(failure),
+            request::Outcome::Forward(forward) => return request::Outcome::Forward(forward),
+        };
+
+        let database = request.rocket().state::<DatabaseState>().unwrap();
+        match database.lock() {
+            Ok(connection) if is_admin(&connection, &session.username) => {
+                request::Outcome::Success(AdminSession { username: session.username })
+            },
+            Ok(_) => request::Outcome::Failure((Status::Forbidden, ())),
+            Err(_) => request::Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+fn end_sessions(session_store_state: &SessionStoreState, keyring: &KeyringState, username: &str) {
+    if let Ok(mut session_store) = session_store_state.write() {
+        session_store.remove_user(username);
+    }
+    if let Ok(mut keyring) = keyring.lock() {
+        keyring.remove_user(username);
+    }
+}
+
+#[get("/admin/users")]
+pub async fn get_users(_admin: AdminSession, database: &State<DatabaseState>) -> Result<Json<Vec<UserRecord>>, Status> {
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    list_users(&connection).map(Json).map_err(|_| Status::InternalServerError)
+}
+
+#[post("/admin/users", data = "<new_user>")]
+pub async fn post_user(admin: AdminSession, audit: Audit, new_user: Json<NewUser>, database: &State<DatabaseState>) -> Status {
+    audit.record_with_target(&admin.username, "create_user", "", &new_user.username);
+
+    if !is_valid_username(&new_user.username) || new_user.password.is_empty() {
+        return Status::BadRequest;
+    }
+
+    let password_hash = match hash_password(&new_user.password) {
+        Some(hash) => hash,
+        None => return Status::InternalServerError,
+    };
+
+    match database.lock() {
+        Ok(connection) => match create_user(&connection, &new_user.username, &password_hash, new_user.is_admin) {
+            Ok(true) => Status::Created,
+            Ok(false) => Status::Conflict,
+            Err(_) => Status::InternalServerError,
+        },
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+// The encryption keys of the user are wrapped with their password. They can
+// only be wrapped again if the user is logged in somewhere with them
+// unlocked; otherwise the reset is refused unless `discard_keys` is set,
+// which leaves their encrypted files unreadable.
+#[put("/admin/users/<username>/password?<discard_keys>", data = "<new_password>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn reset_password(admin: AdminSession, audit: Audit, username: String, discard_keys: Option<bool>, new_password: Json<NewPassword>,
+    database: &State<DatabaseState>, session_store_state: &State<SessionStoreState>, keyring: &State<KeyringState>) -> Status {
+    audit.record_with_target(&admin.username, "reset_password", "", &username);
+
+    if new_password.password.is_empty() {
+        return Status::BadRequest;
+    }
+
+    let password_hash = match hash_password(&new_password.password) {
+        Some(hash) => hash,
+        None => return Status::InternalServerError,
+    };
+
+    let unlocked_keys = match keyring.lock() {
+        Ok(keyring) => keyring.find_user(&username),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    // Wrapped before taking the database, it's as slow as hashing the password.
+    let rewrapped = match unlocked_keys {
+        Some(keys) => match encryption::rewrap(&keys, &username, &new_password.password) {
+            Some(wrapped) => Some(wrapped),
+            None => return Status::InternalServerError,
+        },
+        None => None,
+    };
+
+    let updated = match database.lock() {
+        Ok(connection) => {
+            if encryption::is_enabled(&connection, &username) {
+                let saved = match &rewrapped {
+                    Some(wrapped) => encryption::save_wrapped(&connection, &username, wrapped),
+                    None if discard_keys == Some(true) => encryption::discard_keys(&connection, &username).map(|_| true),
+                    None => return Status::Conflict,
+                };
+                match saved {
+                    Ok(true) => (),
+                    Ok(false) => return Status::Conflict,
+                    Err(_) => return Status::InternalServerError,
+                }
+            }
+            set_password(&connection, &username, &password_hash)
+        },
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match updated {
+        Ok(true) => {
+            end_sessions(session_store_state, keyring, &username);
+            Status::Ok
+        },
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+#[put("/password", data = "<changed_password>")]
+pub async fn change_password(session: AuthenticatedSession, audit: Audit, changed_password: Json<ChangedPassword>, database: &State<DatabaseState>) -> Status {
+    audit.record(&session.username, "change_password", "");
+
+    if changed_password.new_password.is_empty() {
+        return Status::BadRequest;
+    }
+
+    let password_hash = match hash_password(&changed_password.new_password) {
+        Some(hash) => hash,
+        None => return Status::InternalServerError,
+    };
+
+    let (current_hash, wrapped) = match database.lock() {
+        Ok(connection) => (find_password_hash(&connection, &session.username), encryption::load_wrapped(&connection, &session.username)),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    // Checking the password and wrapping the keys again are slow, so they
+    // happen without the database locked.
+    let current_hash = match current_hash {
+        Some(current_hash) if verify_password(&changed_password.password, &current_hash) => current_hash,
+        _ => return Status::Forbidden,
+    };
+    let rewrapped = match wrapped {
+        Some(wrapped) => match encryption::unlock(&wrapped, &session.username, &changed_password.password) {
+            Some(keys) => match encryption::rewrap(&keys, &session.username, &changed_password.new_password) {
+                Some(rewrapped) => Some(rewrapped),
+                None => return Status::InternalServerError,
+            },
+            None => return Status::Conflict,
+        },
+        None => None,
+    };
+
+    let connection = match database.lock() {
+        Ok(connection) => connection,
+        Err(_) => return Status::InternalServerError,
+    };
+
+    // Nothing is written if the password changed or encryption was turned on
+    // in the meantime, the keys would end up under the wrong password.
+    if find_password_hash(&connection, &session.username).as_ref() != Some(&current_hash) {
+        return Status::Conflict;
+    }
+    match &rewrapped {
+        Some(wrapped) => match encryption::save_wrapped(&connection, &session.username, wrapped) {
+            Ok(true) => (),
+            Ok(false) => return Status::Conflict,
+            Err(_) => return Status::InternalServerError,
+        },
+        None if encryption::is_enabled(&connection, &session.username) => return Status::Conflict,
+        None => (),
+    }
+
+    match set_password(&connection, &session.username, &password_hash) {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+#[put("/admin/users/<username>/disable")]
+pub async fn disable_user(admin: AdminSession, audit: Audit, username: String,
+    database: &State<DatabaseState>, session_store_state: &State<SessionStoreState>, keyring: &State<KeyringState>) -> Status {
+    audit.record_with_target(&admin.username, "disable_user", "", &username);
+
+    if admin.username == username {
+        return Status::Conflict;
+    }
+
+    let updated = match database.lock() {
+        Ok(connection) => set_disabled(&connection, &username, true),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match updated {
+        Ok(true) => {
+            end_sessions(session_store_state, keyring, &username);
+            Status::Ok
+        },
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+#[put("/admin/users/<username>/enable")]
+pub async fn enable_user(admin: AdminSession, audit: Audit, username: String, database: &State<DatabaseState>) -> Status {
+    audit.record_with_target(&admin.username, "enable_user", "", &username);
+
+    match database.lock() {
+        Ok(connection) => match set_disabled(&connection, &username, false) {
+            Ok(true) => Status::Ok,
+            Ok(false) => Status::NotFound,
+            Err(_) => Status::InternalServerError,
+        },
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+// Only the account is removed; the user's files stay on disk for an admin
+// to deal with.
+#[delete("/admin/users/<username>")]
+pub async fn remove_user(admin: AdminSession, audit: Audit, username: String,
+    database: &State<DatabaseState>, session_store_state: &State<SessionStoreState>, keyring: &State<KeyringState>) -> Status {
+    audit.record_with_target(&admin.username, "remove_user", "", &username);
+
+    if admin.username == username {
+        return Status::Conflict;
+    }
+
+    let deleted = match database.lock() {
+        Ok(connection) => delete_user(&connection, &username),
+        Err(_) => return Status::InternalServerError,
+    };
+
+    match deleted {
+        Ok(true) => {
+            end_sessions(session_store_state, keyring, &username);
+            Status::Ok
+        },
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
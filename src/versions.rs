@@ -0,0 +1,349 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, post, State};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use rand::prelude::*;
+
+use crate::audit::Audit;
+use crate::database::DatabaseState;
+use crate::encryption::{self, DecryptedFile, UserKeys};
+use crate::quota::{self, QuotaState, UserQuota};
+use crate::range::RangedFile;
+use crate::storage::{self, FileDownload};
+use crate::{unix_timestamp, AuthenticatedSession, MyAppConfig};
+
+// When a file is overwritten its previous contents are kept under
+// `<directory>/.versions/<user>/<id>`, next to the upload staging area and
+// outside the user's folder, so old versions don't show up in listings.
+// Their bytes count towards the user's quota until they are dropped. Only
+// the local backend keeps versions.
+#[derive(Serialize)]
+pub struct VersionRecord {
+    pub id: String,
+    pub path: String,
+    pub created_at: u64,
+    pub size: u64,
+}
+
+fn user_directory(app_config: &MyAppConfig, username: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}", app_config.directory, username))
+}
+
+fn version_directory(directory: &str, username: &str) -> PathBuf {
+    PathBuf::from(format!("{}/.versions/{}", directory, username))
+}
+
+fn generate_version_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 12]>())
+}
+
+fn relative_path(user_directory: &Path, path: &Path) -> String {
+    path.strip_prefix(user_directory).unwrap_or(path).to_string_lossy().to_string()
+}
+
+fn read_record(row: &rusqlite::Row) -> rusqlite::Result<VersionRecord> {
+    Ok(VersionRecord {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        created_at: row.get::<_, i64>(2)? as u64,
+        size: row.get::<_, i64>(3)? as u64,
+    })
+}
+
+pub fn list_versions(connection: &Connection, username: &str, path: &str) -> rusqlite::Result<Vec<VersionRecord>> {
+    let mut statement = connection.prepare(
+        "SELECT id, path, created_at, size FROM versions WHERE username = ?1 AND path = ?2 ORDER BY created_at DESC, rowid DESC")?;
+    let versions = statement.query_map(params![username, path], read_record)?;
+    versions.collect()
+}
+
+fn find_version(connection: &Connection, username: &str, version_id: &str) -> rusqlite::Result<Option<VersionRecord>> {
+    connection.query_row(
+        "SELECT id, path, created_at, size FROM versions WHERE username = ?1 AND id = ?2",
+        params![username, version_id],
+        read_record)
+        .optional()
+}
+
+fn insert_version(connection: &Connection, username: &str, version: &VersionRecord) -> rusqlite::Result<usize> {
+    connection.execute(
+        "INSERT INTO versions (id, username, path, created_at, size) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![version.id, username, version.path, version.created_at as i64, version.size as i64])
+}
+
+fn forget_version(connection: &Connection, username: &str, version_id: &str) -> rusqlite::Result<usize> {
+    connection.execute("DELETE FROM versions WHERE username = ?1 AND id = ?2", params![username, version_id])
+}
+
+// Keeps the history attached to a file or folder when it is renamed or moved.
+pub fn rename_path(connection: &Connection, username: &str, old_path: &str, new_path: &str) -> rusqlite::Result<usize> {
+    connection.execute(
+        "UPDATE versions SET path = ?3 || substr(path, length(?2) + 1)
+        WHERE username = ?1 AND (path = ?2 OR substr(path, 1, length(?2) + 1) = ?2 || '/')",
+        params![username, old_path, new_path])
+}
+
+pub fn track_rename(database: &DatabaseState, user_directory: &Path, username: &str, old_path: &Path, new_path: &Path) {
+    if let Ok(connection) = database.lock() {
+        let _ = rename_path(&connection, username, &relative_path(user_directory, old_path), &relative_path(user_directory, new_path));
+    }
+}
+
+// Saves the current contents of `path` as a version before it gets replaced.
+// The version is a hard link where possible, so the caller can then rename
+// the new contents over `path` without anything being copied. Fails with
+// `StorageFull` when the version doesn't fit in the user's quota.
+pub async fn keep_version(database: &DatabaseState, app_config: &MyAppConfig, tracker: &QuotaState, username: &str, path: &Path) -> std::io::Result<()> {
+    if app_config.max_versions == 0 {
+        return Ok(());
+    }
+
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(()),
+    };
+
+    let quota = match database.lock() {
+        Ok(connection) => UserQuota::tracked(&connection, app_config, tracker.clone(), username),
+        Err(_) => return Err(std::io::Error::other("database unavailable")),
+    };
+    if !quota.allows(metadata.len(), 0) {
+        return Err(std::io::ErrorKind::StorageFull.into());
+    }
+
+    let user_directory = user_directory(app_config, username);
+    let store = version_directory(&app_config.directory, username);
+    tokio::fs::create_dir_all(&store).await?;
+
+    let version = VersionRecord {
+        id: generate_version_id(),
+        path: relative_path(&user_directory, path),
+        created_at: unix_timestamp(),
+        size: metadata.len(),
+    };
+    let version_path = store.join(&version.id);
+    if tokio::fs::hard_link(path, &version_path).await.is_err() {
+        tokio::fs::copy(path, &version_path).await?;
+    }
+
+    let recorded = match database.lock() {
+        Ok(connection) => insert_version(&connection, username, &version).is_ok(),
+        Err(_) => false,
+    };
+    if !recorded {
+        let _ = tokio::fs::remove_file(&version_path).await;
+        return Err(std::io::Error::other("failed to record version"));
+    }
+    quota.record(version.size as i64, 0);
+
+    prune_versions(database, app_config, tracker, username, &version.path).await;
+    Ok(())
+}
+
+// Drops the versions of one path beyond `max_versions` or older than
+// `version_retention_days`.
+async fn prune_versions(database: &DatabaseState, app_config: &MyAppConfig, tracker: &QuotaState, username: &str, path: &str) {
+    let cutoff = match app_config.version_retention_days {
+        0 => 0,
+        days => unix_timestamp().saturating_sub(days * 24 * 60 * 60),
+    };
+
+    let expired: Vec<String> = match database.lock() {
+        Ok(connection) => list_versions(&connection, username, path)
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .filter(|(index, version)| *index >= app_config.max_versions || version.created_at < cutoff)
+            .map(|(_, version)| version.id)
+            .collect(),
+        Err(_) => return,
+    };
+
+    for version_id in expired {
+        remove_version(database, &app_config.directory, tracker, username, &version_id).await;
+    }
+}
+
+async fn remove_version(database: &DatabaseState, directory: &str, tracker: &QuotaState, username: &str, version_id: &str) {
+    let _ = tokio::fs::remove_file(version_directory(directory, username).join(version_id)).await;
+    let size = match database.lock() {
+        Ok(connection) => match find_version(&connection, username, version_id) {
+            Ok(Some(version)) if forget_version(&connection, username, version_id).is_ok() => version.size,
+            _ => return,
+        },
+        Err(_) => return,
+    };
+    quota::release(tracker, username, size);
+}
+
+// Drops the history of a file, or of everything in a folder, deleted for good.
+pub async fn remove_versions_below(database: &DatabaseState, directory: &str, tracker: &QuotaState, username: &str, path: &str) {
+    let version_ids: Vec<String> = match database.lock() {
+        Ok(connection) => {
+            let mut statement = match connection.prepare(
+                "SELECT id FROM versions WHERE username = ?1 AND (path = ?2 OR substr(path, 1, length(?2) + 1) = ?2 || '/')") {
+                Ok(statement) => statement,
+                Err(_) => return,
+            };
+            let rows = statement.query_map(params![username, path], |row| row.get(0));
+            match rows {
+                Ok(rows) => rows.flatten().collect(),
+                Err(_) => return,
+            }
+        },
+        Err(_) => return,
+    };
+
+    for version_id in version_ids {
+        remove_version(database, directory, tracker, username, &version_id).await;
+    }
+}
+
+pub async fn purge_expired(database: &DatabaseState, directory: &str, tracker: &QuotaState, retention_days: u64) {
+    let cutoff = unix_timestamp().saturating_sub(retention_days * 24 * 60 * 60);
+    let expired: Vec<(String, String)> = match database.lock() {
+        Ok(connection) => {
+            let mut statement = match connection.prepare("SELECT username, id FROM versions WHERE created_at < ?1") {
+                Ok(statement) => statement,
+                Err(_) => return,
+            };
+            let rows = statement.query_map(params![cutoff as i64], |row| Ok((row.get(0)?, row.get(1)?)));
+            match rows {
+                Ok(rows) => rows.flatten().collect(),
+                Err(_) => return,
+            }
+        },
+        Err(_) => return,
+    };
+
+    for (username, version_id) in expired {
+        remove_version(database, directory, tracker, &username, &version_id).await;
+    }
+}
+
+#[get("/versions/<file_path..>")]
+pub async fn get_versions(session: AuthenticatedSession, file_path: PathBuf, app_config: &State<MyAppConfig>,
+    database: &State<DatabaseState>) -> Result<Json<Vec<VersionRecord>>, Status> {
+    if !storage::is_local(app_config) {
+        return Err(Status::NotImplemented);
+    }
+    let path = storage::relative_path(&file_path).ok_or(Status::Forbidden)?;
+
+    let connection = database.lock().map_err(|_| Status::InternalServerError)?;
+    list_versions(&connection, &session.username, &path.to_string_lossy())
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/version/<version_id>")]
+pub async fn get_version(session: AuthenticatedSession, audit: Audit, version_id: String, keys: UserKeys, app_config: &State<MyAppConfig>,
+    database: &State<DatabaseState>) -> Result<FileDownload, Status> {
+    audit.record(&session.username, "download_version", &version_id);
+
+    if !storage::is_local(app_config) {
+        return Err(Status::NotImplemented);
+    }
+
+    let version = match database.lock() {
+        Ok(connection) => find_version(&connection, &session.username, &version_id)
+            .map_err(|_| Status::InternalServerError)?
+            .ok_or(Status::NotFound)?,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    let file_name = Path::new(&version.path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let version_path = version_directory(&app_config.directory, &session.username).join(&version.id);
+    if let Some(key_id) = encryption::file_key_id(&version_path).await {
+        let keys = match keys {
+            UserKeys::Unlocked(keys) if keys.contains(key_id) => keys,
+            UserKeys::Unlocked(_) => return Err(Status::Forbidden),
+            _ => return Err(Status::Locked),
+        };
+        let file = tokio::fs::File::open(&version_path).await.map_err(|_| Status::NotFound)?;
+        let size = file.metadata().await.map_err(|_| Status::NotFound)?.len();
+        return Ok(FileDownload::Decrypted(DecryptedFile::new(keys, Box::pin(file), size, &file_name)));
+    }
+
+    RangedFile::open(&version_path).await
+        .map(|file| FileDownload::Local(file.attachment(&file_name)))
+        .map_err(|_| Status::NotFound)
+}
+
+// The restored version becomes the current file and whatever was current
+// becomes the newest version.
+#[post("/version/<version_id>/restore")]
+pub async fn restore_version(session: AuthenticatedSession, audit: Audit, version_id: String, app_config: &State<MyAppConfig>,
+    database: &State<DatabaseState>, tracker: &State<QuotaState>, quota: UserQuota) -> Status {
+    audit.record(&session.username, "restore_version", &version_id);
+
+    if !storage::is_local(app_config) {
+        return Status::NotImplemented;
+    }
+
+    let version = match database.lock() {
+        Ok(connection) => match find_version(&connection, &session.username, &version_id) {
+            Ok(Some(version)) => version,
+            Ok(None) => return Status::NotFound,
+            Err(_) => return Status::InternalServerError,
+        },
+        Err(_) => return Status::InternalServerError,
+    };
+
+    let path = match storage::relative_path(Path::new(&version.path)) {
+        Some(path) if !storage::is_trash(&path) => user_directory(app_config, &session.username).join(path),
+        _ => return Status::Forbidden,
+    };
+
+    let current_size = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+        Ok(_) => return Status::Conflict,
+        Err(_) => None,
+    };
+    if current_size.is_none() && !quota.allows(0, 1) {
+        return Status::InsufficientStorage;
+    }
+
+    // Forget the version first so keeping the current file can't prune it.
+    // Its bytes come back as the current file's.
+    match database.lock() {
+        Ok(connection) => {
+            if forget_version(&connection, &session.username, &version.id).is_err() {
+                return Status::InternalServerError;
+            }
+        },
+        Err(_) => return Status::InternalServerError,
+    }
+    quota.record(-(version.size as i64), 0);
+
+    let version_path = version_directory(&app_config.directory, &session.username).join(&version.id);
+    let restored = async {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        keep_version(database, app_config, tracker, &session.username, &path).await?;
+        tokio::fs::rename(&version_path, &path).await
+    };
+
+    // Keeping the current file charged its bytes again, as a version.
+    match restored.await {
+        Ok(_) => {
+            match current_size {
+                Some(current_size) => quota.record(version.size as i64 - current_size as i64, 0),
+                None => quota.record(version.size as i64, 1),
+            }
+            Status::Ok
+        },
+        Err(error) => {
+            if let Ok(connection) = database.lock() {
+                let _ = insert_version(&connection, &session.username, &version);
+            }
+            quota.record(version.size as i64, 0);
+            match error.kind() {
+                std::io::ErrorKind::StorageFull => Status::InsufficientStorage,
+                _ => Status::InternalServerError,
+            }
+        },
+    }
+}
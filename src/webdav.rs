@@ -0,0 +1,971 @@
+use base64::Engine;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use rocket::http::ContentType;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use rand::prelude::*;
+
+use crate::database::DatabaseState;
+use crate::encryption;
+use crate::filenames;
+use crate::logging;
+use crate::quota::{self, QuotaState, UserQuota};
+use crate::range::{entity_tag, format_http_date, Conditions, RangedFile};
+use crate::storage::{self, StorageState};
+use crate::{lockout, resolve_user_path, totp, unix_timestamp, upload, users, MyAppConfig};
+use crate::audit::{AuditEvent, AuditState};
+use crate::ratelimit::RateLimitState;
+
+// WebDAV (RFC 4918, class 1 and 2) for mounting the drive in file managers,
+// davfs2 or rclone. Rocket only routes the standard HTTP methods, so this runs
+// as its own hyper listener on `webdav_port`, sharing the database, storage,
+// quota tracker and login rate limiter with the main server. Reads go to the
+// user's directory directly, so it only runs with the local backend.
+const DAV_PREFIX: &str = "/dav";
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+const MAX_XML_BODY: usize = 64 * 1024;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Argon2 is deliberately slow and clients send credentials with every request,
+// so a successful login is remembered for a few minutes. The account is still
+// looked up on every request, so disabling it, removing it, changing its
+// password or turning on two-factor auth takes effect at once.
+const CREDENTIAL_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const MAX_LOCK_TIMEOUT: u64 = 60 * 60;
+
+struct DavLock {
+    token: String,
+    username: String,
+    path: PathBuf,
+    exclusive: bool,
+    infinite: bool,
+    owner: String,
+    timeout: u64,
+    expires: Instant,
+}
+
+pub struct DavServer {
+    app_config: MyAppConfig,
+    database: DatabaseState,
+    storage: StorageState,
+    quota: QuotaState,
+    rate_limits: RateLimitState,
+    audit_log: AuditState,
+    credentials: Mutex<HashMap<String, CachedLogin>>,
+    locks: Mutex<HashMap<String, DavLock>>,
+}
+
+struct CachedLogin {
+    username: String,
+    password_hash: String,
+    verified_at: Instant,
+}
+
+type DavResult = Result<Response<Body>, StatusCode>;
+
+impl DavServer {
+    pub fn new(app_config: MyAppConfig, database: DatabaseState, storage: StorageState, quota: QuotaState, rate_limits: RateLimitState,
+        audit_log: AuditState) -> DavServer {
+        DavServer {
+            app_config,
+            database,
+            storage,
+            quota,
+            rate_limits,
+            audit_log,
+            credentials: Mutex::new(HashMap::new()),
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn user_directory(&self, username: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{}", self.app_config.directory, username))
+    }
+
+    async fn user_quota(&self, username: &str) -> Result<UserQuota, StatusCode> {
+        UserQuota::load(&self.database, &self.app_config, self.quota.clone(), self.storage.as_ref(), username).await
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn authenticate(&self, client_ip: IpAddr, authorization: Option<&HeaderValue>) -> Result<String, StatusCode> {
+        let authorization = authorization.and_then(|value| value.to_str().ok()).ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let cached = match self.credentials.lock() {
+            Ok(mut credentials) => {
+                credentials.retain(|_, login| login.verified_at.elapsed() < CREDENTIAL_CACHE_TTL);
+                credentials.get(authorization).map(|login| (login.username.clone(), login.password_hash.clone()))
+            },
+            Err(_) => None,
+        };
+        if let Some((username, password_hash)) = cached {
+            let still_valid = match self.database.lock() {
+                Ok(connection) => users::find_password_hash(&connection, &username) == Some(password_hash)
+                    && !totp::is_enabled(&connection, &username),
+                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+            if still_valid {
+                return Ok(username);
+            }
+            if let Ok(mut credentials) = self.credentials.lock() {
+                credentials.remove(authorization);
+            }
+        }
+
+        // Only fresh password checks count against the limiter.
+        if !self.rate_limits.allow_login(client_ip) {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let decoded = authorization.strip_prefix("Basic ")
+            .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let (username, password) = decoded.split_once(':').ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let (password_hash, two_factor) = match self.database.lock() {
+            Ok(connection) => {
+                if lockout::check(&connection, username, Some(client_ip)).is_some() {
+                    lockout::record_failure(&connection, username, Some(client_ip), "locked");
+                    return Err(StatusCode::TOO_MANY_REQUESTS);
+                }
+                (users::find_password_hash(&connection, username), totp::is_enabled(&connection, username))
+            },
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        // Basic auth has no room for a second factor. Accounts with one are
+        // refused exactly like a wrong password, so the answer doesn't tell
+        // whether the password was right.
+        let password_hash = password_hash.filter(|password_hash| users::verify_password(password, password_hash) && !two_factor);
+        let valid = password_hash.is_some();
+        if let Ok(connection) = self.database.lock() {
+            match valid {
+                true => lockout::record_success(&connection, username),
+                false => lockout::record_failure(&connection, username, Some(client_ip), "webdav"),
+            }
+        }
+        if !valid {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        if let (Ok(mut credentials), Some(password_hash)) = (self.credentials.lock(), password_hash) {
+            credentials.insert(authorization.to_string(), CachedLogin {
+                username: username.to_string(),
+                password_hash,
+                verified_at: Instant::now(),
+            });
+        }
+        Ok(username.to_string())
+    }
+
+    // Fails with 423 when `path` is covered by, or contains, a lock whose
+    // token the client did not submit in the `If` header.
+    fn check_locks(&self, path: &Path, submitted: &[String]) -> Result<(), StatusCode> {
+        let mut locks = self.locks.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        locks.retain(|_, lock| lock.expires > Instant::now());
+
+        let blocked = locks.values().any(|lock| {
+            let covers = lock.path == path || (lock.infinite && path.starts_with(&lock.path)) || lock.path.starts_with(path);
+            covers && !submitted.contains(&lock.token)
+        });
+        if blocked {
+            return Err(StatusCode::LOCKED);
+        }
+        Ok(())
+    }
+
+    fn release_locks(&self, path: &Path) {
+        if let Ok(mut locks) = self.locks.lock() {
+            locks.retain(|_, lock| !lock.path.starts_with(path));
+        }
+    }
+
+    fn lock_discovery(&self, path: &Path) -> String {
+        let locks = match self.locks.lock() {
+            Ok(locks) => locks,
+            Err(_) => return String::new(),
+        };
+
+        locks.values()
+            .filter(|lock| lock.path == path && lock.expires > Instant::now())
+            .map(active_lock_xml)
+            .collect()
+    }
+}
+
+pub fn load_tls(config: &rocket::Config) -> std::io::Result<Option<TlsAcceptor>> {
+    let tls = match &config.tls {
+        Some(tls) => tls,
+        None => return Ok(None),
+    };
+
+    let certs_pem = tls.certs().either(std::fs::read, |bytes| Ok(bytes.to_vec()))?;
+    let key_pem = tls.key().either(std::fs::read, |bytes| Ok(bytes.to_vec()))?;
+
+    let certs = rustls_pemfile::certs(&mut certs_pem.as_slice())?.into_iter().map(Certificate).collect();
+    let key = rustls_pemfile::read_all(&mut key_pem.as_slice())?.into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| std::io::Error::other("no private key found"))?;
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(std::io::Error::other)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+pub async fn serve(server: Arc<DavServer>, address: SocketAddr, tls: Option<TlsAcceptor>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(_) => continue,
+        };
+
+        let server = server.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(server.clone(), peer.ip(), request));
+            match tls {
+                Some(acceptor) => {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let _ = Http::new().serve_connection(stream, service).await;
+                    }
+                },
+                None => {
+                    let _ = Http::new().serve_connection(stream, service).await;
+                },
+            }
+        });
+    }
+}
+
+async fn handle(server: Arc<DavServer>, client_ip: IpAddr, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let request_id = logging::generate_request_id();
+
+    let response = match route(&server, client_ip, &request_id, request).await {
+        Ok(response) => response,
+        Err(StatusCode::UNAUTHORIZED) => {
+            let mut response = empty(StatusCode::UNAUTHORIZED);
+            response.headers_mut().insert("WWW-Authenticate", HeaderValue::from_static("Basic realm=\"MyDrive\""));
+            response
+        },
+        Err(status) => empty(status),
+    };
+
+    let status = response.status().as_u16();
+    let latency_ms = started.elapsed().as_micros() as f64 / 1000.0;
+    tracing::info!(request_id = %request_id, method = %method, path = %path, status, latency_ms, ip = %client_ip, "webdav request");
+    Ok(response)
+}
+
+async fn route(server: &DavServer, client_ip: IpAddr, request_id: &str, request: Request<Body>) -> DavResult {
+    let relative = dav_path(request.uri().path()).ok_or(StatusCode::NOT_FOUND)?;
+
+    if request.method() == hyper::Method::OPTIONS {
+        return Response::builder()
+            .header("DAV", "1, 2")
+            .header("MS-Author-Via", "DAV")
+            .header("Allow", ALLOWED_METHODS)
+            .header(CONTENT_LENGTH, "0")
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let username = server.authenticate(client_ip, request.headers().get("Authorization"))?;
+    let user_directory = server.user_directory(&username);
+    tokio::fs::create_dir_all(&user_directory).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The trash is only reachable through the web interface.
+    let path = match resolve_user_path(&user_directory, &relative) {
+        Some(path) if !storage::is_trash(&relative) => path,
+        _ => return Err(StatusCode::FORBIDDEN),
+    };
+
+    // Same actions as the web interface, see audit.rs.
+    let action = match request.method().as_str() {
+        "GET" => Some("download"),
+        "PUT" => Some("upload"),
+        "DELETE" => Some("delete"),
+        "MKCOL" => Some("create_folder"),
+        "COPY" => Some("copy"),
+        "MOVE" => Some("move"),
+        _ => None,
+    };
+    let target = header_str(&request, "Destination").and_then(destination_path)
+        .map(|destination| destination.to_string_lossy().to_string());
+
+    let context = DavRequest { server, username: username.clone(), user_directory, path };
+    let result = match request.method().as_str() {
+        "GET" => context.get(&request, true).await,
+        "HEAD" => context.get(&request, false).await,
+        "PUT" => context.put(request).await,
+        "DELETE" => context.delete(&request).await,
+        "PROPFIND" => context.propfind(&request).await,
+        "MKCOL" => context.mkcol(request).await,
+        "COPY" => context.copy_or_move(&request, false).await,
+        "MOVE" => context.copy_or_move(&request, true).await,
+        "LOCK" => context.lock(request).await,
+        "UNLOCK" => context.unlock(&request),
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    if let Some(action) = action {
+        server.audit_log.write(&AuditEvent {
+            time: unix_timestamp(),
+            username,
+            ip: Some(client_ip.to_string()),
+            action: action.to_string(),
+            path: relative.to_string_lossy().to_string(),
+            target,
+            status: match &result {
+                Ok(response) => response.status().as_u16(),
+                Err(status) => status.as_u16(),
+            },
+            request_id: Some(request_id.to_string()),
+        });
+    }
+    result
+}
+
+struct DavRequest<'a> {
+    server: &'a DavServer,
+    username: String,
+    user_directory: PathBuf,
+    path: PathBuf,
+}
+
+impl DavRequest<'_> {
+    // Where `path` is in the user's space, as the storage backend takes it.
+    fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        path.strip_prefix(&self.user_directory).unwrap_or(Path::new(""))
+    }
+
+    fn href(&self, path: &Path, is_dir: bool) -> String {
+        let relative = self.relative(path);
+        let mut href = DAV_PREFIX.to_string();
+        for component in relative.components() {
+            href.push('/');
+            href.push_str(&percent_encode(&component.as_os_str().to_string_lossy()));
+        }
+        if is_dir {
+            href.push('/');
+        }
+        href
+    }
+
+    // A new file or folder can't differ from an existing one only in case or
+    // normalization, see filenames.rs. `source` is what is being moved there.
+    async fn check_collision(&self, path: &Path, source: Option<&Path>) -> Result<(), StatusCode> {
+        match filenames::find_collision(&self.server.storage, &self.username, self.relative(path)).await {
+            Some(existing) if Some(existing.as_path()) != source.map(|source| self.relative(source)) => Err(StatusCode::CONFLICT),
+            _ => Ok(()),
+        }
+    }
+
+    async fn get(&self, request: &Request<Body>, send_body: bool) -> DavResult {
+        let file = RangedFile::open(&self.path).await.map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            _ => StatusCode::METHOD_NOT_ALLOWED,
+        })?;
+        // WebDAV logins don't unlock encryption keys.
+        if encryption::file_key_id(&self.path).await.is_some() {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let conditions = Conditions {
+            if_none_match: header_str(request, "If-None-Match"),
+            if_modified_since: header_str(request, "If-Modified-Since"),
+            if_range: header_str(request, "If-Range"),
+            range: header_str(request, "Range"),
+        };
+        let ranged = file.respond(&conditions).map_err(|_| StatusCode::NOT_FOUND)?;
+
+        let mut response = Response::builder().status(ranged.status.code);
+        for header in ranged.headers.iter() {
+            response = response.header(header.name().as_str(), header.value());
+        }
+        let body = match ranged.body {
+            Some(body) if send_body => stream_file(body),
+            _ => Body::empty(),
+        };
+        response.body(body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    async fn put(&self, request: Request<Body>) -> DavResult {
+        if self.path == self.user_directory {
+            return Err(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        self.server.check_locks(&self.path, &submitted_tokens(&request))?;
+
+        if !is_directory(self.path.parent()).await {
+            return Err(StatusCode::CONFLICT);
+        }
+
+        // Without the keys an upload could only be stored unencrypted.
+        let encrypted = match self.server.database.lock() {
+            Ok(connection) => encryption::is_enabled(&connection, &self.username),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        if encrypted {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let replaced_size = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+            Ok(_) => return Err(StatusCode::METHOD_NOT_ALLOWED),
+            Err(_) => None,
+        };
+        self.check_collision(&self.path, None).await?;
+
+        let quota = self.server.user_quota(&self.username).await?;
+        if replaced_size.is_none() && !quota.allows(0, 1) {
+            return Err(StatusCode::INSUFFICIENT_STORAGE);
+        }
+        let quota_limit = quota.available_bytes().map(|available| available + quota.freed_by_replacing(replaced_size.unwrap_or(0)));
+        let limit = quota_limit.unwrap_or(u64::MAX).min(upload::MAX_UPLOAD_SIZE.as_u64());
+
+        let (staging_path, mut staging_file) = upload::create_staging_file(&self.server.app_config.directory, &self.username).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let mut body = request.into_body();
+        let mut written: u64 = 0;
+        let mut failure = None;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    failure = Some(StatusCode::BAD_REQUEST);
+                    break;
+                },
+            };
+
+            written += chunk.len() as u64;
+            if written > limit {
+                failure = Some(match quota_limit {
+                    Some(available) if available < upload::MAX_UPLOAD_SIZE.as_u64() => StatusCode::INSUFFICIENT_STORAGE,
+                    _ => StatusCode::PAYLOAD_TOO_LARGE,
+                });
+                break;
+            }
+
+            if staging_file.write_all(&chunk).await.is_err() {
+                failure = Some(StatusCode::INTERNAL_SERVER_ERROR);
+                break;
+            }
+        }
+
+        if failure.is_none() && staging_file.flush().await.is_err() {
+            failure = Some(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        drop(staging_file);
+
+        if let Some(status) = failure {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            return Err(status);
+        }
+
+        if self.server.storage.store(&self.username, self.relative(&self.path), &staging_path).await.is_err() {
+            let _ = tokio::fs::remove_file(&staging_path).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        match replaced_size {
+            Some(replaced_size) => {
+                quota.record(written as i64 - replaced_size as i64, 0);
+                Ok(empty(StatusCode::NO_CONTENT))
+            },
+            None => {
+                quota.record(written as i64, 1);
+                Ok(empty(StatusCode::CREATED))
+            },
+        }
+    }
+
+    async fn delete(&self, request: &Request<Body>) -> DavResult {
+        if self.path == self.user_directory {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        self.server.check_locks(&self.path, &submitted_tokens(request))?;
+
+        self.move_to_trash(&self.path).await?;
+        self.server.release_locks(&self.path);
+        Ok(empty(StatusCode::NO_CONTENT))
+    }
+
+    // Deleted and overwritten items go to the trash, the same as through the
+    // web interface.
+    async fn move_to_trash(&self, path: &Path) -> Result<(), StatusCode> {
+        let relative = self.relative(path);
+        let usage = self.server.storage.usage(&self.username, relative).await.map_err(|_| StatusCode::NOT_FOUND)?;
+
+        self.server.storage.remove(&self.username, relative).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        self.server.user_quota(&self.username).await?.record(0, -(usage.files as i64));
+        Ok(())
+    }
+
+    async fn propfind(&self, request: &Request<Body>) -> DavResult {
+        let metadata = tokio::fs::metadata(&self.path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+
+        let depth = header_str(request, "Depth").unwrap_or("infinity");
+        if depth != "0" && depth != "1" {
+            // Listing a whole tree in one response is not supported (RFC 4918 9.1).
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let mut responses = self.prop_response(&self.path, &metadata);
+        if metadata.is_dir() && depth == "1" {
+            let mut entries = tokio::fs::read_dir(&self.path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let entry_path = entry.path();
+                if storage::is_trash(self.relative(&entry_path)) {
+                    continue;
+                }
+                if let Ok(entry_metadata) = entry.metadata().await {
+                    responses.push_str(&self.prop_response(&entry_path, &entry_metadata));
+                }
+            }
+        }
+
+        xml_response(StatusCode::MULTI_STATUS, format!("<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>", responses))
+    }
+
+    fn prop_response(&self, path: &Path, metadata: &std::fs::Metadata) -> String {
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let display_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+
+        let mut props = format!("<D:displayname>{}</D:displayname><D:getlastmodified>{}</D:getlastmodified>",
+            xml_escape(&display_name), format_http_date(modified));
+        if metadata.is_dir() {
+            props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            props.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype><D:getetag>{}</D:getetag>",
+                metadata.len(), xml_escape(&content_type(path)), xml_escape(&entity_tag(metadata.len(), modified))));
+        }
+        props.push_str("<D:supportedlock>\
+            <D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+            <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+            </D:supportedlock>");
+        props.push_str(&format!("<D:lockdiscovery>{}</D:lockdiscovery>", self.server.lock_discovery(path)));
+
+        format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+            xml_escape(&self.href(path, metadata.is_dir())), props)
+    }
+
+    async fn mkcol(&self, request: Request<Body>) -> DavResult {
+        self.server.check_locks(&self.path, &submitted_tokens(&request))?;
+
+        if !read_small_body(request.into_body()).await?.is_empty() {
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        if tokio::fs::metadata(&self.path).await.is_ok() {
+            return Err(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        if !is_directory(self.path.parent()).await {
+            return Err(StatusCode::CONFLICT);
+        }
+        self.check_collision(&self.path, None).await?;
+
+        self.server.storage.create_folder(&self.username, self.relative(&self.path)).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(empty(StatusCode::CREATED))
+    }
+
+    async fn copy_or_move(&self, request: &Request<Body>, is_move: bool) -> DavResult {
+        let destination = header_str(request, "Destination")
+            .and_then(destination_path)
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let destination = match resolve_user_path(&self.user_directory, &destination) {
+            Some(path) if path != self.user_directory && !storage::is_trash(self.relative(&path)) => path,
+            _ => return Err(StatusCode::FORBIDDEN),
+        };
+
+        if self.path == self.user_directory || destination.starts_with(&self.path) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let tokens = submitted_tokens(request);
+        if is_move {
+            self.server.check_locks(&self.path, &tokens)?;
+        }
+        self.server.check_locks(&destination, &tokens)?;
+
+        let metadata = tokio::fs::metadata(&self.path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+        if !is_directory(destination.parent()).await {
+            return Err(StatusCode::CONFLICT);
+        }
+
+        self.check_collision(&destination, is_move.then_some(self.path.as_path())).await?;
+
+        let overwrite = header_str(request, "Overwrite") != Some("F");
+        let replaced = tokio::fs::metadata(&destination).await.is_ok();
+        if replaced && !overwrite {
+            return Err(StatusCode::PRECONDITION_FAILED);
+        }
+
+        // A copy of a collection with `Depth: 0` is just the empty collection.
+        let shallow = header_str(request, "Depth") == Some("0");
+        let usage = if metadata.is_dir() && shallow {
+            quota::Usage::default()
+        } else {
+            quota::measure(&self.path)
+        };
+
+        let quota = self.server.user_quota(&self.username).await?;
+        // A replaced destination goes to the trash, which keeps its bytes.
+        if !is_move {
+            let freed_files = if replaced { quota::measure(&destination).files } else { 0 };
+            if !quota.allows(usage.bytes, usage.files.saturating_sub(freed_files)) {
+                return Err(StatusCode::INSUFFICIENT_STORAGE);
+            }
+        }
+
+        if replaced {
+            self.move_to_trash(&destination).await?;
+        }
+
+        if is_move {
+            self.server.storage.rename(&self.username, self.relative(&self.path), self.relative(&destination)).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            self.server.release_locks(&self.path);
+        } else {
+            let storage = &self.server.storage;
+            let copied = if metadata.is_dir() && shallow {
+                storage.create_folder(&self.username, self.relative(&destination)).await
+            } else {
+                copy_entry(storage, &quota, self.relative(&self.path), self.relative(&destination)).await
+            };
+            if copied.is_err() {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+
+        Ok(empty(if replaced { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+    }
+
+    async fn lock(&self, request: Request<Body>) -> DavResult {
+        let tokens = submitted_tokens(&request);
+        let timeout = lock_timeout(header_str(&request, "Timeout"));
+        let infinite = header_str(&request, "Depth") != Some("0");
+        let body = read_small_body(request.into_body()).await?;
+        let body = String::from_utf8_lossy(&body);
+
+        // An empty body refreshes a lock the client already holds.
+        if body.trim().is_empty() {
+            let mut locks = self.server.locks.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let token = tokens.iter()
+                .find(|token| locks.get(*token).is_some_and(|lock| lock.username == self.username && self.path.starts_with(&lock.path)))
+                .ok_or(StatusCode::PRECONDITION_FAILED)?;
+            let lock = locks.get_mut(token).ok_or(StatusCode::PRECONDITION_FAILED)?;
+            lock.timeout = timeout;
+            lock.expires = Instant::now() + Duration::from_secs(timeout);
+            return xml_response(StatusCode::OK, format!("<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>", active_lock_xml(lock)));
+        }
+
+        let lock_scope = element_content(&body, "lockscope").unwrap_or("");
+        let exclusive = element_content(lock_scope, "shared").is_none();
+        let owner = element_content(&body, "owner").unwrap_or("").to_string();
+
+        {
+            let mut locks = self.server.locks.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            locks.retain(|_, lock| lock.expires > Instant::now());
+            let conflict = locks.values().any(|lock| {
+                let overlaps = lock.path == self.path
+                    || (lock.infinite && self.path.starts_with(&lock.path))
+                    || (infinite && lock.path.starts_with(&self.path));
+                overlaps && (lock.exclusive || exclusive)
+            });
+            if conflict {
+                return Err(StatusCode::LOCKED);
+            }
+        }
+
+        // Locking a name that doesn't exist yet reserves it with an empty file.
+        let mut status = StatusCode::OK;
+        if tokio::fs::metadata(&self.path).await.is_err() {
+            if !is_directory(self.path.parent()).await {
+                return Err(StatusCode::CONFLICT);
+            }
+            self.check_collision(&self.path, None).await?;
+            let quota = self.server.user_quota(&self.username).await?;
+            if !quota.allows(0, 1) {
+                return Err(StatusCode::INSUFFICIENT_STORAGE);
+            }
+            tokio::fs::File::create(&self.path).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            quota.record(0, 1);
+            status = StatusCode::CREATED;
+        }
+
+        let lock = DavLock {
+            token: format!("opaquelocktoken:{}", hex::encode(rand::thread_rng().gen::<[u8; 16]>())),
+            username: self.username.clone(),
+            path: self.path.clone(),
+            exclusive,
+            infinite,
+            owner,
+            timeout,
+            expires: Instant::now() + Duration::from_secs(timeout),
+        };
+        let body = format!("<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>", active_lock_xml(&lock));
+        let token = lock.token.clone();
+        self.server.locks.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.insert(token.clone(), lock);
+
+        let mut response = xml_response(status, body)?;
+        let lock_token = HeaderValue::from_str(&format!("<{}>", token)).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        response.headers_mut().insert("Lock-Token", lock_token);
+        Ok(response)
+    }
+
+    fn unlock(&self, request: &Request<Body>) -> DavResult {
+        let token = header_str(request, "Lock-Token")
+            .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        let mut locks = self.server.locks.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        match locks.get(&token) {
+            Some(lock) if lock.username == self.username && self.path.starts_with(&lock.path) => {
+                locks.remove(&token);
+                Ok(empty(StatusCode::NO_CONTENT))
+            },
+            _ => Err(StatusCode::CONFLICT),
+        }
+    }
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn xml_response(status: StatusCode, body: String) -> DavResult {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(Body::from(format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>{}", body)))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn active_lock_xml(lock: &DavLock) -> String {
+    format!("<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{}</D:lockscope><D:depth>{}</D:depth>\
+        <D:owner>{}</D:owner><D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken></D:activelock>",
+        if lock.exclusive { "<D:exclusive/>" } else { "<D:shared/>" },
+        if lock.infinite { "infinity" } else { "0" },
+        lock.owner, lock.timeout, lock.token)
+}
+
+fn header_str<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+// Lock tokens are taken from anywhere in the `If` header; the tagged-list and
+// etag conditions are not evaluated.
+fn submitted_tokens(request: &Request<Body>) -> Vec<String> {
+    let header = match header_str(request, "If") {
+        Some(header) => header,
+        None => return Vec::new(),
+    };
+
+    header.split('<')
+        .filter_map(|part| part.split_once('>').map(|(token, _)| token))
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .map(|token| token.to_string())
+        .collect()
+}
+
+fn lock_timeout(header: Option<&str>) -> u64 {
+    header.and_then(|header| header.split(',').find_map(|value| value.trim().strip_prefix("Second-")))
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .unwrap_or(MAX_LOCK_TIMEOUT)
+        .clamp(1, MAX_LOCK_TIMEOUT)
+}
+
+async fn read_small_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if bytes.len() + chunk.len() > MAX_XML_BODY {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn stream_file<R: tokio::io::AsyncRead + Send + Unpin + 'static>(mut reader: R) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    if sender.send_data(Bytes::copy_from_slice(&buffer[..read])).await.is_err() {
+                        break;
+                    }
+                },
+                Err(_) => {
+                    sender.abort();
+                    break;
+                },
+            }
+        }
+    });
+    body
+}
+
+async fn is_directory(path: Option<&Path>) -> bool {
+    match path {
+        Some(path) => matches!(tokio::fs::metadata(path).await, Ok(metadata) if metadata.is_dir()),
+        None => false,
+    }
+}
+
+// Copies through the storage backend a file at a time, like a folder copy
+// job, so the copies are versioned and deduplicated like any other stored
+// file. Each file counts against the quota once it is copied.
+async fn copy_entry(storage: &StorageState, quota: &UserQuota, source: &Path, destination: &Path) -> std::io::Result<()> {
+    let username = &quota.username;
+    let entry = storage.metadata(username, source).await?;
+    if !entry.is_dir {
+        storage.copy(username, source, destination).await?;
+        quota.record(entry.size as i64, 1);
+        return Ok(());
+    }
+
+    let mut pending = vec![(source.to_path_buf(), destination.to_path_buf())];
+    while let Some((source, destination)) = pending.pop() {
+        storage.create_folder(username, &destination).await?;
+        for name in storage.entry_names(username, &source).await? {
+            let (from, to) = (source.join(&name), destination.join(&name));
+            let entry = storage.metadata(username, &from).await?;
+            if entry.is_dir {
+                pending.push((from, to));
+            } else {
+                storage.copy(username, &from, &to).await?;
+                quota.record(entry.size as i64, 1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn content_type(path: &Path) -> String {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary)
+        .to_string()
+}
+
+// Maps `/dav/a%20b/c` to `a b/c`. Anything outside the prefix is not ours.
+fn dav_path(uri_path: &str) -> Option<PathBuf> {
+    let rest = uri_path.strip_prefix(DAV_PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for segment in rest.split('/').filter(|segment| !segment.is_empty()) {
+        let decoded = percent_decode(segment)?;
+        if decoded.contains(['/', '\\']) {
+            return None;
+        }
+        segments.push(decoded);
+    }
+
+    // The same names as the web interface accepts, anything else is refused.
+    if segments.is_empty() {
+        return Some(PathBuf::new());
+    }
+    filenames::check_relative_path(&segments.join("/"))
+}
+
+// The `Destination` header is usually an absolute URL.
+fn destination_path(destination: &str) -> Option<PathBuf> {
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => destination,
+    };
+    dav_path(path.split('?').next()?)
+}
+
+pub(crate) fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = segment.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+pub(crate) fn percent_encode(segment: &str) -> String {
+    segment.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn xml_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Returns the inner text of the first element with the given local name,
+// whatever namespace prefix the client used.
+fn element_content<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut search_from = 0;
+    while let Some(offset) = xml[search_from..].find('<') {
+        let start = search_from + offset + 1;
+        let tag_end = start + xml[start..].find('>')?;
+        let tag = &xml[start..tag_end];
+        search_from = tag_end;
+
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+
+        let full_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        let local_name = full_name.rsplit(':').next().unwrap_or("");
+        if local_name != name {
+            continue;
+        }
+
+        if tag.ends_with('/') {
+            return Some("");
+        }
+
+        let closing = format!("</{}>", full_name);
+        let content_end = tag_end + 1 + xml[tag_end + 1..].find(&closing)?;
+        return Some(&xml[tag_end + 1..content_end]);
+    }
+    None
+}
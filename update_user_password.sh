@@ -9,19 +9,34 @@ if [[ -z $RESULT ]]; then
     exit 1
 fi 
 
-read -p "Enter an email: " EMAIL
+read -p "Enter a username: " USERNAME
 read -p "Enter a password: " PASSWORD
 
-SALT=$(openssl rand -base64 16)
-HASH=$(echo -n "$PASSWORD" | argon2 "$SALT" -i -l 32 | grep "Encoded:" | awk '{ print $2 }' )
-output=$(sqlite3 "$DB_FILE" "UPDATE users SET password = '$HASH' WHERE email = '$EMAIL'; SELECT changes();" 2>&1)
+if [[ ! "$USERNAME" =~ ^[A-Za-z0-9_.-]+$ ]]; then
+    echo "Error: No user exists"
+    exit 1
+fi
+
+# The encryption keys are wrapped with the password, so it can only be
+# changed by the server, which wraps them again.
+ENCRYPTED=$(sqlite3 "$DB_FILE" "SELECT COUNT(*) FROM encryption WHERE username = '$USERNAME';" 2>/dev/null)
+if [[ "$ENCRYPTED" != "0" && -n "$ENCRYPTED" ]]; then
+    echo "Error: $USERNAME has encryption enabled, changing the password here would make their files unreadable"
+    echo "Reset it as an admin with PUT /admin/users/$USERNAME/password instead"
+    exit 1
+fi
+
+SALT=$(openssl rand -hex 16)
+HASH=$(echo -n "$PASSWORD" | argon2 "$SALT" -i -l 32 -m 12 -p 1 -t 3 | grep "Encoded:" | awk '{ print $2 }' )
+output=$(sqlite3 "$DB_FILE" "UPDATE users SET password = '$HASH' WHERE username = '$USERNAME'; SELECT changes();" 2>&1)
 exitstatus=$?
 
 if [ $exitstatus -ne 0 ]; then
     echo "Unable to update password, ensure user exists"
     echo "$output"
 elif [ $output -eq '0' ]; then 
-    echo "Erro: No user exists"
+    echo "Error: No user exists"
 else
+    sqlite3 "$DB_FILE" "DELETE FROM sessions WHERE username = '$USERNAME';"
     echo "Updated password successfully"
-fi
\ No newline at end of file
+fi